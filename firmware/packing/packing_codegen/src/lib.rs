@@ -1044,7 +1044,7 @@ fn derive_enum(
                 let mut owned_bytes = [0; #width_ident];
                 owned_bytes.copy_from_slice(&bytes[..#width_ident]);
 
-                Ok(packing::PackedBytes::from_bytes::<packing::LittleEndian>(owned_bytes)?)
+                packing::PackedBytes::from_bytes::<packing::LittleEndian>(owned_bytes)
             }
         }
     });
@@ -21,7 +21,10 @@ use cortex_m::{
     interrupt,
     asm::*,
 };    
-use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::digital::v2::{
+    OutputPin,
+    InputPin,
+};
 use rtfm::app;
 use stm32f1xx_hal::{
     prelude::*,
@@ -61,7 +64,7 @@ use itm_logger::*;
 use usb_bootloader::{
     hardware_extra::*,
     ghost_fat::GhostFat,
-    flash::Flash,
+    flash_wrapper::FlashWrapper,
 };
 
 // VID and PID are from dapboot bluepill bootloader
@@ -72,262 +75,6 @@ const USB_PID: u16 = 0xDB42;
 const TICK_MS: u32 = 10;
 const TICK_HZ: Hertz = Hertz(1000 / TICK_MS);
 
-pub struct FlashWrapper {
-    page_size: u32,
-    min_address: u32,
-    max_address: u32,
-    page_buffer: [u8; 2048],
-    current_page: Option<u32>,
-}
-
-impl Flash for FlashWrapper {
-    // Return the page size in bytes
-    fn page_size(&self) -> u32 {
-        self.page_size
-    }
-
-    fn address_range(&self) -> RangeInclusive<u32> {
-        self.min_address..=self.max_address
-    }
-
-    fn current_page(&self) -> &Option<u32> {
-        &self.current_page
-    }
-
-    fn page_buffer(&mut self) -> &mut [u8] {
-        &mut self.page_buffer[..(self.page_size as usize)]
-    }
-
-    // Unlock the flash for erasing/writing
-    fn unlock_flash(&mut self) -> Result<(), BlockDeviceError> {
-        const KEY1: u32 = 0x45670123;
-        const KEY2: u32 = 0xCDEF89AB;
-
-        let flash = unsafe {
-            &(*FLASH::ptr())
-        };
-
-        if flash.cr.read().lock().bit_is_set() {
-            // Unlock flash
-            flash.keyr.write(|w| unsafe { w.key().bits(KEY1) });
-            flash.keyr.write(|w| unsafe { w.key().bits(KEY2) });
-        }
-
-        if flash.cr.read().lock().bit_is_set() {
-            error!("Flash still locked after performing unlock sequence");
-            Err(BlockDeviceError::HardwareError)?;
-        }
-
-        Ok(())
-    }
-
-    // Lock the flash to prevent erasing/writing
-    fn lock_flash(&mut self) -> Result<(), BlockDeviceError> {
-        let flash = unsafe {
-            &(*FLASH::ptr())
-        };
-
-        flash.cr.modify(|_, w| w.lock().set_bit());
-
-        Ok(())
-    }
-
-    // Is the flash busy?
-    fn is_operation_pending(&self) -> bool {
-        let flash = unsafe {
-            &(*FLASH::ptr())
-        };
-        flash.sr.read().bsy().bit_is_set()
-    }
-
-    // Check if the page is empty
-    fn is_page_erased(&mut self, page_address: u32) -> bool {
-        for word in (page_address..(page_address+self.page_size())).step_by(4) {
-            let value = unsafe { read_volatile(word as *const u32) };
-            if value != 0xFFFFFFFF {
-                return false;
-            }
-        }
-        true
-    }
-
-    // Erase the page at the given address. Don't check if erase is necessary, that's done at a higher level
-    fn erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
-        let flash = unsafe {
-            &(*FLASH::ptr())
-        };
-
-        // Make sure the flash is unlocked
-        self.unlock_flash()?;
-
-        // Indicate we want to do a page erase
-        flash.cr.modify(|_, w| w.per().set_bit());
-
-        // Set the address we want to erase
-        flash.ar.write(|w| unsafe { w.far().bits(page_address) });
-
-        // Kick off the operation
-        flash.cr.modify(|_, w| w.strt().set_bit());
-
-        // Wait until it's done
-        self.busy_wait();
-
-        // Clear page erase flag
-        flash.cr.modify(|_, w| w.per().clear_bit());
-
-        // Check the erase worked
-        if !self.is_page_erased(page_address) {
-            error!("Page erase failed");
-            Err(BlockDeviceError::EraseError)?;
-        }
-            
-        info!("erased 0x{:X?}", page_address);  
-        
-
-        Ok(())
-    }
-
-    fn read_bytes(&self, address: u32, bytes: &mut [u8]) -> Result<(), BlockDeviceError> {
-        let range = self.address_range();
-        for (i, b) in bytes.iter_mut().enumerate() {
-            let hw_addr = address + i as u32;
-            if !range.contains(&hw_addr) {
-                Err(BlockDeviceError::InvalidAddress)?;
-            }
-            *b = unsafe { read_volatile(hw_addr as *const u8) };
-        }
-
-        Ok(())
-    }
-
-    fn read_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
-        if page_address != self.page_address(page_address) {
-            Err(BlockDeviceError::InvalidAddress)?;
-        }
-        let range = self.address_range();
-        let buffer = self.page_buffer();
-        for (i, half_word) in buffer.chunks_exact_mut(2).enumerate() {
-            let hw_addr = page_address + i as u32 * 2;
-            if !range.contains(&hw_addr) {
-                Err(BlockDeviceError::InvalidAddress)?;
-            }
-            let value = unsafe { read_volatile(hw_addr as *const [u8; 2]) };
-            half_word.copy_from_slice(&value);
-        }
-
-        self.current_page = Some(page_address);
-
-        Ok(())
-    }
-
-    fn write_page(&mut self) -> Result<(), BlockDeviceError> {
-        let flash = unsafe {
-            &(*FLASH::ptr())
-        };
-
-        let page_address = self.current_page.ok_or(BlockDeviceError::InvalidAddress)?;
-
-        // Make sure the flash is unlocked
-        self.unlock_flash()?;
-
-        let range = self.address_range();
-        let buffer = self.page_buffer();
-
-        let mut half_word = [0; 2];
-        for (i, c) in buffer.chunks_exact(2).enumerate() {
-            let hw_addr = page_address + i as u32 * 2;
-            
-            if !range.contains(&hw_addr) {
-                Err(BlockDeviceError::InvalidAddress)?;
-            }
-
-            half_word.copy_from_slice(c);
-
-            //let value = unsafe { mem::transmute(half_word) };
-
-            let old_value = unsafe { read_volatile(hw_addr as *const [u8; 2]) };
-            if old_value != half_word {
-                info!("0x{:X?}: 0x{:X?} => 0x{:X?}", hw_addr, old_value, half_word); 
-
-                // Indicate we want to write to flash
-                flash.cr.modify(|_, w| w.pg().set_bit());
-
-                // Write the half word
-                unsafe { write_volatile(hw_addr as *mut [u8; 2], half_word); }
-
-                // Wait for write to complete
-                while flash.sr.read().bsy().bit_is_set() {}
-
-                // Clear write flag
-                flash.cr.modify(|_, w| w.pg().clear_bit());  
-
-                let new_value = unsafe { read_volatile(hw_addr as *const [u8; 2]) };
-
-                if new_value != half_word {
-                    error!("write to 0x{:X?} failed", hw_addr);  
-                    Err(BlockDeviceError::WriteError)?;
-                }
-
-                info!("write to 0x{:X?} ok", hw_addr);  
-
-            }
-        }
-
-        Ok(())
-    }
-
-    fn flush_page(&mut self) -> Result<(), BlockDeviceError> {
-        let page_address = self.current_page.ok_or(BlockDeviceError::InvalidAddress)?;
-
-        let mut erase_needed = false;
-        let mut write_needed = false;
-
-        for (i, b) in self.page_buffer().iter().enumerate() {
-            let hw_addr = page_address + i as u32;
-            let new_value = *b;
-            let old_value = unsafe { read_volatile(hw_addr as *const u8) };
-
-            if old_value == new_value {
-                // Value already on flash, no write or erase needed
-                continue;
-            }
-
-            if old_value & new_value == new_value {
-                // New value can be written over old value without erase
-                write_needed = true;
-                trace!("Write needed: 0x{:X}, 0x{:X} => 0x{:X}", hw_addr, old_value, new_value);
-                continue;
-            } 
-
-            trace!("Erase page: 0x{:X}, 0x{:X} => 0x{:X}", hw_addr, old_value, new_value);
-            // Erase is required
-            erase_needed = true;
-            break;
-        }
-
-        if erase_needed {
-            info!("Flush: erase needed (page: 0x{:X})", page_address);
-            self.erase_page(page_address)?;
-        }
-
-        if erase_needed || write_needed {
-            info!("Flush: write needed (page: 0x{:X})", page_address);
-            self.write_page()?;
-
-            /*
-            for c in self.page_buffer().chunks(16) {
-                trace!("0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}", 
-                    c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7],
-                    c[8], c[9], c[10], c[11], c[12], c[13], c[14], c[15],
-                );
-            } 
-            */
-        }
-
-        Ok(())
-    }
-}
-
 #[cfg(feature = "itm")] 
 use cortex_m::{iprintln, peripheral::ITM};
 
@@ -397,14 +144,12 @@ const APP: () = {
             1024
         };
 
-        let flash_wrapper = FlashWrapper { 
+        let flash_wrapper = FlashWrapper::new(
             page_size,
-            page_buffer: [0; 2048],
-            current_page: None,
-            min_address: 0x08010000,
-            max_address: 0x08000000 + flash_kib as u32 * 1024,
-        };
-        info!("Flash MAX: 0x{:X?}", flash_wrapper.max_address);
+            0x08010000,
+            0x08000000 + flash_kib as u32 * 1024,
+        );
+        info!("Flash MAX: 0x{:X?}", flash_wrapper.max_address());
 
 
         let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
@@ -420,6 +165,12 @@ const APP: () = {
         let usb_dm = gpioa.pa11;
         let usb_dp = usb_dp.into_floating_input(&mut gpioa.crh);
 
+        // The BOOT1 jumper isn't needed once the bootloader is running. Setting it to 1 makes
+        // the drive read only
+        let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
+        let boot1 = gpiob.pb2.into_floating_input(&mut gpiob.crl);
+        let write_protect_jumper = boot1.is_high().unwrap();
+
         let usb = Peripheral {
             usb: cx.device.USB,
             pin_dm: usb_dm,
@@ -432,12 +183,15 @@ const APP: () = {
             .start_count_down(TICK_HZ);
         tick_timer.listen(Event::Update);
 
-        let ghost_fat = GhostFat::new(
+        let mut ghost_fat = GhostFat::new(
             flash_wrapper,
             bkp,
         );
 
-        let scsi = Scsi::new(
+        // A locked application can't be replaced either
+        ghost_fat.set_write_protected(write_protect_jumper || read_protection_enabled());
+
+        let mut scsi = Scsi::new(
             USB_BUS.as_ref().unwrap(), 
             64,
             ghost_fat,
@@ -449,6 +203,9 @@ const APP: () = {
         let serial_number = get_serial_number();
         info!("Serial number: {}", serial_number);
 
+        // Lets the host identify the drive across reconnects
+        scsi.set_unit_serial_number(serial_number);
+
         let usb_dev = UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(USB_VID, USB_PID))
             .manufacturer("Fake company")
             .product("Serial port")
@@ -485,6 +242,12 @@ const APP: () = {
           .block_device_mut()
           .tick(TICK_MS);
 
+        // Flash erases don't block, retry anything GhostFat returned WouldBlock for since the
+        // endpoint is NAKing and won't generate a USB interrupt to do it
+        cx.resources
+          .scsi
+          .resume();
+
     }
 };
 
@@ -0,0 +1,399 @@
+//! UF2 bootloader with a debug console. A composite device with the mass storage drive and a
+//! CDC-ACM serial port at the same time
+#![no_main]
+#![no_std]
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+#![allow(unused_imports)]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{self, Ordering},
+    fmt::{self, Write},
+};
+use cortex_m::{
+    interrupt,
+    asm::*,
+    peripheral::SCB,
+};
+use embedded_hal::digital::v2::{
+    OutputPin,
+    InputPin,
+};
+use rtfm::app;
+use stm32f1xx_hal::{
+    prelude::*,
+    time::Hertz,
+    usb::{
+        Peripheral, 
+        UsbBus, 
+        UsbBusType,
+    },
+    timer::{
+        CountDownTimer,
+        Timer,
+        Event,
+    },
+    pac::TIM2,
+};
+use usb_device::{
+    bus,
+    device::{ 
+        UsbDevice, 
+        UsbDeviceBuilder, 
+        UsbVidPid,
+    },
+    UsbError,
+};
+use usbd_serial::SerialPort;
+use usbd_scsi::Scsi;
+use itm_logger::*;
+use usb_bootloader::{
+    hardware_extra::*,
+    ghost_fat::GhostFat,
+    flash_wrapper::FlashWrapper,
+    usb_class::*,
+};
+
+// VID and PID are from dapboot bluepill bootloader
+const USB_VID: u16 = 0x1209; 
+const USB_PID: u16 = 0xDB42;
+
+const TICK_MS: u32 = 10;
+const TICK_HZ: Hertz = Hertz(1000 / TICK_MS);
+
+const UF2_FLASH_START: u32 = 0x08010000;
+
+/// Longest command the console accepts
+const CONSOLE_LINE_BYTES: usize = 32;
+
+/// Line based debug console on the serial port. Echoes what's typed and runs a command for each line
+pub struct Console {
+    line: [u8; CONSOLE_LINE_BYTES],
+    len: usize,
+    flash_kib: u16,
+    page_size: u32,
+    max_address: u32,
+}
+
+impl Console {
+    fn new(flash_kib: u16, page_size: u32, max_address: u32) -> Self {
+        Self {
+            line: [0; CONSOLE_LINE_BYTES],
+            len: 0,
+            flash_kib,
+            page_size,
+            max_address,
+        }
+    }
+
+    fn receive<B: bus::UsbBus>(&mut self, serial: &mut SerialPort<'static, B>, bytes: &[u8]) {
+        let mut out = SerialWriter(serial);
+        for &b in bytes {
+            match b {
+                b'\r' | b'\n' => {
+                    let _ = out.write_str("\r\n");
+                    if self.len > 0 {
+                        self.run_command(&mut out);
+                        self.len = 0;
+                    }
+                    let _ = out.write_str("> ");
+                },
+                // Backspace or delete
+                0x08 | 0x7F => if self.len > 0 {
+                    self.len -= 1;
+                    let _ = out.write_str("\x08 \x08");
+                },
+                _ if self.len < CONSOLE_LINE_BYTES => {
+                    self.line[self.len] = b;
+                    self.len += 1;
+                    out.write_bytes(&[b]);
+                },
+                // Line is full, ignore the rest until the user hits enter
+                _ => {},
+            }
+        }
+    }
+
+    fn run_command<B: bus::UsbBus>(&self, out: &mut SerialWriter<'_, B>) {
+        let command = core::str::from_utf8(&self.line[..self.len]).unwrap_or("").trim();
+        info!("Console command: {}", command);
+
+        let _ = match command {
+            "help" => write!(out, "Commands: help, info, reboot\r\n"),
+            "info" => write!(out,
+                "Serial number: {}\r\nFlash: {} KiB, page size: {} bytes\r\nApplication: 0x{:08X} - 0x{:08X}\r\n",
+                get_serial_number(),
+                self.flash_kib,
+                self.page_size,
+                UF2_FLASH_START,
+                self.max_address,
+            ),
+            "reboot" => SCB::sys_reset(),
+            _ => write!(out, "Unknown command: {}\r\n", command),
+        };
+    }
+}
+
+/// Writes to the serial port, dropping anything that doesn't fit in its buffer
+struct SerialWriter<'s, B: bus::UsbBus>(&'s mut SerialPort<'static, B>);
+
+impl<B: bus::UsbBus> SerialWriter<'_, B> {
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            match self.0.write(bytes) {
+                Ok(count) if count > 0 => bytes = &bytes[count..],
+                _ => break,
+            }
+        }
+    }
+}
+
+impl<B: bus::UsbBus> Write for SerialWriter<'_, B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "itm")] 
+use cortex_m::{iprintln, peripheral::ITM};
+
+#[app(device = stm32f1xx_hal::stm32, peripherals = true)]
+const APP: () = {
+    struct Resources {
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        scsi: Scsi<'static, UsbBusType, GhostFat<FlashWrapper>>,
+        serial: SerialPort<'static, UsbBusType>,
+        console: Console,
+        tick_timer: CountDownTimer<TIM2>,
+    }
+
+    #[init]
+    fn init(mut cx: init::Context) -> init::LateResources {
+        static mut USB_BUS: Option<bus::UsbBusAllocator<UsbBusType>> = None;
+
+        // If caches are enabled, write operations to flash cause the core to hang because it
+        // is very likely to attempt to load into the prefetch buffer while the write is happening
+        // This can be proved by counting busy loops on the SR.BSY flag. With caches enabled this will
+        // almost always get < 2 cycles. With caches disabled it's a much more relistic figure of
+        // 350 cycles for a write and 150k cycles for a page erase.
+        // However, since we're just busy looping while writing it doesn't really matter. Might be 
+        // worth disabling them if there was any useful work to be done in this time but for now,
+        // leave them enabled. 
+        //cx.core.SCB.disable_icache();
+        //cx.core.SCB.disable_dcache(&mut cx.core.CPUID);
+
+        #[cfg(feature = "itm")]
+        {        
+            update_tpiu_baudrate(8_000_000, ITM_BAUD_RATE).expect("Failed to reset TPIU baudrate");
+            logger_init();
+        }
+
+        info!("ITM reset ok.");
+
+        let mut flash = cx.device.FLASH.constrain();
+        let mut rcc = cx.device.RCC.constrain();
+        let bkp = rcc.bkp.constrain(
+            cx.device.BKP, 
+            &mut rcc.apb1,
+            &mut cx.device.PWR,
+        );
+        let tim2 = cx.device.TIM2;
+
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.mhz())
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .freeze(&mut flash.acr);
+
+        #[cfg(feature = "itm")]
+        {
+            let sysclk: Hertz = clocks.sysclk().into();
+            update_tpiu_baudrate(sysclk.0, ITM_BAUD_RATE).expect("Failed to reset TPIU baudrate");
+        }
+
+        assert!(clocks.usbclk_valid());
+
+        let flash_kib = get_flash_kibi();
+        info!("Flash: {} KiB", flash_kib);
+
+        // This may not be 100% accurate. Cube hal has some random IFDEFs that don't even appear
+        // to align with the core density.
+        let page_size = if flash_kib > 128 {
+            2048 
+        } else {
+            1024
+        };
+
+        let flash_wrapper = FlashWrapper::new(
+            page_size,
+            UF2_FLASH_START,
+            0x08000000 + flash_kib as u32 * 1024,
+        );
+        info!("Flash MAX: 0x{:X?}", flash_wrapper.max_address());
+
+        let console = Console::new(flash_kib, page_size, flash_wrapper.max_address());
+
+
+        let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
+
+        // BluePill board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low().unwrap();
+        delay(clocks.sysclk().0 / 100);
+
+        let usb_dm = gpioa.pa11;
+        let usb_dp = usb_dp.into_floating_input(&mut gpioa.crh);
+
+        // The BOOT1 jumper isn't needed once the bootloader is running. Setting it to 1 makes
+        // the drive read only
+        let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
+        let boot1 = gpiob.pb2.into_floating_input(&mut gpiob.crl);
+        let write_protect_jumper = boot1.is_high().unwrap();
+
+        let usb = Peripheral {
+            usb: cx.device.USB,
+            pin_dm: usb_dm,
+            pin_dp: usb_dp,
+        };
+
+        *USB_BUS = Some(UsbBus::new(usb));
+
+        let mut tick_timer = Timer::tim2(tim2, &clocks, &mut rcc.apb1)
+            .start_count_down(TICK_HZ);
+        tick_timer.listen(Event::Update);
+
+        let mut ghost_fat = GhostFat::new(
+            flash_wrapper,
+            bkp,
+        );
+
+        // A locked application can't be replaced either
+        ghost_fat.set_write_protected(write_protect_jumper || read_protection_enabled());
+
+        let mut scsi = Scsi::new(
+            USB_BUS.as_ref().unwrap(), 
+            64,
+            ghost_fat,
+            "Fake Co.",
+            "Fake product",
+            "FK01",
+        );
+
+        // Allocated after the mass storage interface so it's interfaces 1 and 2
+        let serial = SerialPort::new(USB_BUS.as_ref().unwrap());
+        
+        let serial_number = get_serial_number();
+        info!("Serial number: {}", serial_number);
+
+        // Lets the host identify the drive across reconnects
+        scsi.set_unit_serial_number(serial_number);
+
+        let usb_dev = UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(USB_VID, USB_PID))
+            .manufacturer("Fake company")
+            .product("UF2 bootloader")
+            .serial_number(serial_number)
+            .self_powered(true)
+            // Both functions are preceded by an interface association descriptor. This also sets
+            // the device class but it's spelled out so the descriptors are obvious from here
+            .composite_with_iads()
+            .device_class(USB_CLASS_MISCELLANEOUS)
+            .device_sub_class(USB_SUBCLASS_COMMON)
+            .device_protocol(USB_PROTOCOL_IAD)
+            .build();
+
+        init::LateResources { 
+            usb_dev, 
+            scsi, 
+            serial,
+            console,
+            tick_timer,
+        }
+    }
+
+    #[task(binds = USB_HP_CAN_TX, resources = [usb_dev, scsi, serial, console])]
+    fn usb_tx(mut cx: usb_tx::Context) {
+        usb_poll(
+            &mut cx.resources.usb_dev,
+            &mut cx.resources.scsi,
+            &mut cx.resources.serial,
+            &mut cx.resources.console,
+        );
+    }
+
+    #[task(binds = USB_LP_CAN_RX0, resources = [usb_dev, scsi, serial, console])]
+    fn usb_rx0(mut cx: usb_rx0::Context) {
+        usb_poll(
+            &mut cx.resources.usb_dev,
+            &mut cx.resources.scsi,
+            &mut cx.resources.serial,
+            &mut cx.resources.console,
+        );
+    }
+
+    #[task(binds = TIM2, resources = [scsi, tick_timer])]
+    fn tick(cx: tick::Context) {
+        cx.resources
+          .tick_timer
+          .clear_update_interrupt_flag();
+
+        cx.resources
+          .scsi
+          .block_device_mut()
+          .tick(TICK_MS);
+
+        // Flash erases don't block, retry anything GhostFat returned WouldBlock for since the
+        // endpoint is NAKing and won't generate a USB interrupt to do it
+        cx.resources
+          .scsi
+          .resume();
+
+    }
+};
+
+fn usb_poll<B: bus::UsbBus>(
+    usb_dev: &mut UsbDevice<'static, B>,
+    scsi: &mut Scsi<'static, B, GhostFat<FlashWrapper>>,
+    serial: &mut SerialPort<'static, B>,
+    console: &mut Console,
+) {
+    if !usb_dev.poll(&mut [scsi, serial]) {
+        return;
+    }
+
+    let mut buf = [0; 64];
+
+    match serial.read(&mut buf) {
+        Ok(count) => console.receive(serial, &buf[..count]),
+        Err(UsbError::WouldBlock) => {},
+        Err(e) => info!("Err: {:?}", e),
+    }
+}
+
+
+#[panic_handler]
+fn panic(
+    #[cfg_attr(not(feature = "itm"), allow(unused_variables))]
+    info: &PanicInfo
+) -> ! {
+    interrupt::disable();
+
+    #[cfg(feature = "itm")]
+    {
+        let itm = unsafe { &mut *ITM::ptr() };
+        let stim = &mut itm.stim[0];
+
+        iprintln!(stim, "{}", info);
+    }
+
+    loop {
+        // add some side effect to prevent this from turning into a UDF instruction
+        // see rust-lang/rust#28728 for details
+        atomic::compiler_fence(Ordering::SeqCst)
+    }
+}
\ No newline at end of file
@@ -51,7 +51,6 @@ use usb_bootloader::hardware_extra::*;
 // VID and PID are from dapboot bluepill bootloader
 const USB_VID: u16 = 0x1209; 
 const USB_PID: u16 = 0xDB42;
-const USB_CLASS_MISCELLANEOUS: u8 =  0xEF;
 
 #[cfg(feature = "itm")] 
 use cortex_m::{iprintln, peripheral::ITM};
@@ -28,10 +28,14 @@ pub trait Flash {
         while self.is_operation_pending() {}
     }
 
-    /// Erase the page at the given address
+    /// Start erasing the page at the given address
     ///
-    /// Check the address is valid but don't check if erase is necessary, that's done in flush_page
-    fn erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError>;
+    /// Check the address is valid but don't check if erase is necessary, that's done in flush_page.
+    /// The erase isn't finished until `finish_erase_page` returns something other than `WouldBlock`
+    fn start_erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError>;
+
+    /// Finish an erase started by `start_erase_page`, returns `WouldBlock` while the flash is busy
+    fn finish_erase_page(&mut self, page_address: u32) -> nb::Result<(), BlockDeviceError>;
 
     /// Check if the page is empty
     fn is_page_erased(&mut self, page_address: u32) -> bool;
@@ -54,21 +58,34 @@ pub trait Flash {
 
     /// Save the current contents of the page buffer to flash at the address it was read from
     ///
-    /// Check that erase and/or write are really necessary
-    fn flush_page(&mut self) -> Result<(), BlockDeviceError>;
+    /// Check that erase and/or write are really necessary. Returns `WouldBlock` while a page erase
+    /// is in progress, call it again until it returns something else
+    fn flush_page(&mut self) -> nb::Result<(), BlockDeviceError>;
 
     /// Write the provided bytes to flash at the provided address
     ///
-    /// Each touched page will be read into a buffer and flushed back to flash when the next page
-    /// is modified or at the end of the function. 
-    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), BlockDeviceError> {
+    /// Same as `buffer_bytes` followed by `flush_page` so nothing is left in the page buffer
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> nb::Result<(), BlockDeviceError> {
+        self.buffer_bytes(address, bytes)?;
+        self.flush_page()
+    }
+
+    /// Copy the provided bytes into the page buffer for the provided address
+    ///
+    /// Each touched page is read into the buffer and flushed back to flash when the next page is
+    /// modified. The last page stays in the buffer until `flush_page` is called so consecutive
+    /// writes to the same page only erase and write it once. Returns `WouldBlock` while a page
+    /// erase is in progress, call it again with the same arguments until it returns something else
+    fn buffer_bytes(&mut self, address: u32, bytes: &[u8]) -> nb::Result<(), BlockDeviceError> {
         let start_page = self.page_address(address);
         let end_page = self.page_address(address + bytes.len() as u32 - 1);
         let page_size = self.page_size() as usize;
 
         for page in (start_page..=end_page).step_by(page_size) {
             if let Some(cp) = self.current_page() {
-                // If there's a page in the buffer and it's not the current one, flush it
+                // If there's a page in the buffer and it's not the current one, flush it. If the flush
+                // would block we're called again with the same bytes so copying them into the buffer
+                // again below doesn't hurt
                 if *cp != page {
                     self.flush_page()?;
                     self.read_page(page)?;
@@ -87,12 +104,10 @@ pub trait Flash {
                 self.page_buffer()[..count].copy_from_slice(&bytes[offset..(offset + count)]);
             }
         }
-        // Flush the last page 
-        self.flush_page()?;
 
         Ok(())
     }
 
-    /// Read bytes from the provided address
+    /// Read bytes from the provided address, including anything `buffer_bytes` hasn't flushed yet
     fn read_bytes(&self, address: u32, bytes: &mut [u8]) -> Result<(), BlockDeviceError>;
 }
\ No newline at end of file
@@ -0,0 +1,327 @@
+//! Flash access for the STM32F1 used by [GhostFat](../ghost_fat/struct.GhostFat.html)
+use core::{
+    ops::RangeInclusive,
+    ptr::{
+        read_volatile,
+        write_volatile,
+    },
+};
+use stm32f1xx_hal::pac::FLASH;
+use usbd_scsi::BlockDeviceError;
+use itm_logger::*;
+
+use crate::flash::Flash;
+
+/// Reads and writes the internal flash a page at a time
+pub struct FlashWrapper {
+    page_size: u32,
+    min_address: u32,
+    max_address: u32,
+    page_buffer: [u8; 2048],
+    current_page: Option<u32>,
+    /// `flush_page` has started erasing the current page and is waiting for it to finish
+    erase_pending: bool,
+}
+
+impl FlashWrapper {
+    /// `page_size` must be 1024 or 2048. Only addresses in `min_address..=max_address` are read or written
+    pub fn new(page_size: u32, min_address: u32, max_address: u32) -> Self {
+        assert!(page_size == 1024 || page_size == 2048);
+        Self {
+            page_size,
+            min_address,
+            max_address,
+            page_buffer: [0; 2048],
+            current_page: None,
+            erase_pending: false,
+        }
+    }
+
+    pub fn max_address(&self) -> u32 {
+        self.max_address
+    }
+}
+
+impl Flash for FlashWrapper {
+    // Return the page size in bytes
+    fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    fn address_range(&self) -> RangeInclusive<u32> {
+        self.min_address..=self.max_address
+    }
+
+    fn current_page(&self) -> &Option<u32> {
+        &self.current_page
+    }
+
+    fn page_buffer(&mut self) -> &mut [u8] {
+        &mut self.page_buffer[..(self.page_size as usize)]
+    }
+
+    // Unlock the flash for erasing/writing
+    fn unlock_flash(&mut self) -> Result<(), BlockDeviceError> {
+        const KEY1: u32 = 0x45670123;
+        const KEY2: u32 = 0xCDEF89AB;
+
+        let flash = unsafe {
+            &(*FLASH::ptr())
+        };
+
+        if flash.cr.read().lock().bit_is_set() {
+            // Unlock flash
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY1) });
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY2) });
+        }
+
+        if flash.cr.read().lock().bit_is_set() {
+            error!("Flash still locked after performing unlock sequence");
+            Err(BlockDeviceError::HardwareError)?;
+        }
+
+        Ok(())
+    }
+
+    // Lock the flash to prevent erasing/writing
+    fn lock_flash(&mut self) -> Result<(), BlockDeviceError> {
+        let flash = unsafe {
+            &(*FLASH::ptr())
+        };
+
+        flash.cr.modify(|_, w| w.lock().set_bit());
+
+        Ok(())
+    }
+
+    // Is the flash busy?
+    fn is_operation_pending(&self) -> bool {
+        let flash = unsafe {
+            &(*FLASH::ptr())
+        };
+        flash.sr.read().bsy().bit_is_set()
+    }
+
+    // Check if the page is empty
+    fn is_page_erased(&mut self, page_address: u32) -> bool {
+        for word in (page_address..(page_address+self.page_size())).step_by(4) {
+            let value = unsafe { read_volatile(word as *const u32) };
+            if value != 0xFFFFFFFF {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Start erasing the page at the given address. Don't check if erase is necessary, that's done at a higher level
+    //
+    // Instruction fetches from flash stall until the erase finishes so on this part the core doesn't
+    // get much done in the meantime, but the erase no longer holds up the USB interrupt handler
+    fn start_erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
+        let flash = unsafe {
+            &(*FLASH::ptr())
+        };
+
+        // Make sure the flash is unlocked
+        self.unlock_flash()?;
+
+        // Indicate we want to do a page erase
+        flash.cr.modify(|_, w| w.per().set_bit());
+
+        // Set the address we want to erase
+        flash.ar.write(|w| unsafe { w.far().bits(page_address) });
+
+        // Kick off the operation
+        flash.cr.modify(|_, w| w.strt().set_bit());
+
+        Ok(())
+    }
+
+    fn finish_erase_page(&mut self, page_address: u32) -> nb::Result<(), BlockDeviceError> {
+        let flash = unsafe {
+            &(*FLASH::ptr())
+        };
+
+        if self.is_operation_pending() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // Clear page erase flag
+        flash.cr.modify(|_, w| w.per().clear_bit());
+
+        // Check the erase worked
+        if !self.is_page_erased(page_address) {
+            error!("Page erase failed");
+            Err(BlockDeviceError::EraseError)?;
+        }
+            
+        info!("erased 0x{:X?}", page_address);  
+        
+
+        Ok(())
+    }
+
+    fn read_bytes(&self, address: u32, bytes: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let range = self.address_range();
+        let page_size = self.page_size;
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let hw_addr = address + i as u32;
+            if !range.contains(&hw_addr) {
+                Err(BlockDeviceError::InvalidAddress)?;
+            }
+            // The page buffer may have been written to but not flushed yet
+            *b = match self.current_page {
+                Some(page) if (page..page + page_size).contains(&hw_addr) => {
+                    self.page_buffer[(hw_addr - page) as usize]
+                },
+                _ => unsafe { read_volatile(hw_addr as *const u8) },
+            };
+        }
+
+        Ok(())
+    }
+
+    fn read_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
+        if page_address != self.page_address(page_address) {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+        let range = self.address_range();
+        let buffer = self.page_buffer();
+        for (i, half_word) in buffer.chunks_exact_mut(2).enumerate() {
+            let hw_addr = page_address + i as u32 * 2;
+            if !range.contains(&hw_addr) {
+                Err(BlockDeviceError::InvalidAddress)?;
+            }
+            let value = unsafe { read_volatile(hw_addr as *const [u8; 2]) };
+            half_word.copy_from_slice(&value);
+        }
+
+        self.current_page = Some(page_address);
+
+        Ok(())
+    }
+
+    fn write_page(&mut self) -> Result<(), BlockDeviceError> {
+        let flash = unsafe {
+            &(*FLASH::ptr())
+        };
+
+        let page_address = self.current_page.ok_or(BlockDeviceError::InvalidAddress)?;
+
+        // Make sure the flash is unlocked
+        self.unlock_flash()?;
+
+        let range = self.address_range();
+        let buffer = self.page_buffer();
+
+        let mut half_word = [0; 2];
+        for (i, c) in buffer.chunks_exact(2).enumerate() {
+            let hw_addr = page_address + i as u32 * 2;
+            
+            if !range.contains(&hw_addr) {
+                Err(BlockDeviceError::InvalidAddress)?;
+            }
+
+            half_word.copy_from_slice(c);
+
+            //let value = unsafe { mem::transmute(half_word) };
+
+            let old_value = unsafe { read_volatile(hw_addr as *const [u8; 2]) };
+            if old_value != half_word {
+                info!("0x{:X?}: 0x{:X?} => 0x{:X?}", hw_addr, old_value, half_word); 
+
+                // Indicate we want to write to flash
+                flash.cr.modify(|_, w| w.pg().set_bit());
+
+                // Write the half word
+                unsafe { write_volatile(hw_addr as *mut [u8; 2], half_word); }
+
+                // Wait for write to complete
+                while flash.sr.read().bsy().bit_is_set() {}
+
+                // Clear write flag
+                flash.cr.modify(|_, w| w.pg().clear_bit());  
+
+                let new_value = unsafe { read_volatile(hw_addr as *const [u8; 2]) };
+
+                if new_value != half_word {
+                    error!("write to 0x{:X?} failed", hw_addr);  
+                    Err(BlockDeviceError::WriteError)?;
+                }
+
+                info!("write to 0x{:X?} ok", hw_addr);  
+
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush_page(&mut self) -> nb::Result<(), BlockDeviceError> {
+        let page_address = self.current_page.ok_or(BlockDeviceError::InvalidAddress)?;
+
+        if self.erase_pending {
+            let result = self.finish_erase_page(page_address);
+            if let Err(nb::Error::WouldBlock) = result {
+                return result;
+            }
+            self.erase_pending = false;
+            result?;
+
+            // The page is blank now so everything needs writing
+            info!("Flush: write needed (page: 0x{:X})", page_address);
+            self.write_page()?;
+            return Ok(());
+        }
+
+        let mut erase_needed = false;
+        let mut write_needed = false;
+
+        for (i, b) in self.page_buffer().iter().enumerate() {
+            let hw_addr = page_address + i as u32;
+            let new_value = *b;
+            let old_value = unsafe { read_volatile(hw_addr as *const u8) };
+
+            if old_value == new_value {
+                // Value already on flash, no write or erase needed
+                continue;
+            }
+
+            if old_value & new_value == new_value {
+                // New value can be written over old value without erase
+                write_needed = true;
+                trace!("Write needed: 0x{:X}, 0x{:X} => 0x{:X}", hw_addr, old_value, new_value);
+                continue;
+            } 
+
+            trace!("Erase page: 0x{:X}, 0x{:X} => 0x{:X}", hw_addr, old_value, new_value);
+            // Erase is required
+            erase_needed = true;
+            break;
+        }
+
+        if erase_needed {
+            // The write happens once the erase has finished, on a later call
+            info!("Flush: erase needed (page: 0x{:X})", page_address);
+            self.start_erase_page(page_address)?;
+            self.erase_pending = true;
+            return Err(nb::Error::WouldBlock);
+        }
+
+        if write_needed {
+            info!("Flush: write needed (page: 0x{:X})", page_address);
+            self.write_page()?;
+
+            /*
+            for c in self.page_buffer().chunks(16) {
+                trace!("0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}, 0x{:02X?}", 
+                    c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7],
+                    c[8], c[9], c[10], c[11], c[12], c[13], c[14], c[15],
+                );
+            } 
+            */
+        }
+
+        Ok(())
+    }
+}
\ No newline at end of file
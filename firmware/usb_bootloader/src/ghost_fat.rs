@@ -1,5 +1,6 @@
 use core::{
     arch::asm,
+    convert::TryFrom,
     ptr::read_volatile,
 };
 
@@ -274,13 +275,22 @@ pub struct GhostFat<F: Flash> {
     tick_ms: u32,
     restart_ms: u32,
     backup_domain: BackupDomain,
+    /// Reject UF2 writes and tell the host the drive is read only
+    write_protected: bool,
+    /// Page FORMAT UNIT is erasing or about to erase, None when there's no format running
+    format_address: Option<u32>,
+    /// The erase of `format_address` has been started
+    format_erasing: bool,
 }
 
 impl<F: Flash> BlockDevice for GhostFat<F> {
     const BLOCK_BYTES: usize = BLOCK_SIZE;
-    fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> nb::Result<(), BlockDeviceError> {
         assert_eq!(block.len(), BLOCK_SIZE);
 
+        // The FAT is nowhere near big enough to need 64 bit LBAs
+        let lba = u32::try_from(lba).map_err(|_| BlockDeviceError::InvalidAddress)?;
+
         info!("GhostFAT reading block: 0x{:X?}", lba);
 
         // Clear the buffer since we're sending all of it
@@ -380,7 +390,9 @@ impl<F: Flash> BlockDevice for GhostFat<F> {
         }
         Ok(())
     }
-    fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> nb::Result<(), BlockDeviceError> {
+        let lba = u32::try_from(lba).map_err(|_| BlockDeviceError::InvalidAddress)?;
+
         info!("GhostFAT writing block: 0x{:X?}", lba);
 
         //TODO: Should BDE have an error to represent this kind of protocol error?
@@ -388,7 +400,7 @@ impl<F: Flash> BlockDevice for GhostFat<F> {
         //      a user facing error. The best we can manage is something like a write error
         //      or phase error. Some DFU firmwares report back errors by creating a file 
         //      called error.txt in the root. Could be an option but it's not part of UF2.
-        const PROTOCOL_ERROR: Result<(), BlockDeviceError> = Err(BlockDeviceError::WriteError);
+        const PROTOCOL_ERROR: nb::Result<(), BlockDeviceError> = Err(nb::Error::Other(BlockDeviceError::WriteError));
 
         if lba < (START_CLUSTERS + self.fat_files.len() as u32) {
             info!("    GhostFAT skipping non-UF2 area");
@@ -419,7 +431,14 @@ impl<F: Flash> BlockDevice for GhostFat<F> {
         }
 
         info!("   GhostFAT writing {} bytes of UF2 block at 0x{:X?}", uf2.payload_size, uf2.target_address);          
-        self.flash.write_bytes(uf2.target_address, &uf2.data[..uf2.payload_size as usize])?;
+        // Returns WouldBlock while a page is being erased, Scsi calls us again with the same block.
+        // The page stays in the buffer until it's full or the host synchronizes the cache
+        self.flash.buffer_bytes(uf2.target_address, &uf2.data[..uf2.payload_size as usize])?;
+
+        // Make sure the whole program is in flash before restarting into it
+        if self.uf2_blocks_written + 1 >= uf2.number_of_blocks {
+            self.flash.flush_page()?;
+        }
 
         self.uf2_blocks_written += 1;
 
@@ -431,8 +450,85 @@ impl<F: Flash> BlockDevice for GhostFat<F> {
 
         Ok(())
     }
-    fn max_lba(&self) -> u32 {
-        NUM_FAT_BLOCKS - 1
+    fn compare_block(&mut self, lba: u64, block: &[u8]) -> nb::Result<bool, BlockDeviceError> {
+        let mut medium = [0; BLOCK_SIZE];
+        self.read_block(lba, &mut medium)?;
+        Ok(medium[..] == *block)
+    }
+    fn max_lba(&self) -> u64 {
+        (NUM_FAT_BLOCKS - 1).into()
+    }
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+    fn flush(&mut self) -> nb::Result<(), BlockDeviceError> {
+        if self.flash.current_page().is_none() {
+            return Ok(());
+        }
+        self.flash.flush_page()
+    }
+    fn write_cache_enabled(&self) -> bool {
+        true
+    }
+    fn format(&mut self) -> nb::Result<(), BlockDeviceError> {
+        let page_size = self.flash.page_size();
+        let end = *self.flash.address_range().end();
+
+        let mut address = match self.format_address {
+            Some(address) => address,
+            None => {
+                // Let anything buffered finish writing first so it doesn't land after the erase
+                if self.flash.current_page().is_some() {
+                    self.flash.flush_page()?;
+                }
+                self.uf2_blocks_written = 0;
+                self.app_base_address()
+            },
+        };
+
+        if self.format_erasing {
+            let result = self.flash.finish_erase_page(address);
+            if let Err(nb::Error::WouldBlock) = result {
+                return result;
+            }
+            self.format_erasing = false;
+            if result.is_err() {
+                self.format_address = None;
+                return result;
+            }
+            address += page_size;
+        }
+
+        // Start erasing the next page that isn't blank. One page per call so the format doesn't
+        // hold up the USB interrupt
+        while address + (page_size - 1) <= end {
+            if !self.flash.is_page_erased(address) {
+                if let Err(e) = self.flash.start_erase_page(address) {
+                    self.format_address = None;
+                    Err(e)?;
+                }
+                self.format_erasing = true;
+                self.format_address = Some(address);
+                return Err(nb::Error::WouldBlock);
+            }
+            address += page_size;
+        }
+
+        self.format_address = None;
+        info!("Format done");
+
+        // The page buffer still holds what was there before the erase
+        if let Some(page) = *self.flash.current_page() {
+            self.flash.read_page(page)?;
+        }
+        Ok(())
+    }
+    fn format_progress(&self) -> u16 {
+        let start = self.app_base_address();
+        let bytes = u64::from(*self.flash.address_range().end() - start) + 1;
+        self.format_address.map_or(0, |address| {
+            (u64::from(address - start) * 0x10000 / bytes).min(0xFFFF) as u16
+        })
     }
 }
 
@@ -478,6 +574,9 @@ impl<F: Flash> GhostFat<F> {
             tick_ms: 0,
             restart_ms: 0,
             backup_domain,
+            write_protected: false,
+            format_address: None,
+            format_erasing: false,
         };
 
         gf.bootloader_check();
@@ -485,6 +584,11 @@ impl<F: Flash> GhostFat<F> {
         gf
     }
 
+    /// Expose the drive read only so the application can't be replaced
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
     // Read the command out of the backup register and reset the register to 0
     fn take_backup_command(&self) -> u32 {
         let cmd = read_u32_backup_register(&self.backup_domain, BACKUP_REGISTER);
@@ -2,6 +2,7 @@
 /// Something similar exists for some HAL implementations but not the f1
 
 use core::str::from_utf8_unchecked;
+use stm32f1xx_hal::pac::FLASH;
 
 pub const ITM_BAUD_RATE: u32 = 8_000_000;
 const SERIAL_NUMBER_LEN: usize = 24;
@@ -92,4 +93,13 @@ pub fn get_serial_number() -> &'static str {
 
 pub fn get_flash_kibi() -> u16 {
     FlashSize::get().kibi_bytes()
+}
+
+/// Read out protection is set in the option bytes. The application is locked so it can't be
+/// read back over SWD and mustn't be replaced either
+pub fn read_protection_enabled() -> bool {
+    let flash = unsafe {
+        &(*FLASH::ptr())
+    };
+    flash.obr.read().rdprt().bit_is_set()
 }
\ No newline at end of file
@@ -4,4 +4,8 @@ pub mod hardware_extra;
 
 pub mod ghost_fat;
 
-pub mod flash;
\ No newline at end of file
+pub mod flash;
+
+pub mod flash_wrapper;
+
+pub mod usb_class;
\ No newline at end of file
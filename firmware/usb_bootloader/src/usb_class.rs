@@ -0,0 +1,8 @@
+//! Device class codes shared by the bootloader binaries
+
+/// Composite device with interface association descriptors, device class, subclass and protocol
+///
+/// [USB IAD ECN](https://www.usb.org/sites/default/files/iadclasscode_r10.pdf)
+pub const USB_CLASS_MISCELLANEOUS: u8 = 0xEF;
+pub const USB_SUBCLASS_COMMON: u8 = 0x02;
+pub const USB_PROTOCOL_IAD: u8 = 0x01;
\ No newline at end of file
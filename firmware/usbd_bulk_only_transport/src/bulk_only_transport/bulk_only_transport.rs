@@ -1,15 +1,16 @@
+use core::fmt::Debug;
 use usb_device::class_prelude::*;
 use usb_device::{
     Result as UsbResult,
     control::{
         RequestType,
+        Recipient,
         Request,
     },
 };
 pub use UsbError::WouldBlock;
 
 use packing::{
-    Error as PackingError,
     Packed,
     PackedSize,
 };
@@ -18,6 +19,10 @@ use usbd_mass_storage::{
     MscClass,
     InterfaceSubclass,
     InterfaceProtocol,
+    Transport,
+    CommandBlock,
+    TransferBuffer,
+    DEFAULT_BUFFER_BYTES,
 };
 use crate::logging::*;
 use super::{
@@ -25,32 +30,14 @@ use super::{
     CommandStatusWrapper,
     Direction,
     CommandStatus,
+    TransferState,
+    Error,
 };
 
 
 const REQ_GET_MAX_LUN: u8 = 0xFE;
 const REQ_BULK_ONLY_RESET: u8 = 0xFF;
 
-const BUFFER_BYTES: usize = 512;
-
-#[derive(Debug)]
-pub enum Error {
-    UsbError(UsbError),
-    PackingError(PackingError),
-    DataError,
-}
-
-impl From<UsbError> for Error {
-    fn from(e: UsbError) -> Error {
-        Error::UsbError(e)
-    }
-}
-impl From<PackingError> for Error {
-    fn from(e: PackingError) -> Error {
-        Error::PackingError(e)
-    }
-}
-
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 enum State {
@@ -60,34 +47,23 @@ enum State {
     WaitingForCommand,
     /// Command initiated a transfer to the host (IN in USB parlance). Sends the 
     /// number of bytes the command asked for unless instructed to terminate early. 
-    /// Moves to NeedToSendStatus or NeedZlp
+    /// Moves to NeedToSendStatus
     SendingDataToHost,
     /// Command initiated a transfer from the host (OUT in USB parlance). Reads the 
-    /// number of bytes the command asked to send. Moves to NeedToSendStatus or NeedZlp
+    /// number of bytes the command asked to send. Moves to NeedToSendStatus
     ReceivingDataFromHost,
-    /// Need to send a zero length packet because the transfer was shorter than
-    /// the requested length and we ended on a full packet. Sends a ZLP then moves to
-    /// NeedToSendStatus
-    NeedZlp,
-    /// Data transfer has finished. Sends a command block status packet
+    /// An invalid CBW was received. Both endpoints stay stalled until the host performs
+    /// reset recovery (bulk only mass storage reset followed by clear feature endpoint halt
+    /// on both endpoints). Moves to WaitingForCommand
+    WaitingForResetRecovery,
+    /// Data transfer has finished. Sends a command block status packet once the bulk
+    /// in endpoint isn't halted
     NeedToSendStatus,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum TransferState {
-    /// There is no data transfer underway, we are either waiting for a command or
-    /// about to send ZLP/CSW after a transfer has finished. We might be sending a CSW
-    /// so `bytes_remaining` and `empty` use the same logic as `SendingDataToHost`
-    NotTransferring { bytes_remaining: usize, empty: bool },
-    /// We are receving data from the host, `bytes_available` indicates how many bytes
-    /// have been read into the buffer. `full` indicates we're out of buffer space and
-    /// need the caller to process the data before we can do more work. `done` indicates
-    /// data_residue has reached 0 and there won't be any more data read
-    ReceivingDataFromHost { bytes_available: usize, full: bool, done: bool },
-    /// We are sending data to the host, `bytes_remaining` indicates how many bytes are 
-    /// still in the buffer. `empty` indicates the buffer is empty and we can't do any
-    /// more work before the caller gives us more data
-    SendingDataToHost { bytes_remaining: usize, empty: bool },
+    /// A bulk only mass storage reset was ACKed but the command set hasn't finished resetting
+    /// yet. Neither bulk endpoint is read or written so the host's next CBW is NAKed until
+    /// `finish_reset` is called. Moves to WaitingForCommand or WaitingForResetRecovery if the reset
+    /// failed
+    ResetPending,
 }
 
 /// # USB Bulk Only Transport protocol
@@ -97,24 +73,29 @@ pub enum TransferState {
 /// [Glossary](index.html#glossary)
 /// 
 /// ## Functionality overview
-/// 1. Reading CBWs
+/// 1. Reading CBWs and checking they are valid and meaningful
 /// 1. Initiating a data transfer with the length and direction from the CBW
 /// 1. Sending USB packets to the underlaying driver when there is data in the buffer
 /// 1. Terminating the data transfer when enough data is processed or early termination is requested
-/// 1. Adding ZLP when necessary
+/// 1. Comparing what the host asked for with what the command set intends to transfer and handling
+///    any mismatch with endpoint stalls and phase errors - the thirteen cases from Section 6.7
+///    [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
 /// 1. Sending CSW with correct data residue
+/// 1. Stalling both endpoints when an invalid CBW is received and keeping them stalled until the
+///    host performs reset recovery
 /// 1. Responding to class specific control requests (bulk only reset and get max lun)
+/// 1. Bulk only mass storage reset. The request is ACKed straight away but the bulk endpoints are
+///    held until the command set has finished resetting, however long that takes - see
+///    `finish_reset`
+/// 1. Responding to get max lun with `max_lun`. The LUN in each CBW is passed through untouched,
+///    routing commands to the right logical unit is up to the command set implementation
 ///
-/// ## Unimplemented/Untested:
-/// 1. More than 1 LUN - the SCSI implementation used for testing uses LUN 0 and responds to requests
-///    for any other LUN with CommandError. This class will respond to get max lun correctly for any
-///    valid value of `max_lun` but I haven't implemented anything that uses more than 1 LUN
-/// 1. Bulk only mass storage reset that takes any length of time - the spec (Section 3.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10))
-///    allows for the reset request to kick off the reset and have the host wait/poll until the reset
-///    is done. This is likely for devices where the reset might take a long (relative to poll rate) time.
-///    This isn't implemented here.
+/// ## Buffer size
+/// `BUFFER_BYTES` sets the size of the buffer used for both directions of the data transfer. It must
+/// be a multiple of the max packet size. Larger buffers allow the command set implementation to
+/// queue up more data per poll at the expense of RAM.
 ///
-pub struct BulkOnlyTransport<'a, B: UsbBus> {
+pub struct BulkOnlyTransport<'a, B: UsbBus, const BUFFER_BYTES: usize = DEFAULT_BUFFER_BYTES> {
     inner: MscClass<'a, B>,
     
     /// This is the response this class will give to the Get Max LUN request
@@ -130,33 +111,64 @@ pub struct BulkOnlyTransport<'a, B: UsbBus> {
     /// The previous command's CSW. 
     command_status_wrapper: CommandStatusWrapper,
 
-    /// The buffer, used for both reading and writing
-    buffer: [u8; BUFFER_BYTES],
+    /// Used for the CBW, the data phase and the CSW. The data phase starts as the length in the
+    /// CBW but is reduced by `set_device_intent` if the command set intends to transfer less
+    buffer: TransferBuffer<BUFFER_BYTES>,
+
+    /// Set when we stall the read (OUT) endpoint, cleared when the host clears the halt
+    read_ep_halted: bool,
+
+    /// Set when we stall the write (IN) endpoint, cleared when the host clears the halt
+    write_ep_halted: bool,
 
-    /// The next free index in the buffer
-    buffer_i: usize,
+    /// Accept CBWs that aren't strictly valid or meaningful, see `set_lenient_cbw_validation`
+    lenient_cbw_validation: bool,
+}
+
+impl<'a, B: UsbBus, const BUFFER_BYTES: usize> BulkOnlyTransport<'a, B, BUFFER_BYTES> {
+    /// The underlying class. For transports that use bulk only as a fallback alternate setting and
+    /// share its interface and endpoints
+    pub fn msc_class(&self) -> &MscClass<'a, B> {
+        &self.inner
+    }
 
-    /// The next data to send in the buffer
-    data_i: usize,
+    pub fn msc_class_mut(&mut self) -> &mut MscClass<'a, B> {
+        &mut self.inner
+    }
+
+    /// The data phase buffer. For transports that use bulk only as a fallback alternate setting
+    /// and only need one buffer between them. Bulk only doesn't touch it while another alternate
+    /// setting is selected
+    pub fn transfer_buffer(&self) -> &TransferBuffer<BUFFER_BYTES> {
+        &self.buffer
+    }
 
-    /// Tracks if the last write was a full packet or not
-    /// Used to determine if a ZLP is required or not
-    last_packet_full: bool,
+    pub fn transfer_buffer_mut(&mut self) -> &mut TransferBuffer<BUFFER_BYTES> {
+        &mut self.buffer
+    }
 
-    /// Indicates we are going to end the current data transaction after draining the current 
-    /// buffer regardless of data_residue
-    data_done: bool,
+    /// The underlying class and the data phase buffer at the same time, for sending and receiving
+    pub fn msc_class_and_transfer_buffer_mut(&mut self) -> (&mut MscClass<'a, B>, &mut TransferBuffer<BUFFER_BYTES>) {
+        (&mut self.inner, &mut self.buffer)
+    }
 }
 
-impl<B: UsbBus> BulkOnlyTransport<'_, B> {
+impl<B: UsbBus, const BUFFER_BYTES: usize> BulkOnlyTransport<'_, B, BUFFER_BYTES> {
     pub const BUFFER_BYTES: usize = BUFFER_BYTES;
+
+    /// Creates a new BulkOnlyTransport
+    ///
+    /// Panics if `max_lun` > 15 or if `BUFFER_BYTES` isn't a non-zero multiple of `max_packet_size`
     pub fn new(
         alloc: &UsbBusAllocator<B>, 
         max_packet_size: u16, 
         subclass: InterfaceSubclass,
         max_lun: u8,
-    ) -> BulkOnlyTransport<'_, B> {
+    ) -> BulkOnlyTransport<'_, B, BUFFER_BYTES> {
         assert!(max_lun < 16);
+        // Packets are only read when there's space for a full one so anything left over at the
+        // end of the buffer would never be filled
+        assert!(BUFFER_BYTES > 0 && BUFFER_BYTES.is_multiple_of(max_packet_size as usize));
         BulkOnlyTransport {
             inner: MscClass::new(
                 alloc, 
@@ -168,11 +180,90 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
             state: State::WaitingForCommand,
             command_block_wrapper: Default::default(),
             command_status_wrapper: Default::default(),
-            buffer: [0; BUFFER_BYTES],
-            buffer_i: 0,
-            data_i: 0,
-            last_packet_full: false,
-            data_done: false,
+            buffer: TransferBuffer::new(),
+            read_ep_halted: false,
+            write_ep_halted: false,
+            lenient_cbw_validation: false,
+        }
+    }
+
+    /// Relaxes the checks made on CBWs for hosts that don't follow the spec
+    ///
+    /// By default a CBW must be exactly 31 bytes, start with the signature, have no reserved bits
+    /// set, be for a LUN <= `max_lun` and have a command block length of 1 to 16. Anything else
+    /// stalls both endpoints until the host performs reset recovery. In lenient mode bytes before
+    /// the signature and after the CBW are discarded, reserved bits are ignored and the LUN and
+    /// command block length are passed through to the command set unchecked.
+    pub fn set_lenient_cbw_validation(&mut self, lenient: bool) {
+        self.lenient_cbw_validation = lenient;
+    }
+
+    /// Is a bulk only mass storage reset waiting for `finish_reset`
+    pub fn reset_pending(&self) -> bool {
+        self.state == State::ResetPending
+    }
+
+    /// Ends a bulk only mass storage reset once the command set has reset everything it needs to
+    ///
+    /// The spec lets the device NAK the status stage of the reset request until it's done, but
+    /// usb-device only lets a class accept or reject a control transfer from within the callback.
+    /// So the request is ACKed as soon as it arrives and only the bulk side is held: until this is
+    /// called nothing is read from or written to the bulk endpoints so the host can't start the
+    /// next command. Section 3.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+    /// If `ok` is false both endpoints are stalled and the host has to perform reset recovery again
+    pub fn finish_reset(&mut self, ok: bool) {
+        if self.state != State::ResetPending {
+            return;
+        }
+        if ok {
+            self.change_state(State::WaitingForCommand);
+        } else {
+            self.stall_for_reset_recovery();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        let handled_res = match req {
+            // Clear feature endpoint halt for one of our endpoints. This is normally handled by UsbDevice
+            // but we need to track the halt state and the spec requires the endpoints to stay halted
+            // until a bulk only reset if we received an invalid CBW
+            // Section 6.6.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+            Request {
+                request_type: RequestType::Standard,
+                recipient: Recipient::Endpoint,
+                request: Request::CLEAR_FEATURE,
+                value: Request::FEATURE_ENDPOINT_HALT,
+                index,
+                ..
+            } if self.inner.is_read_ep(index) || self.inner.is_write_ep(index) => {
+                if self.state == State::WaitingForResetRecovery {
+                    trace_usb_control!("USB_CONTROL> Clear feature endpoint halt ignored, waiting for reset recovery");
+                } else {
+                    self.clear_halt(index);
+                }
+                Some(xfer.accept())
+            },
+
+            // Bulk only mass storage reset. ACKed now, the bulk endpoints are held until `finish_reset`
+            Request { request_type: RequestType::Class, request: REQ_BULK_ONLY_RESET, index, .. }
+                if self.inner.correct_interface_number(index) =>
+            {
+                trace_usb_control!("USB_CONTROL> Bulk only mass storage reset");
+                self.bulk_only_reset();
+                self.change_state(State::ResetPending);
+                Some(xfer.accept())
+            },
+
+            _ => {
+                self.inner.control_out(xfer);
+                None
+            },
+        };
+
+        if let Some(Err(e)) = handled_res {
+            error!("Error from ControlOut.accept: {:?}", e);
         }
     }
 
@@ -185,6 +276,10 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     pub fn read(&mut self) -> Result<(), Error> {
+        if self.read_ep_halted {
+            return Ok(());
+        }
+
         match self.state {
             State::WaitingForCommand => self.waiting_for_command(),
             State::ReceivingDataFromHost => self.receiving_data_from_host(),
@@ -193,54 +288,115 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     pub fn write(&mut self) -> Result<(), Error> {
+        if self.write_ep_halted {
+            return Ok(());
+        }
+
         match self.state {
             State::SendingDataToHost => self.sending_data_to_host(),
-            State::NeedZlp => self.send_zlp(),
             State::NeedToSendStatus => self.need_to_send_status(),
             _ => Ok(()),
         }
     }
 
     fn waiting_for_command(&mut self) -> Result<(), Error> {
-        if self.buffer.len() - self.buffer_i < (self.max_packet_size() as usize) {
-            trace_bot_buffer!("BUFFER> too full to read command");
-            Err(WouldBlock)?;
+        let bytes = self.buffer.read_packet(&mut self.inner)?;
+        trace_bot_bytes!("BYTES> Read {} bytes for command", bytes);
+
+        let cbw = if self.lenient_cbw_validation {
+            self.lenient_cbw()
+        } else {
+            self.strict_cbw(bytes)
+        };
+
+        match cbw {
+            Ok(None) => {},
+            Err(Error::DataError) => {
+                // There's no command to report a status for so the host has to reset us
+                // Section 6.6.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+                self.stall_for_reset_recovery();
+            },
+            Err(e) => Err(e)?,
+            Ok(Some(cbw)) => {
+                self.transition_to_data(cbw);
+
+                // After transitioning to data, we need to read but we might not get another interrupt
+                // TODO: dig into this a bit. It seems that we *should* get an interrupt since the last
+                // read that initiated the command should have drained the RX buffer. Depending on if
+                // logging is enabled or not it appears to sometimes work and sometimes not which makes
+                // me think it's a timing issue. In the hardware example I'm testing with RTFM appears
+                // to be clearing the 
+                self.read()?;
+            },
         }
 
-        let bytes = self.inner.read_packet(&mut self.buffer[self.buffer_i..])?;
-        trace_bot_bytes!("BYTES> Read {} bytes for command", bytes);
-        self.buffer_i += bytes;
+        Ok(())
+    }
+
+    /// Unpacks the CBW in the buffer if it's valid and meaningful
+    /// Sections 6.2.1 and 6.2.2 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+    ///
+    /// Returns `Ok(None)` if more packets are needed and `Error::DataError` if the CBW is invalid
+    fn strict_cbw(&mut self, last_packet_bytes: usize) -> Result<Option<CommandBlockWrapper>, Error> {
+        // The CBW transfer ends with a short packet. It's only more than one packet with
+        // a max packet size < 32
+        let received = self.buffer.bytes_buffered();
+        if last_packet_bytes == self.max_packet_usize() && received < CommandBlockWrapper::BYTES {
+            return Ok(None);
+        }
+
+        if received != CommandBlockWrapper::BYTES {
+            warn!("CBW invalid, {} bytes received", received);
+            Err(Error::DataError)?;
+        }
+
+        let data = self.buffer.data_mut();
+        if !CommandBlockWrapper::check_signature(data) {
+            warn!("CBW invalid, bad signature");
+            Err(Error::DataError)?;
+        }
+
+        let cbw = CommandBlockWrapper::unpack(data)
+            .map_err(|e| {
+                warn!("CBW unpack error: {:?}", e);
+                Error::DataError
+            })?;
+
+        if !cbw.is_meaningful(self.max_lun) {
+            warn!("CBW not meaningful, lun: {}, length: {}", cbw.lun, cbw.data_length);
+            Err(Error::DataError)?;
+        }
+
+        Ok(Some(cbw))
+    }
+
+    /// Unpacks the CBW in the buffer skipping anything before the signature, ignoring
+    /// reserved bits and not checking it's meaningful
+    ///
+    /// Returns `Ok(None)` if more packets are needed and `Error::DataError` if the CBW is invalid
+    fn lenient_cbw(&mut self) -> Result<Option<CommandBlockWrapper>, Error> {
         // This looks for the signature and throws away any bytes until it finds one
-        let new_i = CommandBlockWrapper::truncate_to_signature(&mut self.buffer[..self.buffer_i]);
-        if self.buffer_i != new_i {
-            trace_bot_headers!("HEADER> Discarded {} bytes looking for command block wrapper signature", self.buffer_i - new_i);
-            self.buffer_i = new_i;
+        let received = self.buffer.bytes_buffered();
+        let new_len = CommandBlockWrapper::truncate_to_signature(self.buffer.data_mut());
+        if received != new_len {
+            trace_bot_headers!("HEADER> Discarded {} bytes looking for command block wrapper signature", received - new_len);
+            self.buffer.truncate(new_len);
         }
 
-        if self.buffer_i >= CommandBlockWrapper::BYTES {
-            trace_bot_buffer!("BUFFER> full enough to try deserializing command block wrapper");
-            let cbw = CommandBlockWrapper::unpack(&self.buffer)
-                // We don't want to return a PackingError here because that's a fatal error
-                // TODO: Possibly should set PhaseError?
-                .map_err(|_| Error::DataError);
-            if cbw.is_err() {
-                let err = cbw.err().unwrap();
-                warn!("CBW unpack error: {:?}", err);
-                self.buffer_i = 0;
-                return Err(err);
-            }
-            self.transition_to_data(cbw?);
-
-            // After transitioning to data, we need to read but we might not get another interrupt
-            // TODO: dig into this a bit. It seems that we *should* get an interrupt since the last
-            // read that initiated the command should have drained the RX buffer. Depending on if
-            // logging is enabled or not it appears to sometimes work and sometimes not which makes
-            // me think it's a timing issue. In the hardware example I'm testing with RTFM appears
-            // to be clearing the 
-            self.read()?;
+        if new_len < CommandBlockWrapper::BYTES {
+            return Ok(None);
         }
 
-        Ok(())
+        trace_bot_buffer!("BUFFER> full enough to try deserializing command block wrapper");
+        let data = &mut self.buffer.data_mut()[..CommandBlockWrapper::BYTES];
+        CommandBlockWrapper::clear_reserved_bits(data);
+        let cbw = CommandBlockWrapper::unpack(data)
+            .map_err(|e| {
+                warn!("CBW unpack error: {:?}", e);
+                Error::DataError
+            })?;
+
+        Ok(Some(cbw))
     }
 
     // Updates the command status wrapper in readiness to execute the provided command.
@@ -252,6 +408,49 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         self.command_status_wrapper.status = CommandStatus::CommandOk;
     }
 
+    fn stall_read_ep(&mut self) {
+        trace_bot_states!("STATE> Stalling bulk out");
+        self.inner.stall_read_ep();
+        self.read_ep_halted = true;
+    }
+
+    fn stall_write_ep(&mut self) {
+        trace_bot_states!("STATE> Stalling bulk in");
+        self.inner.stall_write_ep();
+        self.write_ep_halted = true;
+    }
+
+    fn stall_for_reset_recovery(&mut self) {
+        self.buffer.clear();
+        self.stall_read_ep();
+        self.stall_write_ep();
+        self.change_state(State::WaitingForResetRecovery);
+    }
+
+    /// Bulk only mass storage reset. Abandons the current command and gets ready for the next CBW.
+    /// Halted endpoints stay halted until the host clears them
+    fn bulk_only_reset(&mut self) {
+        self.buffer.reset();
+        self.change_state(State::WaitingForCommand);
+    }
+
+    fn clear_halt(&mut self, index: u16) {
+        if self.inner.is_read_ep(index) {
+            trace_usb_control!("USB_CONTROL> Clear feature endpoint halt, bulk out");
+            self.inner.unstall_read_ep();
+            self.read_ep_halted = false;
+        } else if self.inner.is_write_ep(index) {
+            trace_usb_control!("USB_CONTROL> Clear feature endpoint halt, bulk in");
+            self.inner.unstall_write_ep();
+            self.write_ep_halted = false;
+
+            // A CSW might have been waiting for this
+            if let Err(e) = self.send_status_now() {
+                error!("Error sending CSW after clear halt: {:?}", e);
+            }
+        }
+    }
+
     fn change_state(&mut self, new_state: State) {
         trace_bot_states!("STATE> {:?} -> {:?}",
             self.state,
@@ -262,12 +461,8 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
 
     fn transition_to_data(&mut self, cbw: CommandBlockWrapper) {
         trace_bot_headers!("HEADER> CommandBlockWrapper: {:X?}", cbw);
-        // Reset the positions in the buffer
-        self.buffer_i = 0;
-        self.data_i = 0;
-        
-        // Reset the data_done override
-        self.data_done = false;
+        // Assume the device wants to transfer what the host asked for until told otherwise
+        self.buffer.start(cbw.data_transfer_length);
 
         // Update the csw so we can send that after the data
         self.prepare_for_command(&cbw);
@@ -286,10 +481,60 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         self.command_block_wrapper = cbw;
     }
 
+    /// Tells the transport what the command set intends to do in the data phase of the current command
+    ///
+    /// `bytes` is how much data the command will transfer in `direction`. 0 means no data will be
+    /// transferred, in which case `direction` is ignored. This is compared with the CBW to work out
+    /// which of the thirteen cases (Section 6.7 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10))
+    /// applies. Should be called once per command before any data is transferred. If it isn't called
+    /// the transport assumes the command intends to transfer exactly what the host asked for.
+    ///
+    /// Returns `Error::PhaseError` if the direction doesn't match or the command intends to transfer
+    /// more than the host asked for. The phase error has already been reported to the host so the
+    /// command should be abandoned without sending a status.
+    pub fn set_device_intent(&mut self, direction: Direction, bytes: u32) -> Result<(), Error> {
+        match self.state {
+            State::SendingDataToHost |
+            State::ReceivingDataFromHost => {},
+            _ => return Ok(()),
+        }
+
+        let host_bytes = self.command_block_wrapper.data_transfer_length;
+        let host_direction = self.command_block_wrapper.direction;
+
+        // Some data may have arrived along with the CBW before we knew what the command wanted
+        let transferred = host_bytes - self.command_status_wrapper.data_residue;
+
+        trace_bot_states!("STATE> Host intends {:?} {} bytes, device intends {:?} {} bytes",
+            host_direction, host_bytes, direction, bytes);
+
+        if bytes == 0 {
+            // Cases 1, 4 & 9. If the host expected data the endpoint is stalled when the command completes
+            self.buffer.set_transfer_remaining(0);
+        } else if host_bytes == 0 || direction != host_direction || bytes > host_bytes {
+            // Cases 2, 3, 7, 8, 10 & 13
+            warn!("Phase error. Host intends {:?} {} bytes, device intends {:?} {} bytes",
+                host_direction, host_bytes, direction, bytes);
+            self.buffer.set_transfer_remaining(0);
+            self.command_status_wrapper.status = CommandStatus::PhaseError;
+            self.buffer.set_done();
+            self.check_end_data_transfer()?;
+            Err(Error::PhaseError)?;
+        } else {
+            // Cases 5, 6, 11 & 12. If the device transfers less than the host expected the endpoint
+            // is stalled when the command completes
+            self.buffer.set_transfer_remaining(bytes.saturating_sub(transferred));
+        }
+
+        Ok(())
+    }
+
+    /// The CBW for the command being executed. `None` once the command set has reported a status
+    /// even if data is still being transferred
     pub fn get_current_command(&self) -> Option<&CommandBlockWrapper> {
         match self.state {
             State::SendingDataToHost |
-            State::ReceivingDataFromHost => Some(&self.command_block_wrapper),
+            State::ReceivingDataFromHost if !self.buffer.is_done() => Some(&self.command_block_wrapper),
             _ => None,
         }
     }
@@ -303,22 +548,12 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     pub fn transfer_state(&self) -> TransferState {
-        trace_bot_buffer!("BUFFER> i: {}, di: {}", self.buffer_i, self.data_i);
-        match self.state {
-            State::ReceivingDataFromHost => TransferState::ReceivingDataFromHost { 
-                bytes_available: self.buffer_i - self.data_i,
-                full: self.buffer_i == self.buffer.len(),
-                done: self.command_status_wrapper.data_residue == 0,
-            },
-            State::SendingDataToHost => TransferState::SendingDataToHost { 
-                bytes_remaining: self.buffer_i - self.data_i,
-                empty: self.buffer_i == 0,
-            },
-            _ => TransferState::NotTransferring {
-                bytes_remaining: self.buffer_i - self.data_i,
-                empty: self.buffer_i == 0,
-            },
-        }
+        let direction = match self.state {
+            State::ReceivingDataFromHost => Some(Direction::HostToDevice),
+            State::SendingDataToHost => Some(Direction::DeviceToHost),
+            _ => None,
+        };
+        self.buffer.transfer_state(direction)
     }
 
     /// Gets a mutable slice of the buffer of the specified length
@@ -327,23 +562,12 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     /// Advances the buffer pointer so don't call it unless you actually
     /// need to put data in the buffer.
     pub fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
-        if len > self.buffer.len() {
-            panic!("BulkOnlyTransport::take_buffer_space called with len > buffer.len() ({} > {}) which can never be successful",
-                len, self.buffer.len());
-        } 
-
-        if len <= self.buffer.len() - self.buffer_i {
-            trace_bot_buffer!("BUFFER> successfully allocated {} bytes", len);
-            let s = self.buffer_i;
-            let e = s + len;
-
-            self.buffer_i += len;
+        self.buffer.take_buffer_space(len)
+    }
 
-            Ok(&mut self.buffer[s..e])
-        } else {
-            trace_bot_buffer!("BUFFER> insufficient space to allocate {} bytes", len);
-            Err(WouldBlock)?
-        }
+    /// The same as `take_buffer_space` but doesn't advance the buffer pointer
+    pub fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.buffer.peek_buffer_space(len)
     }
 
     /// Returns a slice containing data from the buffer if there is `len` bytes available
@@ -351,133 +575,85 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     /// `take_available` modifies behaviour to return whatever is available, even if that is [u8; 0]
     /// returns WouldBlock if there isn't enough data available
     pub fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
-        if len > self.buffer.len() {
-            panic!("BulkOnlyTransport::take_buffered_data called with len > buffer.len() ({} > {}) which can never be successful",
-                len, self.buffer.len());
-        }
-
-        let available = self.buffer_i - self.data_i;
-        if !take_available && len > available {
-            trace_bot_buffer!("BUFFER> contains insufficient data for take; requested: {}, available: {}", len, self.buffer_i - self.data_i);
-            Err(WouldBlock)?
-        }
-
-        let s = self.data_i;
-        let len = len.min(available);
-        let e = s + len;
-
-        self.data_i += len;
-        if self.data_i == self.buffer_i {
-            self.data_i = 0;
-            self.buffer_i = 0;
-        }
-        trace_bot_buffer!("BUFFER> took {}, available after: {}", len, self.buffer_i - self.data_i);
+        self.buffer.take_buffered_data(len, take_available)
+    }
 
-        Ok(&self.buffer[s..e])
+    /// The same as `take_buffered_data` but leaves the data in the buffer
+    pub fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.buffer.peek_buffered_data(len, take_available)
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        let packet_size = self.max_packet_size() as usize;
-        let residue = self.command_status_wrapper.data_residue as usize;
-
-        let bytes = if self.data_i < self.buffer_i && residue > 0 {
-            let start = self.data_i;
-
-            // TODO: Is this the right place to enforce data_residue?
-            //       It's certainly a USB feature not a SCSI/etc feature...
-            let len = (self.buffer_i - self.data_i)
-                .min(residue)
-                .min(packet_size);
-
-            let end = start + len;
-
-            let bytes = self.inner.write_packet(&self.buffer[start..end])?;
-
-            self.last_packet_full = bytes == packet_size;
-            self.data_i += bytes;
+        let remaining = self.buffer.transfer_remaining();
+        let bytes = self.buffer.flush(&mut self.inner)?;
 
-            let residue = residue - bytes;
-            self.command_status_wrapper.data_residue = residue as u32;
-
-            // If we've sent all the bytes, reset the buffer indexes to 0 to free
-            // up the whole buffer for more data
-            if self.data_i == self.buffer_i || residue == 0{
-                self.data_i = 0;
-                self.buffer_i = 0;
-            }
-
-            bytes
-        } else {
-            0
-        };
+        // The CSW itself doesn't count towards the data residue
+        if self.state == State::SendingDataToHost {
+            self.command_status_wrapper.data_residue -= bytes as u32;
+        }
 
-        trace_bot_bytes!("BYTES> Sent {} bytes. Data residue {} -> {}. Buff bytes: {}", 
+        trace_bot_bytes!("BYTES> Sent {} bytes. Remaining {} -> {}. Data residue: {}. Buff bytes: {}", 
             bytes, 
-            residue, 
+            remaining, 
+            self.buffer.transfer_remaining(),
             self.command_status_wrapper.data_residue,
-            self.buffer_i - self.data_i,
+            self.buffer.bytes_buffered(),
         );
 
         Ok(())
     }
 
-    fn send_zlp(&mut self) -> Result<(), Error> {
-        match self.inner.write_packet(&[]) {
-            Ok(_) => trace_bot_zlp!("ZLP> sent"),
-            Err(e) => {
-                trace_bot_zlp!("ZLP> sending failed: {:?}", e);
-                Err(e)?
-            },
-        }        
-
-        self.change_state(State::NeedToSendStatus);
-
-        Ok(())
-    }
-
     fn pack_csw(&mut self) {
-        self.command_status_wrapper.pack(&mut self.buffer[..CommandStatusWrapper::BYTES]).unwrap();
-        self.buffer_i = CommandStatusWrapper::BYTES;
-        self.data_i = 0;
-        self.command_status_wrapper.data_residue = self.buffer_i as u32;
+        self.buffer.clear();
+        let buf = self.buffer.take_buffer_space(CommandStatusWrapper::BYTES).unwrap();
+        self.command_status_wrapper.pack(buf).unwrap();
+        self.buffer.set_transfer_remaining(CommandStatusWrapper::BYTES as u32);
         trace_bot_headers!("HEADER> CommandStatusWrapper buffered to send: {:X?}", self.command_status_wrapper);
     }
 
     fn end_data_transfer(&mut self) -> Result<(), Error> {
+        let residue = self.command_status_wrapper.data_residue;
 
-        // We only send a zero length packet if the last write was a full packet AND we are sending
-        // less total bytes than the command header asked for
-        let needs_zlp = self.last_packet_full && 
-                        self.state == State::SendingDataToHost &&
-                        self.command_status_wrapper.data_residue > 0;
-
-        // Get the csw ready to send
+        // Get the csw ready to send, this discards anything left in the buffer
         self.pack_csw();
 
-        // send_zlp or flush are called here because we may not get an interrupt in a timley manner
-        // if we don't send immediately and
-        if needs_zlp {            
-            trace_bot_zlp!("ZLP> required");
-            self.change_state(State::NeedZlp);
-            self.send_zlp()?;
-        } else {
-            trace_bot_zlp!("ZLP> not required");
-            self.change_state(State::NeedToSendStatus);
-            self.flush()?;
+        // If the host expected more data than was transferred the endpoint has to be stalled
+        // to end the transfer. Section 6.7 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+        // If it's the in endpoint, the CSW waits until the host clears the halt
+        if residue > 0 {
+            trace_bot_zlp!("ZLP> Data residue: {}, stalling instead of sending a ZLP", residue);
+            match self.command_block_wrapper.direction {
+                Direction::DeviceToHost => self.stall_write_ep(),
+                Direction::HostToDevice => self.stall_read_ep(),
+            }
         }
 
-        Ok(())
+        self.change_state(State::NeedToSendStatus);
+        self.send_status_now()
+    }
+
+    /// Tries to send the CSW straight away because we may not get an interrupt in a timely manner
+    /// otherwise. If the endpoint is busy or halted it will be sent on the next call to `write` instead
+    fn send_status_now(&mut self) -> Result<(), Error> {
+        if self.state != State::NeedToSendStatus || self.write_ep_halted {
+            return Ok(());
+        }
+
+        match self.need_to_send_status() {
+            Err(Error::UsbError(WouldBlock)) => Ok(()),
+            r => r,
+        }
     }
 
     pub fn send_command_ok(&mut self) -> Result<(), Error> {
         self.command_status_wrapper.status = CommandStatus::CommandOk;
-        self.data_done = true;
+        self.buffer.set_done();
         self.check_end_data_transfer()
     }
 
     pub fn send_command_error(&mut self) -> Result<(), Error> {
         self.command_status_wrapper.status = CommandStatus::CommandError;
-        self.data_done = true;
+        self.buffer.set_done();
         self.check_end_data_transfer()
     }
 
@@ -489,23 +665,23 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     fn check_end_data_transfer(&mut self) -> Result<(), Error> {
+        // Nothing can end until the command has finished and given us a status
+        if !self.buffer.is_done() {
+            return Ok(());
+        }
+
         match self.state {
             State::ReceivingDataFromHost => {
-                // Check if we've read everything we were expecting
-                if self.command_status_wrapper.data_residue == 0 &&
-                    // AND it's been handled
-                    self.data_i == self.buffer_i
-                {
-                    trace_bot_states!("STATE> Data residue = 0 and buffer empty, all data received");
-                    self.end_data_transfer()?;
-                }
+                // Anything the host is still sending is no longer needed
+                trace_bot_states!("STATE> Command finished, remaining to receive: {}", self.buffer.transfer_remaining());
+                self.end_data_transfer()?;
             },
             State::SendingDataToHost => {
-                if self.command_status_wrapper.data_residue == 0 {
-                    trace_bot_states!("STATE> Data residue = 0, all data sent");
+                if self.buffer.transfer_remaining() == 0 {
+                    trace_bot_states!("STATE> All data sent");
                     self.end_data_transfer()?;
-                } else if self.data_done && self.data_i == self.buffer_i {
-                    trace_bot_states!("STATE> Data residue > 0, early termination");
+                } else if self.buffer.is_empty() {
+                    trace_bot_states!("STATE> Buffer empty, early termination");
                     self.end_data_transfer()?;
                 }
             }
@@ -515,28 +691,19 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     fn receiving_data_from_host(&mut self) -> Result<(), Error> {
-        if self.command_status_wrapper.data_residue > 0 &&
-            self.buffer.len() - self.buffer_i >= self.max_packet_usize() 
-        {
-            let bytes = self.inner.read_packet(&mut self.buffer[self.buffer_i..])?;
-            self.buffer_i += bytes;
-
-            let bytes = bytes as u32;
-            let residue = self.command_status_wrapper.data_residue;
-            if self.command_status_wrapper.data_residue >= bytes {
-                self.command_status_wrapper.data_residue -= bytes;
-            } else {
-                warn!("Read more bytes that CBW offered");
-                self.command_status_wrapper.data_residue = 0;
-            }
-            
-            trace_bot_bytes!("BYTES> Read {} bytes. Data residue {} -> {}. Buff bytes: {}", 
+        let remaining = self.buffer.transfer_remaining();
+        let bytes = self.buffer.receive(&mut self.inner)? as u32;
+
+        if bytes > 0 {
+            self.command_status_wrapper.data_residue = self.command_status_wrapper.data_residue.saturating_sub(bytes);
+
+            trace_bot_bytes!("BYTES> Read {} bytes. Remaining {} -> {}. Data residue: {}. Buff bytes: {}", 
                 bytes, 
-                residue, 
+                remaining, 
+                self.buffer.transfer_remaining(),
                 self.command_status_wrapper.data_residue,
-                self.buffer_i - self.data_i,
+                self.buffer.bytes_buffered(),
             );
-        
         }
 
         self.check_end_data_transfer()?;
@@ -547,8 +714,8 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     fn need_to_send_status(&mut self) -> Result<(), Error> {
         self.flush()?;
 
-        // Check if we've sent what we were asked to
-        if self.command_status_wrapper.data_residue == 0 {
+        // Check if we've sent the whole CSW
+        if self.buffer.transfer_remaining() == 0 {
             self.change_state(State::WaitingForCommand);
         }
 
@@ -556,17 +723,87 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 }
 
-impl<B: UsbBus> UsbClass<B> for BulkOnlyTransport<'_, B> {
+impl<B: UsbBus, const BUFFER_BYTES: usize> Transport<B> for BulkOnlyTransport<'_, B, BUFFER_BYTES> {
+    const BUFFER_BYTES: usize = BUFFER_BYTES;
+
+    fn interface_subclass(&self) -> InterfaceSubclass {
+        self.inner.subclass()
+    }
+
+    fn read(&mut self) -> Result<(), Error> {
+        self.read()
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        self.write()
+    }
+
+    fn get_current_command(&self) -> Option<CommandBlock<'_>> {
+        self.get_current_command().map(|cbw| CommandBlock {
+            lun: cbw.lun,
+            // Lenient CBW validation doesn't check the length
+            bytes: &cbw.data[..(cbw.data_length as usize).min(cbw.data.len())],
+        })
+    }
+
+    fn set_device_intent(&mut self, direction: Direction, bytes: u32) -> Result<(), Error> {
+        self.set_device_intent(direction, bytes)
+    }
+
+    fn transfer_state(&self) -> TransferState {
+        self.transfer_state()
+    }
+
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.take_buffer_space(len)
+    }
+
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.peek_buffer_space(len)
+    }
+
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.take_buffered_data(len, take_available)
+    }
+
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.peek_buffered_data(len, take_available)
+    }
+
+    fn send_command_ok(&mut self) -> Result<(), Error> {
+        self.send_command_ok()
+    }
+
+    fn send_command_error(&mut self, _sense_data: &[u8]) -> Result<(), Error> {
+        // The CSW only has room for the status, the host has to issue a request sense for more
+        self.send_command_error()
+    }
+
+    fn reset_pending(&self) -> bool {
+        self.reset_pending()
+    }
+
+    fn finish_reset(&mut self, ok: bool) {
+        self.finish_reset(ok)
+    }
+
+    fn take_alt_setting_changed(&mut self) -> bool {
+        // There's only alternate setting 0
+        false
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> UsbClass<B> for BulkOnlyTransport<'_, B, BUFFER_BYTES> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
         self.inner.get_configuration_descriptors(writer)
     }
 
     fn reset(&mut self) { 
         trace_usb_control!("USB_CONTROL> reset");
-        self.buffer_i = 0;
-        self.data_i = 0;
-        self.data_done = false;
-        self.change_state(State::WaitingForCommand);
+        self.bulk_only_reset();
+        // A USB reset clears any halts along with everything else
+        self.read_ep_halted = false;
+        self.write_ep_halted = false;
         self.inner.reset()
     }
 
@@ -590,18 +827,6 @@ impl<B: UsbBus> UsbClass<B> for BulkOnlyTransport<'_, B> {
                     Ok(1)
                 })),
 
-            // Bulk only mass storage reset
-            Request { request_type: RequestType::Class, request: REQ_BULK_ONLY_RESET, .. } => {
-                // There's some more functionality around this request to allow the reset to take
-                // more time - NAK the status until the reset is done.
-                // This isn't implemented.
-                // See Section 3.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
-                self.reset();
-                Some(xfer.accept(|_| {
-                    trace_usb_control!("USB_CONTROL> Bulk only mass storage reset");
-                    Ok(0)
-                }))
-            },
             _ => {
                 self.inner.control_in(xfer);
                 None
@@ -614,7 +839,7 @@ impl<B: UsbBus> UsbClass<B> for BulkOnlyTransport<'_, B> {
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
-        self.inner.control_out(xfer)
+        self.control_out(xfer)
     }
 
     fn poll(&mut self) { 
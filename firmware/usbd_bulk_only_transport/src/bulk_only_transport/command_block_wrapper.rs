@@ -68,7 +68,27 @@ impl Default for CommandBlockWrapper {
 }
 
 impl CommandBlockWrapper {
-    fn check_signature(buf: &[u8]) -> bool {
+    /// Checks the CBW is meaningful - the LUN is supported and the command block length is
+    /// between 1 and 16. Reserved bits in the flags are checked when unpacking the direction
+    /// Section 6.2.2 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+    pub fn is_meaningful(&self, max_lun: u8) -> bool {
+        self.lun <= max_lun &&
+        self.data_length >= 1 &&
+        self.data_length <= 16
+    }
+
+    /// Zeros the reserved bits in a packed CBW so it can be unpacked even if the host
+    /// has put something in them
+    pub fn clear_reserved_bits(buf: &mut [u8]) {
+        // Direction is the top bit of the flags
+        buf[12] &= 0x80;
+        // LUN is 4 bits
+        buf[13] &= 0x0F;
+        // Command block length is 5 bits
+        buf[14] &= 0x1F;
+    }
+
+    pub(crate) fn check_signature(buf: &[u8]) -> bool {
         buf.len() >= 4 &&
         buf[0] == SIGNATURE_0 &&
         buf[1] == SIGNATURE_1 &&
@@ -141,4 +161,32 @@ fn test_truncate_to_signature() {
     for i in 0..LEN {
         assert_eq!(buffer[i], old[i]);
     }
+}
+
+#[test]
+fn test_meaningful() {
+    use packing::PackedSize;
+
+    let mut buffer = [0; CommandBlockWrapper::BYTES];
+    let cbw = CommandBlockWrapper {
+        lun: 1,
+        data_length: 10,
+        ..Default::default()
+    };
+    cbw.pack(&mut buffer).unwrap();
+    assert!(cbw.is_meaningful(1));
+    assert!(!cbw.is_meaningful(0));
+
+    // Reserved bits in the flags mean it can't be unpacked until they're cleared
+    buffer[12] |= 0x01;
+    buffer[14] |= 0x20;
+    assert!(CommandBlockWrapper::unpack(&buffer).is_err());
+    CommandBlockWrapper::clear_reserved_bits(&mut buffer);
+    assert_eq!(CommandBlockWrapper::unpack(&buffer).unwrap(), cbw);
+
+    let cbw = CommandBlockWrapper {
+        data_length: 17,
+        ..Default::default()
+    };
+    assert!(!cbw.is_meaningful(0));
 }
\ No newline at end of file
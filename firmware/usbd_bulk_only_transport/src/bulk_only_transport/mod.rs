@@ -1,6 +1,3 @@
-mod direction;
-pub use direction::*;
-
 mod command_status;
 pub use command_status::*;
 
@@ -11,8 +8,11 @@ mod command_status_wrapper;
 pub use command_status_wrapper::*;
 
 mod bulk_only_transport;
-pub use bulk_only_transport::{
-    BulkOnlyTransport,
+pub use bulk_only_transport::BulkOnlyTransport;
+
+pub use usbd_mass_storage::{
+    DEFAULT_BUFFER_BYTES,
+    Direction,
     TransferState,
-    Error,
+    TransportError as Error,
 };
\ No newline at end of file
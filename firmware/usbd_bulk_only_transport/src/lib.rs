@@ -4,7 +4,9 @@ mod bulk_only_transport;
 
 pub use bulk_only_transport::{
     BulkOnlyTransport,
+    DEFAULT_BUFFER_BYTES,
     CommandBlockWrapper,
+    Direction,
     TransferState,
     Error,
 };
@@ -0,0 +1,632 @@
+use usb_device::class_prelude::*;
+use usb_device::{
+    Result as UsbResult,
+    control::{
+        RequestType,
+        Recipient,
+        Request,
+    },
+};
+pub use UsbError::WouldBlock;
+
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use usbd_mass_storage::{
+    MscClass,
+    InterfaceSubclass,
+    InterfaceProtocol,
+    Transport,
+    TransportError as Error,
+    CommandBlock,
+    Direction,
+    TransferState,
+    TransferBuffer,
+    DEFAULT_BUFFER_BYTES,
+};
+use crate::logging::*;
+use crate::interrupt_data_block::{
+    InterruptDataBlock,
+    CommandStatus,
+};
+
+/// Accept device specific command. Carries the command block in the data stage
+const REQ_ADSC: u8 = 0x00;
+
+/// A command block reset is an ADSC starting with these bytes followed by 0xFF padding
+const COMMAND_BLOCK_RESET: [u8; 2] = [0x1D, 0x04];
+
+/// Longest command block accepted. UFI command blocks are always 12 bytes
+const MAX_COMMAND_BLOCK_BYTES: usize = 16;
+
+/// How often the host polls the interrupt endpoint for command completion
+const INTERRUPT_INTERVAL_MS: u8 = 1;
+
+/// Where the additional sense code is in fixed format sense data. The qualifier follows it
+const FIXED_SENSE_ASC_INDEX: usize = 12;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum State {
+    /// Waiting for an ADSC request to arrive on the control endpoint. Moves to
+    /// ExecutingCommand
+    WaitingForCommand,
+    /// A command block has been received but the command set hasn't said what it
+    /// wants to transfer yet. Moves to SendingDataToHost or ReceivingDataFromHost
+    /// depending on the device intent or NeedToSendStatus if there's no data
+    ExecutingCommand,
+    /// Command initiated a transfer to the host (IN in USB parlance). Sends the
+    /// number of bytes the command set intends to unless instructed to terminate early.
+    /// Moves to NeedToSendStatus
+    SendingDataToHost,
+    /// Command initiated a transfer from the host (OUT in USB parlance). Reads the
+    /// number of bytes the command set intends to. Moves to NeedToSendStatus
+    ReceivingDataFromHost,
+    /// Data transfer has finished. Sends the interrupt data block on the interrupt
+    /// endpoint. Moves to WaitingForCommand
+    NeedToSendStatus,
+    /// A command block reset was accepted but the command set hasn't finished resetting yet.
+    /// The bulk endpoints aren't read or written and ADSCs are rejected until `finish_reset`
+    /// is called. Moves to WaitingForCommand
+    ResetPending,
+}
+
+/// # USB Control/Bulk/Interrupt transport protocol
+///
+/// So far only tested with the UFI command set - see [Scsi](struct.Scsi.html)
+///
+/// [USB CBI Spec](https://www.usb.org/document-library/mass-storage-controlbulkinterrupt-cbi-specification-11)
+///
+/// ## Functionality overview
+/// 1. Accepting command blocks sent with the accept device specific command (ADSC) class request.
+///    ADSCs that arrive while a command is executing are rejected
+/// 1. Initiating a data transfer with the length and direction from the command set (CBI has no
+///    equivalent of the BOT CBW so the host and device both work it out from the command block)
+/// 1. Sending USB packets to the underlaying driver when there is data in the buffer
+/// 1. Terminating the data transfer when enough data is processed or early termination is requested.
+///    If the command ends before all the data was transferred the bulk endpoint is stalled
+/// 1. Reporting command completion on the interrupt endpoint. The UFI command set reports the
+///    additional sense code and qualifier, everything else reports pass/fail
+/// 1. Command block reset (an ADSC containing `1D 04 FF FF ...`). The ADSC is ACKed straight away
+///    but the bulk endpoints are held until the command set has finished resetting - see
+///    `finish_reset`
+///
+/// CBI doesn't carry a LUN, the command set has to get it from the command block if it supports
+/// more than one. Only CBI with command completion interrupt is supported.
+///
+/// ## Buffer size
+/// `BUFFER_BYTES` sets the size of the buffer used for both directions of the data transfer. It must
+/// be a multiple of the max packet size.
+///
+pub struct CbiTransport<'a, B: UsbBus, const BUFFER_BYTES: usize = DEFAULT_BUFFER_BYTES> {
+    inner: MscClass<'a, B>,
+
+    /// Are we waiting, sending or receiving data
+    state: State,
+
+    /// The most recent command block. Not valid if in WaitingForCommand state
+    command_block: [u8; MAX_COMMAND_BLOCK_BYTES],
+
+    /// The number of valid bytes in command_block
+    command_block_bytes: usize,
+
+    /// Status of the current command, sent on the interrupt endpoint after the data
+    interrupt_data_block: InterruptDataBlock,
+
+    /// Data phase buffer
+    buffer: TransferBuffer<BUFFER_BYTES>,
+
+    /// Set when we stall the read (OUT) endpoint, cleared when the host clears the halt
+    read_ep_halted: bool,
+
+    /// Set when we stall the write (IN) endpoint, cleared when the host clears the halt
+    write_ep_halted: bool,
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> CbiTransport<'_, B, BUFFER_BYTES> {
+    pub const BUFFER_BYTES: usize = BUFFER_BYTES;
+
+    /// Creates a new CbiTransport
+    ///
+    /// `subclass` is normally `InterfaceSubclass::Ufi`. Panics if `BUFFER_BYTES` isn't a non-zero
+    /// multiple of `max_packet_size`
+    pub fn new(
+        alloc: &UsbBusAllocator<B>,
+        max_packet_size: u16,
+        subclass: InterfaceSubclass,
+    ) -> CbiTransport<'_, B, BUFFER_BYTES> {
+        // Packets are only read when there's space for a full one so anything left over at the
+        // end of the buffer would never be filled
+        assert!(BUFFER_BYTES > 0 && BUFFER_BYTES.is_multiple_of(max_packet_size as usize));
+        CbiTransport {
+            inner: MscClass::with_interrupt_ep(
+                alloc,
+                max_packet_size,
+                subclass,
+                InterfaceProtocol::CbiWithCCInterrupt,
+                InterruptDataBlock::BYTES as u16,
+                INTERRUPT_INTERVAL_MS,
+            ),
+            state: State::WaitingForCommand,
+            command_block: [0; MAX_COMMAND_BLOCK_BYTES],
+            command_block_bytes: 0,
+            interrupt_data_block: Default::default(),
+            buffer: TransferBuffer::new(),
+            read_ep_halted: false,
+            write_ep_halted: false,
+        }
+    }
+
+    /// Is a command block reset waiting for `finish_reset`
+    pub fn reset_pending(&self) -> bool {
+        self.state == State::ResetPending
+    }
+
+    /// Ends a command block reset once the command set has reset everything it needs to
+    ///
+    /// The reset ADSC is ACKed as soon as it arrives because usb-device doesn't let a class hold the
+    /// status stage open. Only the bulk side is held: until this is called the bulk endpoints
+    /// aren't used and other ADSCs are rejected.
+    /// If `ok` is false both bulk endpoints are stalled so the host notices and recovers
+    pub fn finish_reset(&mut self, ok: bool) {
+        if self.state != State::ResetPending {
+            return;
+        }
+        if !ok {
+            self.stall_read_ep();
+            self.stall_write_ep();
+        }
+        self.change_state(State::WaitingForCommand);
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        let handled_res = match req {
+            // Clear feature endpoint halt for one of our endpoints. This is normally handled by UsbDevice
+            // but we need to track the halt state so we don't try and use a halted endpoint
+            Request {
+                request_type: RequestType::Standard,
+                recipient: Recipient::Endpoint,
+                request: Request::CLEAR_FEATURE,
+                value: Request::FEATURE_ENDPOINT_HALT,
+                index,
+                ..
+            } if self.inner.is_read_ep(index) || self.inner.is_write_ep(index) => {
+                self.clear_halt(index);
+                Some(xfer.accept())
+            },
+
+            // Accept device specific command
+            Request { request_type: RequestType::Class, recipient: Recipient::Interface, request: REQ_ADSC, index, .. }
+                if self.inner.correct_interface_number(index) =>
+            {
+                let data = xfer.data();
+
+                if is_command_block_reset(data) {
+                    trace_usb_control!("USB_CONTROL> Command block reset");
+                    self.command_block_reset();
+                    self.change_state(State::ResetPending);
+                    Some(xfer.accept())
+                } else if self.state == State::ResetPending {
+                    warn!("ADSC rejected, command block reset hasn't finished");
+                    Some(xfer.reject())
+                } else if self.state != State::WaitingForCommand {
+                    warn!("ADSC rejected, still busy with the previous command: {:?}", self.state);
+                    Some(xfer.reject())
+                } else if data.is_empty() || data.len() > MAX_COMMAND_BLOCK_BYTES {
+                    warn!("ADSC rejected, invalid command block length: {}", data.len());
+                    Some(xfer.reject())
+                } else {
+                    self.command_block[..data.len()].copy_from_slice(data);
+                    self.command_block_bytes = data.len();
+                    self.transition_to_command();
+                    Some(xfer.accept())
+                }
+            },
+
+            _ => {
+                self.inner.control_out(xfer);
+                None
+            },
+        };
+
+        if let Some(Err(e)) = handled_res {
+            error!("Error from ControlOut.accept: {:?}", e);
+        }
+    }
+
+    pub fn read(&mut self) -> Result<(), Error> {
+        if self.read_ep_halted {
+            return Ok(());
+        }
+
+        match self.state {
+            State::ReceivingDataFromHost => self.receiving_data_from_host(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self) -> Result<(), Error> {
+        match self.state {
+            State::SendingDataToHost if !self.write_ep_halted => self.sending_data_to_host(),
+            State::NeedToSendStatus => self.need_to_send_status(),
+            _ => Ok(()),
+        }
+    }
+
+    fn stall_read_ep(&mut self) {
+        trace_cbi_states!("STATE> Stalling bulk out");
+        self.inner.stall_read_ep();
+        self.read_ep_halted = true;
+    }
+
+    fn stall_write_ep(&mut self) {
+        trace_cbi_states!("STATE> Stalling bulk in");
+        self.inner.stall_write_ep();
+        self.write_ep_halted = true;
+    }
+
+    /// Command block reset. Abandons the current command and gets ready for the next ADSC.
+    /// Halted endpoints stay halted until the host clears them
+    fn command_block_reset(&mut self) {
+        self.buffer.reset();
+        self.change_state(State::WaitingForCommand);
+    }
+
+    fn clear_halt(&mut self, index: u16) {
+        if self.inner.is_read_ep(index) {
+            trace_usb_control!("USB_CONTROL> Clear feature endpoint halt, bulk out");
+            self.inner.unstall_read_ep();
+            self.read_ep_halted = false;
+        } else if self.inner.is_write_ep(index) {
+            trace_usb_control!("USB_CONTROL> Clear feature endpoint halt, bulk in");
+            self.inner.unstall_write_ep();
+            self.write_ep_halted = false;
+        }
+    }
+
+    fn change_state(&mut self, new_state: State) {
+        trace_cbi_states!("STATE> {:?} -> {:?}",
+            self.state,
+            new_state,
+        );
+        self.state = new_state;
+    }
+
+    fn transition_to_command(&mut self) {
+        trace_cbi_commands!("COMMAND> Command block: {:X?}", &self.command_block[..self.command_block_bytes]);
+        // Nothing to transfer until the command set says otherwise
+        self.buffer.reset();
+
+        self.interrupt_data_block = Default::default();
+
+        self.change_state(State::ExecutingCommand);
+    }
+
+    /// Tells the transport what the command set intends to do in the data phase of the current command
+    ///
+    /// `bytes` is how much data the command will transfer in `direction`. 0 means no data will be
+    /// transferred, in which case `direction` is ignored. Must be called once per command before any
+    /// data is transferred. If it isn't called the command has no data phase.
+    pub fn set_device_intent(&mut self, direction: Direction, bytes: u32) -> Result<(), Error> {
+        if self.state != State::ExecutingCommand {
+            return Ok(());
+        }
+
+        trace_cbi_states!("STATE> Device intends {:?} {} bytes", direction, bytes);
+
+        self.buffer.set_transfer_remaining(bytes);
+
+        if bytes > 0 {
+            match direction {
+                Direction::HostToDevice => self.change_state(State::ReceivingDataFromHost),
+                Direction::DeviceToHost => self.change_state(State::SendingDataToHost),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The command block for the command being executed. `None` once the command set has reported
+    /// a status even if data is still being transferred
+    pub fn get_current_command(&self) -> Option<&[u8]> {
+        match self.state {
+            State::ExecutingCommand |
+            State::SendingDataToHost |
+            State::ReceivingDataFromHost if !self.buffer.is_done() => Some(&self.command_block[..self.command_block_bytes]),
+            _ => None,
+        }
+    }
+
+    pub fn transfer_state(&self) -> TransferState {
+        let direction = match self.state {
+            State::ReceivingDataFromHost => Some(Direction::HostToDevice),
+            State::SendingDataToHost => Some(Direction::DeviceToHost),
+            _ => None,
+        };
+        self.buffer.transfer_state(direction)
+    }
+
+    /// Gets a mutable slice of the buffer of the specified length
+    /// panics if len requested is > the max size of the buffer
+    /// returns WouldBlock if there isn't currently space in the buffer
+    /// Advances the buffer pointer so don't call it unless you actually
+    /// need to put data in the buffer.
+    pub fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.buffer.take_buffer_space(len)
+    }
+
+    /// The same as `take_buffer_space` but doesn't advance the buffer pointer
+    pub fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.buffer.peek_buffer_space(len)
+    }
+
+    /// Returns a slice containing data from the buffer if there is `len` bytes available
+    /// panics if len requested is > the max size of the buffer
+    /// `take_available` modifies behaviour to return whatever is available, even if that is [u8; 0]
+    /// returns WouldBlock if there isn't enough data available
+    pub fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.buffer.take_buffered_data(len, take_available)
+    }
+
+    /// The same as `take_buffered_data` but leaves the data in the buffer
+    pub fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.buffer.peek_buffered_data(len, take_available)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let remaining = self.buffer.transfer_remaining();
+        let bytes = self.buffer.flush(&mut self.inner)?;
+
+        trace_cbi_bytes!("BYTES> Sent {} bytes. Remaining {} -> {}. Buff bytes: {}",
+            bytes,
+            remaining,
+            self.buffer.transfer_remaining(),
+            self.buffer.bytes_buffered(),
+        );
+
+        Ok(())
+    }
+
+    fn end_data_transfer(&mut self) -> Result<(), Error> {
+        // The host works out how much data to expect from the command block. If we stopped short the
+        // endpoint has to be stalled so it doesn't wait for the rest
+        if self.buffer.transfer_remaining() > 0 {
+            trace_cbi_states!("STATE> Transfer ended with {} bytes remaining", self.buffer.transfer_remaining());
+            match self.state {
+                State::SendingDataToHost => self.stall_write_ep(),
+                State::ReceivingDataFromHost => self.stall_read_ep(),
+                _ => {},
+            }
+        }
+
+        // Anything left in the buffer is no longer needed
+        self.buffer.end();
+
+        self.change_state(State::NeedToSendStatus);
+        self.send_status_now()
+    }
+
+    /// Tries to send the status straight away because we may not get an interrupt in a timely manner
+    /// otherwise. If the endpoint is busy it will be sent on the next call to `write` instead
+    fn send_status_now(&mut self) -> Result<(), Error> {
+        match self.need_to_send_status() {
+            Err(Error::UsbError(WouldBlock)) => Ok(()),
+            r => r,
+        }
+    }
+
+    pub fn send_command_ok(&mut self) -> Result<(), Error> {
+        self.interrupt_data_block = if self.inner.subclass() == InterfaceSubclass::Ufi {
+            InterruptDataBlock::ufi(0, 0)
+        } else {
+            InterruptDataBlock::command_completion(CommandStatus::Pass)
+        };
+        self.buffer.set_done();
+        self.check_end_data_transfer()
+    }
+
+    /// Ends the current command with a failure. The additional sense code and qualifier from
+    /// `sense_data` (fixed format) are only sent to the host if the subclass is UFI
+    pub fn send_command_error(&mut self, sense_data: &[u8]) -> Result<(), Error> {
+        self.interrupt_data_block = if self.inner.subclass() == InterfaceSubclass::Ufi {
+            let asc = sense_data.get(FIXED_SENSE_ASC_INDEX).copied().unwrap_or(0);
+            let ascq = sense_data.get(FIXED_SENSE_ASC_INDEX + 1).copied().unwrap_or(0);
+            InterruptDataBlock::ufi(asc, ascq)
+        } else {
+            InterruptDataBlock::command_completion(CommandStatus::Fail)
+        };
+        self.buffer.set_done();
+        self.check_end_data_transfer()
+    }
+
+    fn sending_data_to_host(&mut self) -> Result<(), Error> {
+        // Send as much data as possible from the current buffer
+        self.flush()?;
+
+        self.check_end_data_transfer()
+    }
+
+    fn check_end_data_transfer(&mut self) -> Result<(), Error> {
+        // Nothing can end until the command has finished and given us a status
+        if !self.buffer.is_done() {
+            return Ok(());
+        }
+
+        match self.state {
+            State::ExecutingCommand => {
+                trace_cbi_states!("STATE> Command finished without data");
+                self.end_data_transfer()?;
+            },
+            State::ReceivingDataFromHost => {
+                // Anything the host is still sending is no longer needed
+                trace_cbi_states!("STATE> Command finished, remaining to receive: {}", self.buffer.transfer_remaining());
+                self.end_data_transfer()?;
+            },
+            State::SendingDataToHost => {
+                if self.buffer.transfer_remaining() == 0 {
+                    trace_cbi_states!("STATE> All data sent");
+                    self.end_data_transfer()?;
+                } else if self.buffer.is_empty() {
+                    trace_cbi_states!("STATE> Buffer empty, early termination");
+                    self.end_data_transfer()?;
+                }
+            }
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn receiving_data_from_host(&mut self) -> Result<(), Error> {
+        let remaining = self.buffer.transfer_remaining();
+        let bytes = self.buffer.receive(&mut self.inner)?;
+
+        if bytes > 0 {
+            trace_cbi_bytes!("BYTES> Read {} bytes. Remaining {} -> {}. Buff bytes: {}",
+                bytes,
+                remaining,
+                self.buffer.transfer_remaining(),
+                self.buffer.bytes_buffered(),
+            );
+        }
+
+        self.check_end_data_transfer()?;
+
+        Ok(())
+    }
+
+    fn need_to_send_status(&mut self) -> Result<(), Error> {
+        if self.state != State::NeedToSendStatus {
+            return Ok(());
+        }
+
+        let mut buf = [0; InterruptDataBlock::BYTES];
+        self.interrupt_data_block.pack(&mut buf)?;
+        self.inner.write_interrupt_packet(&buf)?;
+        trace_cbi_commands!("COMMAND> Interrupt data block sent: {:X?}", self.interrupt_data_block);
+
+        self.change_state(State::WaitingForCommand);
+
+        Ok(())
+    }
+}
+
+/// A command block reset is a SEND DIAGNOSTIC with the self test bit set followed by 0xFF instead
+/// of the usual reserved bytes so it can't be mistaken for a real command
+fn is_command_block_reset(data: &[u8]) -> bool {
+    data.len() > COMMAND_BLOCK_RESET.len() &&
+        data.starts_with(&COMMAND_BLOCK_RESET) &&
+        data[COMMAND_BLOCK_RESET.len()..].iter().all(|b| *b == 0xFF)
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> Transport<B> for CbiTransport<'_, B, BUFFER_BYTES> {
+    const BUFFER_BYTES: usize = BUFFER_BYTES;
+
+    fn interface_subclass(&self) -> InterfaceSubclass {
+        self.inner.subclass()
+    }
+
+    fn read(&mut self) -> Result<(), Error> {
+        self.read()
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        self.write()
+    }
+
+    fn get_current_command(&self) -> Option<CommandBlock<'_>> {
+        self.get_current_command().map(|bytes| CommandBlock {
+            lun: 0,
+            bytes,
+        })
+    }
+
+    fn set_device_intent(&mut self, direction: Direction, bytes: u32) -> Result<(), Error> {
+        self.set_device_intent(direction, bytes)
+    }
+
+    fn transfer_state(&self) -> TransferState {
+        self.transfer_state()
+    }
+
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.take_buffer_space(len)
+    }
+
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.peek_buffer_space(len)
+    }
+
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.take_buffered_data(len, take_available)
+    }
+
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.peek_buffered_data(len, take_available)
+    }
+
+    fn send_command_ok(&mut self) -> Result<(), Error> {
+        self.send_command_ok()
+    }
+
+    fn send_command_error(&mut self, sense_data: &[u8]) -> Result<(), Error> {
+        self.send_command_error(sense_data)
+    }
+
+    fn reset_pending(&self) -> bool {
+        self.reset_pending()
+    }
+
+    fn finish_reset(&mut self, ok: bool) {
+        self.finish_reset(ok)
+    }
+
+    fn take_alt_setting_changed(&mut self) -> bool {
+        // There's only alternate setting 0
+        false
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> UsbClass<B> for CbiTransport<'_, B, BUFFER_BYTES> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+        self.inner.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        trace_usb_control!("USB_CONTROL> reset");
+        self.command_block_reset();
+        // A USB reset clears any halts along with everything else
+        self.read_ep_halted = false;
+        self.write_ep_halted = false;
+        self.inner.reset()
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        // CBI doesn't have any class specific control in requests
+        self.inner.control_in(xfer)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        self.control_out(xfer)
+    }
+
+    fn poll(&mut self) {
+        panic!("CbiTransport::poll should never be called. Consumers (SCSI for example) should use CbiTransport::read and CbiTransport::write");
+    }
+}
+
+#[test]
+fn test_command_block_reset() {
+    let mut reset = [0xFF; 12];
+    reset[..2].copy_from_slice(&COMMAND_BLOCK_RESET);
+    assert!(is_command_block_reset(&reset));
+
+    // A real SEND DIAGNOSTIC with the self test bit set
+    let mut send_diagnostic = [0; 12];
+    send_diagnostic[..2].copy_from_slice(&COMMAND_BLOCK_RESET);
+    assert!(!is_command_block_reset(&send_diagnostic));
+
+    assert!(!is_command_block_reset(&COMMAND_BLOCK_RESET));
+}
\ No newline at end of file
@@ -0,0 +1,55 @@
+use packing::Packed;
+
+/// The status of a command reported in a command completion interrupt
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum CommandStatus {
+    /// Command completed successfully
+    Pass = 0x00,
+    /// Command failed
+    Fail = 0x01,
+    /// The host and device got out of step, the host will reset the device
+    PhaseError = 0x02,
+    /// Command failed and will keep failing until the host resolves the problem
+    PersistentFailure = 0x03,
+}
+
+/// Sent to the host on the interrupt endpoint when a command completes
+/// [USB CBI Spec](https://www.usb.org/document-library/mass-storage-controlbulkinterrupt-cbi-specification-11)
+///
+/// The UFI command set uses the same two bytes for the additional sense code and qualifier of the
+/// command instead. Both zero means the command passed
+/// [UFI Command Spec](https://www.usb.org/document-library/mass-storage-ufi-command-specification-10)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(little_endian, lsb0)]
+pub struct InterruptDataBlock {
+    /// bType. 0x00 for command completion. ASC for UFI
+    #[packed(start_bit=7, end_bit=0, start_byte=0, end_byte=0)]
+    pub interrupt_type: u8,
+    /// bValue. The command status in bits 0..1 for command completion. ASCQ for UFI
+    #[packed(start_bit=7, end_bit=0, start_byte=1, end_byte=1)]
+    pub value: u8,
+}
+
+impl InterruptDataBlock {
+    /// Command completion interrupt with `status`
+    pub fn command_completion(status: CommandStatus) -> Self {
+        Self {
+            interrupt_type: 0x00,
+            value: status.to_primitive(),
+        }
+    }
+
+    /// UFI command completion interrupt with the additional sense code and qualifier
+    pub fn ufi(asc: u8, ascq: u8) -> Self {
+        Self {
+            interrupt_type: asc,
+            value: ascq,
+        }
+    }
+}
+
+impl Default for InterruptDataBlock {
+    fn default() -> Self {
+        Self::command_completion(CommandStatus::Pass)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,34 @@
+#![no_std]
+
+mod interrupt_data_block;
+mod cbi_transport;
+
+pub use cbi_transport::CbiTransport;
+pub use interrupt_data_block::{
+    CommandStatus,
+    InterruptDataBlock,
+};
+
+mod logging {
+    pub use itm_logger::*;
+
+    #[cfg(feature = "trace-cbi-commands")]
+    pub use itm_logger::trace as trace_cbi_commands;
+    #[cfg(not(feature = "trace-cbi-commands"))]
+    pub use itm_logger::stub as trace_cbi_commands;
+
+    #[cfg(feature = "trace-cbi-states")]
+    pub use itm_logger::trace as trace_cbi_states;
+    #[cfg(not(feature = "trace-cbi-states"))]
+    pub use itm_logger::stub as trace_cbi_states;
+
+    #[cfg(feature = "trace-cbi-bytes")]
+    pub use itm_logger::trace as trace_cbi_bytes;
+    #[cfg(not(feature = "trace-cbi-bytes"))]
+    pub use itm_logger::stub as trace_cbi_bytes;
+
+    #[cfg(feature = "trace-usb-control")]
+    pub use itm_logger::trace as trace_usb_control;
+    #[cfg(not(feature = "trace-usb-control"))]
+    pub use itm_logger::stub as trace_usb_control;
+}
\ No newline at end of file
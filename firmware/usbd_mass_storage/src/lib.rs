@@ -7,9 +7,17 @@
 //! | CBW    | Command block wrapper. Header that contains information about the data that is expected to be sent/received next | Section 5.1 [USB Bulk Only Transport Spec][USBBot] |
 //! | CSW    | Command status wrapper. Status sent after data transfer to indicate success/failure and confirm length of data sent | Section 5.2 [USB Bulk Only Transport Spec][USBBot] |
 //! | Data Residue | Data residue (bytes) is the difference in the length requested in the CBW and the actual amount of data sent/received | Section 5.2 [USB Bulk Only Transport Spec][USBBot] |
+//! | CBI    | Control/Bulk/Interrupt transport. Command blocks are sent on the control endpoint and status on an interrupt endpoint | [USB CBI Spec][USBCbi] |
+//! | ADSC   | Accept device specific command. The class request CBI uses to send a command block | [USB CBI Spec][USBCbi] |
+//! | UFI    | USB floppy interface. A subset of the SCSI command set used with CBI | [USB UFI Command Spec][USBUfi] |
+//! | UAS    | USB attached SCSI. Transport with separate command, status and data pipes that lets the host queue several tagged commands | [USB UAS Spec][USBUas] |
+//! | IU     | Information unit. The command, status and task management messages UAS sends on the command and status pipes | [USB UAS Spec][USBUas] |
 //!
 //! [USB2Bus]: https://www.usb.org/document-library/usb-20-specification
 //! [USBBot]: https://www.usb.org/document-library/mass-storage-bulk-only-10
+//! [USBCbi]: https://www.usb.org/document-library/mass-storage-controlbulkinterrupt-cbi-specification-11
+//! [USBUfi]: https://www.usb.org/document-library/mass-storage-ufi-command-specification-10
+//! [USBUas]: https://www.usb.org/document-library/usb-attached-scsi-protocol-uasp-v10-and-adopters-agreement
 //!
 
 #![no_std]
@@ -17,11 +25,15 @@
 mod msc;
 mod interface_subclass;
 mod interface_protocol;
+mod transport;
+mod transfer_buffer;
 
 pub use usb_device::{Result, UsbError};
 pub use msc::*;
 pub use interface_subclass::*;
 pub use interface_protocol::*;
+pub use transport::*;
+pub use transfer_buffer::*;
 
 mod logging {
     pub use itm_logger::*;
@@ -30,4 +42,9 @@ mod logging {
     pub use itm_logger::trace as trace_usb_control;
     #[cfg(not(feature = "trace-usb-control"))]
     pub use itm_logger::stub as trace_usb_control;
+
+    #[cfg(feature = "trace-transfer-buffer")]
+    pub use itm_logger::trace as trace_transfer_buffer;
+    #[cfg(not(feature = "trace-transfer-buffer"))]
+    pub use itm_logger::stub as trace_transfer_buffer;
 }
@@ -22,6 +22,7 @@ pub struct MscClass<'a, B: UsbBus> {
     pub(crate) msc_if: InterfaceNumber,
     pub(crate) read_ep: EndpointOut<'a, B>,
     pub(crate) write_ep: EndpointIn<'a, B>,
+    pub(crate) interrupt_ep: Option<EndpointIn<'a, B>>,
     pub(crate) subclass: InterfaceSubclass,
     pub(crate) protocol: InterfaceProtocol,
 }
@@ -37,11 +38,29 @@ impl<B: UsbBus> MscClass<'_, B> {
             msc_if: alloc.interface(),
             write_ep: alloc.bulk(max_packet_size),
             read_ep: alloc.bulk(max_packet_size),
+            interrupt_ep: None,
             subclass,
             protocol,
         }
     }
 
+    /// Creates a new MscClass that also has an interrupt IN endpoint, as used by the CBI transport
+    /// to signal command completion
+    ///
+    /// `interval` is the polling interval for the interrupt endpoint in milliseconds
+    pub fn with_interrupt_ep(
+        alloc: &UsbBusAllocator<B>, 
+        max_packet_size: u16, 
+        subclass: InterfaceSubclass,
+        protocol: InterfaceProtocol,
+        interrupt_max_packet_size: u16,
+        interval: u8,
+    ) -> MscClass<'_, B> {
+        let mut msc = MscClass::new(alloc, max_packet_size, subclass, protocol);
+        msc.interrupt_ep = Some(alloc.interrupt(interrupt_max_packet_size, interval));
+        msc
+    }
+
     pub fn max_packet_size(&self) -> u16 {
         // The size is the same for both endpoints.
         self.read_ep.max_packet_size()
@@ -55,13 +74,79 @@ impl<B: UsbBus> MscClass<'_, B> {
         self.write_ep.write(buf)
     }
 
+    /// Writes to the interrupt endpoint. Returns `UsbError::InvalidEndpoint` if the class was created
+    /// without one
+    pub fn write_interrupt_packet(&mut self, buf: &[u8]) -> Result<usize> {
+        match &self.interrupt_ep {
+            Some(ep) => ep.write(buf),
+            None => Err(UsbError::InvalidEndpoint),
+        }
+    }
+
+    pub fn subclass(&self) -> InterfaceSubclass {
+        self.subclass
+    }
+
     pub fn correct_interface_number(&self, interface_number: u16) -> bool {
          interface_number == u8::from(self.msc_if) as u16
     }
+
+    /// Checks if `index` from an endpoint request refers to the read (OUT) endpoint
+    pub fn is_read_ep(&self, index: u16) -> bool {
+        index as u8 == u8::from(self.read_ep.address())
+    }
+
+    /// Checks if `index` from an endpoint request refers to the write (IN) endpoint
+    pub fn is_write_ep(&self, index: u16) -> bool {
+        index as u8 == u8::from(self.write_ep.address())
+    }
+
+    /// Stalls the read (OUT) endpoint. The host clears this with a clear feature endpoint halt request
+    pub fn stall_read_ep(&self) {
+        self.read_ep.stall()
+    }
+
+    /// Stalls the write (IN) endpoint. The host clears this with a clear feature endpoint halt request
+    pub fn stall_write_ep(&self) {
+        self.write_ep.stall()
+    }
+
+    pub fn unstall_read_ep(&self) {
+        self.read_ep.unstall()
+    }
+
+    pub fn unstall_write_ep(&self) {
+        self.write_ep.unstall()
+    }
+}
+
+impl<'a, B: UsbBus> MscClass<'a, B> {
+    pub fn interface_number(&self) -> InterfaceNumber {
+        self.msc_if
+    }
+
+    /// The bulk OUT endpoint. For transports that share it between alternate settings
+    pub fn read_ep(&self) -> &EndpointOut<'a, B> {
+        &self.read_ep
+    }
+
+    /// The bulk IN endpoint. For transports that share it between alternate settings
+    pub fn write_ep(&self) -> &EndpointIn<'a, B> {
+        &self.write_ep
+    }
 }
 
 impl<B: UsbBus> UsbClass<B> for MscClass<'_, B> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        // Only written if the device is built with `composite_with_iads`, for composite devices
+        writer.iad(
+            self.msc_if,
+            1,
+            USB_CLASS_MSC,
+            self.subclass.to_primitive(),
+            self.protocol.to_primitive(),
+        )?;
+
         writer.interface(
             self.msc_if,
             USB_CLASS_MSC,
@@ -72,6 +157,10 @@ impl<B: UsbBus> UsbClass<B> for MscClass<'_, B> {
         writer.endpoint(&self.read_ep)?;
         writer.endpoint(&self.write_ep)?;
 
+        if let Some(ep) = &self.interrupt_ep {
+            writer.endpoint(ep)?;
+        }
+
         Ok(())
     }
 
@@ -0,0 +1,268 @@
+//! Buffer for the data phase of a command, shared by all the transports
+use usb_device::class_prelude::*;
+
+use crate::{
+    MscClass,
+    Direction,
+    TransferState,
+    TransportError as Error,
+};
+use crate::logging::*;
+
+/// Holds data on its way between the command set and the bulk endpoints and keeps track of how much
+/// of the data phase is left
+///
+/// The command set fills it with `take_buffer_space` and the transport sends it with `flush`. In the
+/// other direction the transport fills it with `receive` and the command set empties it with
+/// `take_buffered_data`. The indexes go back to the start whenever the buffer empties.
+pub struct TransferBuffer<const BUFFER_BYTES: usize> {
+    /// The buffer, used for both reading and writing
+    buffer: [u8; BUFFER_BYTES],
+
+    /// The next free index in the buffer
+    buffer_i: usize,
+
+    /// The next data to send in the buffer
+    data_i: usize,
+
+    /// Bytes left to transfer in the current data phase
+    transfer_remaining: u32,
+
+    /// Indicates we are going to end the current data transaction after draining the current
+    /// buffer regardless of transfer_remaining
+    data_done: bool,
+}
+
+impl<const BUFFER_BYTES: usize> Default for TransferBuffer<BUFFER_BYTES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const BUFFER_BYTES: usize> TransferBuffer<BUFFER_BYTES> {
+    pub const fn new() -> Self {
+        TransferBuffer {
+            buffer: [0; BUFFER_BYTES],
+            buffer_i: 0,
+            data_i: 0,
+            transfer_remaining: 0,
+            data_done: false,
+        }
+    }
+
+    /// Throws away everything ready for the next command
+    pub fn reset(&mut self) {
+        self.start(0);
+    }
+
+    /// Throws away anything in the buffer and starts a data phase of `transfer_remaining` bytes
+    pub fn start(&mut self, transfer_remaining: u32) {
+        self.clear();
+        self.data_done = false;
+        self.transfer_remaining = transfer_remaining;
+    }
+
+    /// Throws away anything in the buffer and ends the data phase
+    pub fn end(&mut self) {
+        self.clear();
+        self.transfer_remaining = 0;
+    }
+
+    /// Throws away anything in the buffer
+    pub fn clear(&mut self) {
+        self.buffer_i = 0;
+        self.data_i = 0;
+    }
+
+    pub fn transfer_remaining(&self) -> u32 {
+        self.transfer_remaining
+    }
+
+    pub fn set_transfer_remaining(&mut self, transfer_remaining: u32) {
+        self.transfer_remaining = transfer_remaining;
+    }
+
+    /// The command set has reported a status so the data phase ends once the buffer drains
+    pub fn is_done(&self) -> bool {
+        self.data_done
+    }
+
+    pub fn set_done(&mut self) {
+        self.data_done = true;
+    }
+
+    /// Bytes in the buffer that haven't been sent or taken yet
+    pub fn bytes_buffered(&self) -> usize {
+        self.buffer_i - self.data_i
+    }
+
+    /// Everything in the buffer has been sent or taken
+    pub fn is_empty(&self) -> bool {
+        self.data_i == self.buffer_i
+    }
+
+    /// The data in the buffer, for transports that read their own headers through it
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[self.data_i..self.buffer_i]
+    }
+
+    /// Keeps the first `len` bytes of the data in the buffer
+    pub fn truncate(&mut self, len: usize) {
+        self.buffer_i = self.buffer_i.min(self.data_i + len);
+    }
+
+    /// The state of the data phase if it's going in `direction`. `None` if there isn't one
+    pub fn transfer_state(&self, direction: Option<Direction>) -> TransferState {
+        trace_transfer_buffer!("BUFFER> i: {}, di: {}", self.buffer_i, self.data_i);
+        match direction {
+            Some(Direction::HostToDevice) => TransferState::ReceivingDataFromHost {
+                bytes_available: self.bytes_buffered(),
+                full: self.buffer_i == self.buffer.len(),
+                done: self.transfer_remaining == 0,
+            },
+            Some(Direction::DeviceToHost) => TransferState::SendingDataToHost {
+                bytes_remaining: self.bytes_buffered(),
+                empty: self.buffer_i == 0,
+            },
+            None => TransferState::NotTransferring {
+                bytes_remaining: self.bytes_buffered(),
+                empty: self.buffer_i == 0,
+            },
+        }
+    }
+
+    /// Gets a mutable slice of the buffer of the specified length
+    /// panics if len requested is > the max size of the buffer
+    /// returns WouldBlock if there isn't currently space in the buffer
+    /// Advances the buffer pointer so don't call it unless you actually
+    /// need to put data in the buffer.
+    pub fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.peek_buffer_space(len)?;
+        trace_transfer_buffer!("BUFFER> successfully allocated {} bytes", len);
+
+        let s = self.buffer_i;
+        let e = s + len;
+
+        self.buffer_i += len;
+
+        Ok(&mut self.buffer[s..e])
+    }
+
+    /// The same as `take_buffer_space` but doesn't advance the buffer pointer
+    pub fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        if len > self.buffer.len() {
+            panic!("TransferBuffer::peek_buffer_space called with len > buffer.len() ({} > {}) which can never be successful",
+                len, self.buffer.len());
+        }
+
+        if len <= self.buffer.len() - self.buffer_i {
+            let s = self.buffer_i;
+            let e = s + len;
+
+            Ok(&mut self.buffer[s..e])
+        } else {
+            trace_transfer_buffer!("BUFFER> insufficient space to allocate {} bytes", len);
+            Err(UsbError::WouldBlock)?
+        }
+    }
+
+    /// Returns a slice containing data from the buffer if there is `len` bytes available
+    /// panics if len requested is > the max size of the buffer
+    /// `take_available` modifies behaviour to return whatever is available, even if that is [u8; 0]
+    /// returns WouldBlock if there isn't enough data available
+    pub fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        let len = self.peek_buffered_data(len, take_available)?.len();
+
+        let s = self.data_i;
+        let e = s + len;
+
+        self.data_i += len;
+        if self.data_i == self.buffer_i {
+            self.clear();
+        }
+        trace_transfer_buffer!("BUFFER> took {}, available after: {}", len, self.bytes_buffered());
+
+        Ok(&self.buffer[s..e])
+    }
+
+    /// The same as `take_buffered_data` but leaves the data in the buffer
+    pub fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        if len > self.buffer.len() {
+            panic!("TransferBuffer::peek_buffered_data called with len > buffer.len() ({} > {}) which can never be successful",
+                len, self.buffer.len());
+        }
+
+        let available = self.bytes_buffered();
+        if !take_available && len > available {
+            trace_transfer_buffer!("BUFFER> contains insufficient data for take; requested: {}, available: {}", len, available);
+            Err(UsbError::WouldBlock)?
+        }
+
+        let s = self.data_i;
+        let e = s + len.min(available);
+
+        Ok(&self.buffer[s..e])
+    }
+
+    /// Sends the next packet of buffered data on the bulk in endpoint of `msc` without going past
+    /// the end of the data phase. Returns the number of bytes sent
+    pub fn flush<B: UsbBus>(&mut self, msc: &mut MscClass<'_, B>) -> Result<usize, Error> {
+        let remaining = self.transfer_remaining as usize;
+        if self.is_empty() || remaining == 0 {
+            return Ok(0);
+        }
+
+        let start = self.data_i;
+        let len = self.bytes_buffered()
+            .min(remaining)
+            .min(msc.max_packet_size() as usize);
+
+        let bytes = msc.write_packet(&self.buffer[start..start + len])?;
+
+        self.data_i += bytes;
+        self.transfer_remaining -= bytes as u32;
+
+        // If we've sent all the bytes, reset the buffer indexes to 0 to free
+        // up the whole buffer for more data
+        if self.is_empty() || self.transfer_remaining == 0 {
+            self.clear();
+        }
+
+        Ok(bytes)
+    }
+
+    /// Reads a packet from the bulk out endpoint of `msc` into the buffer. Returns WouldBlock if
+    /// there isn't room for a full packet. Doesn't count towards the data phase, see `receive`
+    pub fn read_packet<B: UsbBus>(&mut self, msc: &mut MscClass<'_, B>) -> Result<usize, Error> {
+        if self.buffer.len() - self.buffer_i < msc.max_packet_size() as usize {
+            trace_transfer_buffer!("BUFFER> too full to read a packet");
+            Err(UsbError::WouldBlock)?;
+        }
+
+        let bytes = msc.read_packet(&mut self.buffer[self.buffer_i..])?;
+        self.buffer_i += bytes;
+
+        Ok(bytes)
+    }
+
+    /// Reads the next packet of the data phase if there's room for it. Returns the number of bytes
+    /// read, 0 if the data phase has finished or the buffer is too full
+    pub fn receive<B: UsbBus>(&mut self, msc: &mut MscClass<'_, B>) -> Result<usize, Error> {
+        if self.transfer_remaining == 0 ||
+            self.buffer.len() - self.buffer_i < msc.max_packet_size() as usize
+        {
+            return Ok(0);
+        }
+
+        let bytes = self.read_packet(msc)?;
+
+        if self.transfer_remaining >= bytes as u32 {
+            self.transfer_remaining -= bytes as u32;
+        } else {
+            warn!("Read more bytes than expected");
+            self.transfer_remaining = 0;
+        }
+
+        Ok(bytes)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,146 @@
+//! Interface between a mass storage transport protocol and the command set running on top of it
+use core::fmt::Debug;
+use usb_device::class_prelude::*;
+use packing::{
+    Error as PackingError,
+    Packed,
+};
+
+use crate::InterfaceSubclass;
+
+/// The buffer size used by transports if one isn't specified
+pub const DEFAULT_BUFFER_BYTES: usize = 512;
+
+#[derive(Debug)]
+pub enum TransportError {
+    UsbError(UsbError),
+    PackingError(PackingError),
+    DataError,
+    /// The host and device disagree about the direction or length of the data transfer. The
+    /// transport has already reported this to the host and the command should be abandoned
+    PhaseError,
+}
+
+impl From<UsbError> for TransportError {
+    fn from(e: UsbError) -> TransportError {
+        TransportError::UsbError(e)
+    }
+}
+impl From<PackingError> for TransportError {
+    fn from(e: PackingError) -> TransportError {
+        TransportError::PackingError(e)
+    }
+}
+
+/// The direction of a data transfer
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum Direction {
+    /// Host to device, OUT in USB parlance
+    HostToDevice = 0x00,
+    /// Device to host, IN in USB parlance
+    DeviceToHost = 0x80,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    /// There is no data transfer underway, we are either waiting for a command or
+    /// about to send a status after a transfer has finished. We might be sending a status
+    /// so `bytes_remaining` and `empty` use the same logic as `SendingDataToHost`
+    NotTransferring { bytes_remaining: usize, empty: bool },
+    /// We are receving data from the host, `bytes_available` indicates how many bytes
+    /// have been read into the buffer. `full` indicates we're out of buffer space and
+    /// need the caller to process the data before we can do more work. `done` indicates
+    /// everything the command intends to receive has been read and there won't be any more
+    ReceivingDataFromHost { bytes_available: usize, full: bool, done: bool },
+    /// We are sending data to the host, `bytes_remaining` indicates how many bytes are
+    /// still in the buffer. `empty` indicates the buffer is empty and we can't do any
+    /// more work before the caller gives us more data
+    SendingDataToHost { bytes_remaining: usize, empty: bool },
+}
+
+/// A command block received from the host
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct CommandBlock<'a> {
+    /// The LUN the command is addressed to if the transport carries one, otherwise 0
+    pub lun: u8,
+    /// The command set specific bytes of the command
+    pub bytes: &'a [u8],
+}
+
+/// # Mass storage transport protocol
+///
+/// Moves command blocks, data and status between the host and a command set implementation
+/// (for example [Scsi](struct.Scsi.html)). The command set polls the transport with `read` and
+/// `write`, picks up commands with `get_current_command` and reports the outcome of each one with
+/// `send_command_ok` or `send_command_error`.
+pub trait Transport<B: UsbBus>: UsbClass<B> {
+    /// Size of the buffer used for data transfers
+    const BUFFER_BYTES: usize;
+
+    /// The subclass (command set) reported in the interface descriptor
+    fn interface_subclass(&self) -> InterfaceSubclass;
+
+    /// Receives anything the host has sent
+    fn read(&mut self) -> Result<(), TransportError>;
+
+    /// Sends anything that's waiting to go to the host
+    fn write(&mut self) -> Result<(), TransportError>;
+
+    /// The command currently being executed. `None` if there isn't one or the command set has
+    /// already reported its status
+    fn get_current_command(&self) -> Option<CommandBlock<'_>>;
+
+    /// Tells the transport what the command set intends to do in the data phase of the current
+    /// command. Should be called once per command before any data is transferred
+    ///
+    /// `bytes` is how much data the command will transfer in `direction`. 0 means no data will be
+    /// transferred, in which case `direction` is ignored. Returns `TransportError::PhaseError` if
+    /// the host asked for something incompatible. The phase error has already been reported to
+    /// the host so the command should be abandoned without sending a status.
+    fn set_device_intent(&mut self, direction: Direction, bytes: u32) -> Result<(), TransportError>;
+
+    fn transfer_state(&self) -> TransferState;
+
+    /// Gets a mutable slice of the buffer of the specified length
+    /// panics if len requested is > the max size of the buffer
+    /// returns WouldBlock if there isn't currently space in the buffer
+    /// Advances the buffer pointer so don't call it unless you actually
+    /// need to put data in the buffer.
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], TransportError>;
+
+    /// The same as `take_buffer_space` but doesn't advance the buffer pointer. For filling the
+    /// space before deciding to keep it, follow it with `take_buffer_space` to keep it
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], TransportError>;
+
+    /// Returns a slice containing data from the buffer if there is `len` bytes available
+    /// panics if len requested is > the max size of the buffer
+    /// `take_available` modifies behaviour to return whatever is available, even if that is [u8; 0]
+    /// returns WouldBlock if there isn't enough data available
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], TransportError>;
+
+    /// The same as `take_buffered_data` but leaves the data in the buffer. For using the data
+    /// before deciding to consume it, follow it with `take_buffered_data` to consume it
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], TransportError>;
+
+    /// Ends the current command successfully once any buffered data has been sent
+    fn send_command_ok(&mut self) -> Result<(), TransportError>;
+
+    /// Ends the current command with a failure
+    ///
+    /// `sense_data` is fixed format sense data describing the failure. Only transports that report
+    /// sense along with the status (UAS, CBI with the UFI command set) use it, the others rely on the
+    /// host issuing a request sense
+    fn send_command_error(&mut self, sense_data: &[u8]) -> Result<(), TransportError>;
+
+    /// Is a reset of the mass storage interface by the host waiting for `finish_reset`. The reset
+    /// request has already been ACKed, the bulk endpoints aren't read or written until it's finished
+    fn reset_pending(&self) -> bool;
+
+    /// Ends a pending reset once the command set has reset everything it needs to. `ok` is false if
+    /// that failed, the transport reports it to the host in whatever way it can
+    fn finish_reset(&mut self, ok: bool);
+
+    /// Has the host selected an alternate setting of the interface since this was last called.
+    /// The transport has abandoned whatever it was doing, the command set should do the same
+    fn take_alt_setting_changed(&mut self) -> bool;
+}
\ No newline at end of file
@@ -14,19 +14,215 @@ pub enum BlockDeviceError {
 
     /// Address is invalid or out of range
     InvalidAddress,
+
+    /// The device isn't ready and isn't going to be without intervention
+    NotReady,
+
+    /// The device is starting up (an SD card initialising for example), the host will retry
+    BecomingReady,
+
+    /// There's no medium in the device
+    MediumNotPresent,
+
+    /// The medium can't be written to
+    WriteProtected,
+
+    /// The block couldn't be read, even after retries. A CRC failure reading from an SD card
+    /// for example
+    UnrecoveredReadError,
+
+    /// The block was read but only after correcting it. From `read_block` or `verify_block` this
+    /// means the block is fine (and `read_block` has filled the buffer), the transfer carries on
+    /// and the host is told once it's done
+    RecoveredReadError,
+
+    /// The device is busy with something else, the host will retry
+    Busy,
+
+    /// The device didn't respond in time
+    Timeout,
 }
 
+/// Power condition the host asks for with START STOP UNIT (SBC-3 5.25)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerCondition {
+    Active,
+    Idle,
+    Standby,
+    /// The host is handing control of the power condition back to the device
+    DeviceControlled,
+}
+
+/// Storage presented to the host as a logical unit
+///
+/// Reads and writes can take as long as they need. Returning `WouldBlock` leaves the transfer
+/// pending, the USB endpoint NAKs until the same call (same `lba` and contents) is made again
+/// and returns something other than `WouldBlock`. `block` isn't guaranteed to be at the same
+/// address between calls so anything that outlives a call (DMA for example) needs its own buffer.
+///
+/// While the endpoint is NAKing there are no USB interrupts to drive the retries. Call
+/// [Scsi::resume](struct.Scsi.html#method.resume) when the device finishes or periodically.
+///
+/// READ and WRITE are a span of blocks: `begin_read`/`begin_write` with the whole span, then
+/// `read_next_block`/`write_next_block` for each block in order, then `end_transfer`. Block devices
+/// that can transfer several blocks at once (SD card multi-block commands, chained DMA, flash
+/// programmed a page at a time) can use the span, the defaults just call `read_block`/`write_block`.
 pub trait BlockDevice {
     /// The number of bytes per block. This determines the size of the buffer passed
     /// to read/write functions
     const BLOCK_BYTES: usize;
 
     /// Read the block indicated by `lba` into the provided buffer
-    fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError>;
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> nb::Result<(), BlockDeviceError>;
 
     /// Write the `block` buffer to the block indicated by `lba`
-    fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError>;
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> nb::Result<(), BlockDeviceError>;
     
     /// Get the maxium valid lba (logical block address)
-    fn max_lba(&self) -> u32;
+    fn max_lba(&self) -> u64;
+
+    /// Start reading `count` blocks from `lba`. Called once for each READ before the first
+    /// `read_next_block`. Returns `WouldBlock` the same way as `read_block`. The default does nothing
+    fn begin_read(&mut self, _lba: u64, _count: u64) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Read the next block of the span started by `begin_read`, `lba` goes up by one each time.
+    /// Returns `WouldBlock` the same way as `read_block`. The default calls `read_block`
+    fn read_next_block(&mut self, lba: u64, block: &mut [u8]) -> nb::Result<(), BlockDeviceError> {
+        self.read_block(lba, block)
+    }
+
+    /// Start writing `count` blocks from `lba`. Called once for each WRITE before the first
+    /// `write_next_block`. Returns `WouldBlock` the same way as `write_block`. The default does nothing
+    fn begin_write(&mut self, _lba: u64, _count: u64) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Write the next block of the span started by `begin_write`, `lba` goes up by one each time.
+    /// Returns `WouldBlock` the same way as `write_block`. The default calls `write_block`
+    fn write_next_block(&mut self, lba: u64, block: &[u8]) -> nb::Result<(), BlockDeviceError> {
+        self.write_block(lba, block)
+    }
+
+    /// Finish the span started by `begin_read` or `begin_write` once its last block has been
+    /// transferred. The host doesn't get the status until it returns something other than
+    /// `WouldBlock`, so a multi-block write can wait for programming to finish. The default does
+    /// nothing
+    fn end_transfer(&mut self) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// The span started by `begin_read` or `begin_write` won't be finished because of an error, a
+    /// reset or the host changing the alternate setting. Called instead of `end_transfer`, possibly
+    /// after `reset`. It can't block, anything that has to wait needs doing in the next call. The
+    /// default does nothing
+    fn abort_transfer(&mut self) {}
+
+    /// Check the block indicated by `lba` can be read back. Used by VERIFY without BYTCHK.
+    /// Returns `WouldBlock` the same way as `read_block`. The default assumes every block is fine
+    fn verify_block(&mut self, _lba: u64) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Compare `block` with the block indicated by `lba`, `Ok(false)` if they're different. Used
+    /// by VERIFY with BYTCHK. Returns `WouldBlock` the same way as `read_block`
+    ///
+    /// There's no default because reading the block back needs a buffer of `BLOCK_BYTES`, which
+    /// only the block device knows the size of
+    fn compare_block(&mut self, lba: u64, block: &[u8]) -> nb::Result<bool, BlockDeviceError>;
+
+    /// Erase the whole medium for FORMAT UNIT. Called repeatedly until it returns something other
+    /// than `WouldBlock`, the first call starts the format. Nothing else that accesses the medium
+    /// is called until it's finished. The default does nothing
+    fn format(&mut self) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// How far through the format is, out of 65536. Hosts poll for it with REQUEST SENSE while a
+    /// format started with IMMED runs
+    fn format_progress(&self) -> u16 {
+        0
+    }
+
+    /// A write `write_block` already returned `Ok` for that failed when the cache was written
+    /// back outside of `write_block` or `flush`, along with its LBA. Checked before every command,
+    /// the host is told with a deferred error. Only the first is kept until it's been reported.
+    /// The default never has one
+    fn take_write_back_error(&mut self) -> Option<(u64, BlockDeviceError)> {
+        None
+    }
+
+    /// Write anything `write_block` has cached back to the medium. Called for SYNCHRONIZE CACHE,
+    /// which hosts send on fsync and before the medium is ejected or unmounted. Returns
+    /// `WouldBlock` the same way as `write_block`. The default does nothing
+    fn flush(&mut self) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// `write_block` can return before the data is on the medium and it needs a `flush` to get
+    /// there. Reported to the host as WCE in the caching mode page so it knows to send
+    /// SYNCHRONIZE CACHE. The default is write through
+    fn write_cache_enabled(&self) -> bool {
+        false
+    }
+
+    /// Writes are rejected with a DATA PROTECT sense key and the host is told the medium is
+    /// read only. Checked at the start of every command that changes the contents of the device.
+    /// The default is writable
+    fn is_write_protected(&self) -> bool {
+        false
+    }
+
+    /// Start or stop the medium for START STOP UNIT. Hosts stop it before ejecting the medium or
+    /// shutting down, anything cached has been flushed already unless they asked for it not to be.
+    /// Nothing is read or written while it's stopped, commands that need the medium fail until the
+    /// host starts it again. Returns `WouldBlock` the same way as `write_block`. The default does
+    /// nothing
+    fn start_stop(&mut self, _start: bool) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Enter the power condition the host asked for with START STOP UNIT. Returns `WouldBlock` the
+    /// same way as `write_block`. The default does nothing
+    fn set_power_condition(&mut self, _power_condition: PowerCondition) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Run the default self-test for SEND DIAGNOSTIC. Any error fails the test, the host gets a
+    /// HARDWARE ERROR. Returns `WouldBlock` the same way as `write_block`. The default passes
+    fn self_test(&mut self) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// The block device can discard blocks with `unmap`. Enables UNMAP and WRITE SAME(16) with the
+    /// UNMAP bit and tells the host it can send them, which is what TRIM turns into. The default
+    /// is false
+    fn unmap_supported(&self) -> bool {
+        false
+    }
+
+    /// Discard `count` blocks from `lba` that the host has no use for, for example because the
+    /// filesystem freed them. What they read back as afterwards is up to the block device. Only
+    /// called if `unmap_supported` returns true. Returns `WouldBlock` the same way as `write_block`
+    fn unmap(&mut self, _lba: u64, _count: u64) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// There are 2^n logical blocks of `BLOCK_BYTES` per physical block. For example 512 byte
+    /// blocks backed by 4KiB flash pages would be 3. Lets the host align writes to physical blocks.
+    /// The default is 0, one logical block per physical block
+    fn logical_blocks_per_physical_block_exponent(&self) -> u8 {
+        0
+    }
+
+    /// Abort anything in progress because the host has reset the interface (a bulk only mass
+    /// storage reset or a CBI command block reset)
+    ///
+    /// Called from `Scsi::poll` and `Scsi::resume` until it returns something other than
+    /// `WouldBlock`. The transport doesn't start the next command until then. An error stalls the
+    /// bulk endpoints so the host has to recover. The default does nothing
+    fn reset(&mut self) -> nb::Result<(), BlockDeviceError> {
+        Ok(())
+    }
 }
\ No newline at end of file
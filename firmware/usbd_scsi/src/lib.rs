@@ -6,6 +6,9 @@ pub use scsi::*;
 mod block_device;
 pub use block_device::*;
 
+mod logical_units;
+pub use logical_units::*;
+
 mod logging {
     pub use itm_logger::*;
 
@@ -0,0 +1,66 @@
+use crate::{
+    block_device::BlockDevice,
+    scsi::LogicalUnitState,
+};
+
+/// Does some work with the block device behind a logical unit
+///
+/// [LogicalUnits](trait.LogicalUnits.html) can contain block devices of different types so this is
+/// how the correctly typed block device for a LUN is handed out without needing dynamic dispatch
+pub trait BlockDeviceVisitor {
+    type Output;
+
+    fn visit<BD: BlockDevice>(self, block_device: &mut BD) -> Self::Output;
+}
+
+/// A set of block devices, each of which is presented to the host as a separate logical unit (LUN)
+///
+/// Implemented for any single [BlockDevice](trait.BlockDevice.html), which is presented as LUN 0, and
+/// for tuples of up to 4 block devices where the position in the tuple is the LUN. For example
+/// `(internal_flash, sd_card)` presents the internal flash as LUN 0 and the SD card as LUN 1.
+pub trait LogicalUnits {
+    /// The number of logical units in the set. Must be between 1 and 16
+    const COUNT: u8;
+
+    /// Storage for the state [Scsi](struct.Scsi.html) keeps for each logical unit. Should be an
+    /// array of `COUNT` elements
+    type States: Default + AsRef<[LogicalUnitState]> + AsMut<[LogicalUnitState]>;
+
+    /// Calls `visitor` with the block device for `lun`
+    ///
+    /// Returns `None` if `lun` isn't one of the logical units in this set
+    fn visit<V: BlockDeviceVisitor>(&mut self, lun: u8, visitor: V) -> Option<V::Output>;
+}
+
+impl<BD: BlockDevice> LogicalUnits for BD {
+    const COUNT: u8 = 1;
+    type States = [LogicalUnitState; 1];
+
+    fn visit<V: BlockDeviceVisitor>(&mut self, lun: u8, visitor: V) -> Option<V::Output> {
+        match lun {
+            0 => Some(visitor.visit(self)),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_logical_units_for_tuple {
+    ($count: expr; $($lun: tt => $bd: ident),+) => (
+        impl<$($bd: BlockDevice),+> LogicalUnits for ($($bd,)+) {
+            const COUNT: u8 = $count;
+            type States = [LogicalUnitState; $count];
+
+            fn visit<V: BlockDeviceVisitor>(&mut self, lun: u8, visitor: V) -> Option<V::Output> {
+                match lun {
+                    $($lun => Some(visitor.visit(&mut self.$lun)),)+
+                    _ => None,
+                }
+            }
+        }
+    )
+}
+
+impl_logical_units_for_tuple!(1; 0 => BD0);
+impl_logical_units_for_tuple!(2; 0 => BD0, 1 => BD1);
+impl_logical_units_for_tuple!(3; 0 => BD0, 1 => BD1, 2 => BD2);
+impl_logical_units_for_tuple!(4; 0 => BD0, 1 => BD1, 2 => BD2, 3 => BD3);
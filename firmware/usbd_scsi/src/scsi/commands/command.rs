@@ -1,6 +1,6 @@
 use packing::Packed;
 
-use usbd_bulk_only_transport::CommandBlockWrapper as CommandBlockWrapper_NEW;
+use usbd_mass_storage::CommandBlock;
 use crate::scsi::{
     commands::*,
     enums::*,
@@ -15,6 +15,7 @@ pub enum Command {
     Inquiry(InquiryCommand),
     TestUnitReady(TestUnitReadyCommand),
     ReadCapacity(ReadCapacity10Command),
+    ReadCapacity16(ReadCapacity16Command),
     ModeSense(ModeSenseXCommand),
     PreventAllowMediumRemoval(PreventAllowMediumRemovalCommand),
     RequestSense(RequestSenseCommand),
@@ -28,47 +29,55 @@ pub enum Command {
     ReadFormatCapacities(ReadFormatCapacitiesCommand),
     Verify(Verify10Command),
     SynchronizeCache(SynchronizeCache10Command),
+    Unmap(UnmapCommand),
+    WriteSame(WriteSame16Command),
 }
 
 impl Command {
-    pub fn extract_from_cbw(cbw: &CommandBlockWrapper_NEW) -> Result<Command, Error> {
-        let op_code = OpCode::from_primitive(cbw.data[0]).map_err(|_| Error::UnhandledOpCode)?;
+    pub fn extract_from_command_block(command_block: &CommandBlock) -> Result<Command, Error> {
+        let op_code = *command_block.bytes.first().ok_or(Error::InsufficientDataForCommand)?;
+        let op_code = OpCode::from_primitive(op_code).map_err(|_| Error::UnhandledOpCode)?;
         match op_code {
-            OpCode::Read6 => Ok(Command::Read(checked_extract::<Read6Command>(cbw)?.into())),
-            OpCode::Read10 => Ok(Command::Read(checked_extract::<Read10Command>(cbw)?.into())),
-            OpCode::Read12 => Ok(Command::Read(checked_extract::<Read12Command>(cbw)?.into())),
-            OpCode::ReadCapacity10 => Ok(Command::ReadCapacity(checked_extract(cbw)?)), 
-            OpCode::ReadFormatCapacities => Ok(Command::ReadFormatCapacities(checked_extract(cbw)?)),
-            OpCode::Inquiry => Ok(Command::Inquiry(checked_extract(cbw)?)),
-            OpCode::TestUnitReady => Ok(Command::TestUnitReady(checked_extract(cbw)?)),
-            OpCode::ModeSense6 => Ok(Command::ModeSense(checked_extract::<ModeSense6Command>(cbw)?.into())),
-            OpCode::ModeSense10 => Ok(Command::ModeSense(checked_extract::<ModeSense10Command>(cbw)?.into())),
-            OpCode::ModeSelect6 => Ok(Command::ModeSelect(checked_extract::<ModeSelect6Command>(cbw)?.into())),
-            OpCode::ModeSelect10 => Ok(Command::ModeSelect(checked_extract::<ModeSelect10Command>(cbw)?.into())),
-            OpCode::PreventAllowMediumRemoval => Ok(Command::PreventAllowMediumRemoval(checked_extract(cbw)?)),
-            OpCode::RequestSense => Ok(Command::RequestSense(checked_extract(cbw)?)),
-            OpCode::Write6 => Ok(Command::Write(checked_extract::<Write6Command>(cbw)?.into())),
-            OpCode::Write10 => Ok(Command::Write(checked_extract::<Write10Command>(cbw)?.into())),
-            OpCode::Write12 => Ok(Command::Write(checked_extract::<Write12Command>(cbw)?.into())),
-            OpCode::Format => Ok(Command::Format(checked_extract(cbw)?)),
-            OpCode::SendDiagnostic => Ok(Command::SendDiagnostic(checked_extract(cbw)?)),
-            OpCode::ReportLuns => Ok(Command::ReportLuns(checked_extract(cbw)?)),
-            OpCode::StartStopUnit => Ok(Command::StartStopUnit(checked_extract(cbw)?)),
-            OpCode::Verify10 => Ok(Command::Verify(checked_extract(cbw)?)),
-            OpCode::SynchronizeCache10 => Ok(Command::SynchronizeCache(checked_extract(cbw)?)),
+            OpCode::Read6 => Ok(Command::Read(checked_extract::<Read6Command>(command_block)?.into())),
+            OpCode::Read10 => Ok(Command::Read(checked_extract::<Read10Command>(command_block)?.into())),
+            OpCode::Read12 => Ok(Command::Read(checked_extract::<Read12Command>(command_block)?.into())),
+            OpCode::Read16 => Ok(Command::Read(checked_extract::<Read16Command>(command_block)?.into())),
+            OpCode::ReadCapacity10 => Ok(Command::ReadCapacity(checked_extract(command_block)?)), 
+            OpCode::ServiceActionIn16 => Ok(Command::ReadCapacity16(checked_extract(command_block)?)),
+            OpCode::ReadFormatCapacities => Ok(Command::ReadFormatCapacities(checked_extract(command_block)?)),
+            OpCode::Inquiry => Ok(Command::Inquiry(checked_extract(command_block)?)),
+            OpCode::TestUnitReady => Ok(Command::TestUnitReady(checked_extract(command_block)?)),
+            OpCode::ModeSense6 => Ok(Command::ModeSense(checked_extract::<ModeSense6Command>(command_block)?.into())),
+            OpCode::ModeSense10 => Ok(Command::ModeSense(checked_extract::<ModeSense10Command>(command_block)?.into())),
+            OpCode::ModeSelect6 => Ok(Command::ModeSelect(checked_extract::<ModeSelect6Command>(command_block)?.into())),
+            OpCode::ModeSelect10 => Ok(Command::ModeSelect(checked_extract::<ModeSelect10Command>(command_block)?.into())),
+            OpCode::PreventAllowMediumRemoval => Ok(Command::PreventAllowMediumRemoval(checked_extract(command_block)?)),
+            OpCode::RequestSense => Ok(Command::RequestSense(checked_extract(command_block)?)),
+            OpCode::Write6 => Ok(Command::Write(checked_extract::<Write6Command>(command_block)?.into())),
+            OpCode::Write10 => Ok(Command::Write(checked_extract::<Write10Command>(command_block)?.into())),
+            OpCode::Write12 => Ok(Command::Write(checked_extract::<Write12Command>(command_block)?.into())),
+            OpCode::Write16 => Ok(Command::Write(checked_extract::<Write16Command>(command_block)?.into())),
+            OpCode::Format => Ok(Command::Format(checked_extract(command_block)?)),
+            OpCode::SendDiagnostic => Ok(Command::SendDiagnostic(checked_extract(command_block)?)),
+            OpCode::ReportLuns => Ok(Command::ReportLuns(checked_extract(command_block)?)),
+            OpCode::StartStopUnit => Ok(Command::StartStopUnit(checked_extract(command_block)?)),
+            OpCode::Verify10 => Ok(Command::Verify(checked_extract(command_block)?)),
+            OpCode::SynchronizeCache10 => Ok(Command::SynchronizeCache(checked_extract(command_block)?)),
+            OpCode::Unmap => Ok(Command::Unmap(checked_extract(command_block)?)),
+            OpCode::WriteSame16 => Ok(Command::WriteSame(checked_extract(command_block)?)),
             _ => Err(Error::UnhandledOpCode),
         }
     }
 }
 
 
-fn checked_extract<T>(cbw: &CommandBlockWrapper_NEW) -> Result<T, Error> 
+fn checked_extract<T>(command_block: &CommandBlock) -> Result<T, Error> 
 where 
     T: ParsePackedStruct,
     Error: From<<T as Packed>::Error>,
 {
-    if cbw.data_length < T::BYTES as u8 {
+    if command_block.bytes.len() < T::BYTES {
         Err(Error::InsufficientDataForCommand)?;
     }
-    Ok(T::parse(&cbw.data)?)
+    Ok(T::parse(command_block.bytes)?)
 }
\ No newline at end of file
@@ -28,4 +28,102 @@ pub struct FormatCommand {
     #[pkd(7, 0, 5, 5)]
     pub control: Control,
 }
-impl ParsePackedStruct for FormatCommand {}
\ No newline at end of file
+impl ParsePackedStruct for FormatCommand {}
+
+/// Header of the parameter list sent when FMTDATA is set and LONGLIST isn't (SBC-3 5.3.2.2)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ShortFormatParameterListHeader {
+    #[pkd(2, 0, 0, 0)]
+    pub protection_fields_usage: u8,
+
+    #[pkd(7, 7, 1, 1)]
+    pub format_options_valid: bool,
+
+    #[pkd(6, 6, 1, 1)]
+    pub disable_primary: bool,
+
+    #[pkd(5, 5, 1, 1)]
+    pub disable_certification: bool,
+
+    #[pkd(4, 4, 1, 1)]
+    pub stop_format: bool,
+
+    /// An initialization pattern descriptor follows the header
+    #[pkd(3, 3, 1, 1)]
+    pub initialization_pattern: bool,
+
+    #[pkd(2, 2, 1, 1)]
+    pub disable_saving_parameters: bool,
+
+    /// Send the status as soon as the parameter list has been checked rather than after the format
+    #[pkd(1, 1, 1, 1)]
+    pub immediate: bool,
+
+    #[pkd(7, 0, 2, 3)]
+    pub defect_list_length: u16,
+}
+
+/// Header of the parameter list sent when FMTDATA and LONGLIST are set (SBC-3 5.3.2.2)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LongFormatParameterListHeader {
+    #[pkd(2, 0, 0, 0)]
+    pub protection_fields_usage: u8,
+
+    #[pkd(7, 7, 1, 1)]
+    pub format_options_valid: bool,
+
+    #[pkd(6, 6, 1, 1)]
+    pub disable_primary: bool,
+
+    #[pkd(5, 5, 1, 1)]
+    pub disable_certification: bool,
+
+    #[pkd(4, 4, 1, 1)]
+    pub stop_format: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub initialization_pattern: bool,
+
+    #[pkd(2, 2, 1, 1)]
+    pub disable_saving_parameters: bool,
+
+    #[pkd(1, 1, 1, 1)]
+    pub immediate: bool,
+
+    #[pkd(7, 4, 3, 3)]
+    pub p_i_information: u8,
+
+    #[pkd(3, 0, 3, 3)]
+    pub protection_interval_exponent: u8,
+
+    #[pkd(7, 0, 4, 7)]
+    pub defect_list_length: u32,
+}
+
+/// The parts of either parameter list header that matter
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct FormatParameterListHeader {
+    pub initialization_pattern: bool,
+    pub immediate: bool,
+    pub defect_list_length: u32,
+}
+impl From<ShortFormatParameterListHeader> for FormatParameterListHeader {
+    fn from(h: ShortFormatParameterListHeader) -> Self {
+        Self {
+            initialization_pattern: h.initialization_pattern,
+            immediate: h.immediate,
+            defect_list_length: h.defect_list_length.into(),
+        }
+    }
+}
+impl From<LongFormatParameterListHeader> for FormatParameterListHeader {
+    fn from(h: LongFormatParameterListHeader) -> Self {
+        Self {
+            initialization_pattern: h.initialization_pattern,
+            immediate: h.immediate,
+            defect_list_length: h.defect_list_length,
+        }
+    }
+}
\ No newline at end of file
@@ -31,45 +31,31 @@ pub struct InquiryCommand {
 impl ParsePackedStruct for InquiryCommand {}
 
 
-
-/*
- if evpd 
-    return data related to page_code (spc-4 section 7.8)
-    if unsupported(page_code) 
-        return CHECK_CONDITION and set SENSE:   
-            key: ILLEGAL_REQUEST
-            additional code: INVALID_FIELD_IN_CBD
- 
- if !evpd
-    return standard inquiry data (spc-4 section 6.4.2)
-    if page_code != 0
-        return CHECK_CONDITION and set SENSE:   
-            key: ILLEGAL_REQUEST
-            additional code: INVALID_FIELD_IN_CBD 
-*/      
-
-
 #[test]
 fn test_inquiry() {
-    let mut bytes = [0; 5];
+    let mut bytes = [0; 6];
     let mut cmd = InquiryCommand::default();
     assert_eq!(cmd, InquiryCommand::unpack(&bytes).unwrap());
 
-    bytes[0] |= 0b00000001;
+    bytes[0] = 0x12;
+    cmd.op_code = 0x12;
+    assert_eq!(cmd, InquiryCommand::unpack(&bytes).unwrap());
+
+    bytes[1] |= 0b00000001;
     cmd.enable_vital_product_data = true;
     assert_eq!(cmd, InquiryCommand::unpack(&bytes).unwrap());    
 
-    bytes[1] = 0x99;
+    bytes[2] = 0x99;
     cmd.page_code = 0x99;
     assert_eq!(cmd, InquiryCommand::unpack(&bytes).unwrap());    
 
     let al = 9999;
-    bytes[2] = ((al >> 8) & 0xFF) as u8;
-    bytes[3] = ((al >> 0) & 0xFF) as u8;
+    bytes[3] = ((al >> 8) & 0xFF) as u8;
+    bytes[4] = ((al >> 0) & 0xFF) as u8;
     cmd.allocation_length = al;
     assert_eq!(cmd, InquiryCommand::unpack(&bytes).unwrap());    
 
-    bytes[4] = 0x31;
+    bytes[5] = 0x31;
     cmd.control = Control::default();
     assert_eq!(cmd, InquiryCommand::unpack(&bytes).unwrap());    
 }
\ No newline at end of file
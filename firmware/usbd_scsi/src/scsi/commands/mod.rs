@@ -49,11 +49,17 @@ pub use synchronize_cache::*;
 mod test_unit_ready;
 pub use test_unit_ready::*;
 
+mod unmap;
+pub use unmap::*;
+
 mod verify;
 pub use verify::*;
 
 mod write;
 pub use write::*;
 
+mod write_same;
+pub use write_same::*;
+
 mod mode_parameter;
 pub use mode_parameter::*;
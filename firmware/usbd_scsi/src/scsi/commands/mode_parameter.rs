@@ -33,9 +33,13 @@ impl ModeParameterHeader6 {
     /// Increase the relevant length fields to indicate the provided page follows this header
     /// can be called multiple times but be aware of the max length allocated by CBW
     pub fn increase_length_for_page(&mut self, page_code: PageCode) {
-        self.mode_data_length += match page_code {
-            PageCode::CachingModePage => CachingModePage::BYTES as u8,
-        };
+        self.mode_data_length += page_code.page_bytes() as u8;
+    }
+
+    /// Set the block descriptor length and increase the mode data length to include it
+    pub fn set_block_descriptor_length(&mut self, bytes: usize) {
+        self.mode_data_length += bytes as u8 - self.block_descriptor_length;
+        self.block_descriptor_length = bytes as u8;
     }
 }
 
@@ -71,11 +75,14 @@ impl Default for ModeParameterHeader10 {
 impl ModeParameterHeader10 {
     /// Increase the relevant length fields to indicate the provided page follows this header
     /// can be called multiple times but be aware of the max length allocated by CBW
-    #[allow(dead_code)]
     pub fn increase_length_for_page(&mut self, page_code: PageCode) {
-        self.mode_data_length += match page_code {
-            PageCode::CachingModePage => CachingModePage::BYTES as u16,
-        };
+        self.mode_data_length += page_code.page_bytes() as u16;
+    }
+
+    /// Set the block descriptor length and increase the mode data length to include it
+    pub fn set_block_descriptor_length(&mut self, bytes: usize) {
+        self.mode_data_length += bytes as u16 - self.block_descriptor_length;
+        self.block_descriptor_length = bytes as u16;
     }
 }
 
@@ -90,13 +97,233 @@ pub struct SbcDeviceSpecificParameter {
     pub disable_page_out_and_force_unit_access_available: bool,
 }
 
+/// Block descriptor returned by MODE SENSE unless the long LBA one was asked for (SBC-3 6.4.4.2)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed, Default)]
+#[packed(big_endian, lsb0)]
+pub struct ShortLbaBlockDescriptor {
+    /// Saturates at 0xFFFFFFFF, READ CAPACITY(16) has the real number
+    #[pkd(7, 0, 0, 3)]
+    pub number_of_blocks: u32,
+
+    #[pkd(7, 0, 5, 7)]
+    pub logical_block_length: u32,
+}
+impl ShortLbaBlockDescriptor {
+    pub fn new(number_of_blocks: u64, logical_block_length: u32) -> Self {
+        Self {
+            number_of_blocks: number_of_blocks.min(u32::MAX.into()) as u32,
+            logical_block_length,
+        }
+    }
+}
+
+/// Block descriptor returned by MODE SENSE(10) when LLBAA is set (SBC-3 6.4.4.3)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed, Default)]
+#[packed(big_endian, lsb0)]
+pub struct LongLbaBlockDescriptor {
+    #[pkd(7, 0, 0, 7)]
+    pub number_of_blocks: u64,
+
+    #[pkd(7, 0, 12, 15)]
+    pub logical_block_length: u32,
+}
+impl LongLbaBlockDescriptor {
+    pub fn new(number_of_blocks: u64, logical_block_length: u32) -> Self {
+        Self {
+            number_of_blocks,
+            logical_block_length,
+        }
+    }
+}
+
+/// MODE SENSE page code that asks for every page
+pub const ALL_PAGES: u8 = 0x3F;
+
+/// MODE SENSE subpage code that asks for every subpage
+pub const ALL_SUBPAGES: u8 = 0xFF;
+
+/// Parameters saveable bit in byte 0 of a page. Reserved in MODE SELECT
+pub const PARAMETERS_SAVEABLE: u8 = 0x80;
+
+/// Subpage format bit in byte 0 of a page. None of the supported pages have subpages
+pub const SUBPAGE_FORMAT: u8 = 0x40;
+
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 pub enum PageCode {
-    CachingModePage = 0x08,
+    ReadWriteErrorRecovery = 0x01,
+    FlexibleDisk = 0x05,
+    Caching = 0x08,
+    InformationalExceptionsControl = 0x1C,
+}
+
+impl PageCode {
+    /// Every supported page in the order they're returned for `ALL_PAGES`
+    pub const ALL: [PageCode; 4] = [
+        PageCode::ReadWriteErrorRecovery,
+        PageCode::FlexibleDisk,
+        PageCode::Caching,
+        PageCode::InformationalExceptionsControl,
+    ];
+
+    /// Length of the whole page including the page code and page length bytes
+    pub fn page_bytes(&self) -> usize {
+        match self {
+            PageCode::ReadWriteErrorRecovery => ReadWriteErrorRecoveryModePage::BYTES,
+            PageCode::FlexibleDisk => FlexibleDiskModePage::BYTES,
+            PageCode::Caching => CachingModePage::BYTES,
+            PageCode::InformationalExceptionsControl => InformationalExceptionsControlModePage::BYTES,
+        }
+    }
+}
+
+/// SBC-3 6.4.8
+/// Default config is to report recovered errors as little as possible, there's nothing to
+/// configure on flash
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadWriteErrorRecoveryModePage {
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    #[pkd(7, 7, 2, 2)]
+    pub automatic_write_reallocation_enabled: bool,
+
+    #[pkd(6, 6, 2, 2)]
+    pub automatic_read_reallocation_enabled: bool,
+
+    #[pkd(5, 5, 2, 2)]
+    pub transfer_block: bool,
+
+    #[pkd(4, 4, 2, 2)]
+    pub read_continuous: bool,
+
+    #[pkd(3, 3, 2, 2)]
+    pub enable_early_recovery: bool,
+
+    #[pkd(2, 2, 2, 2)]
+    pub post_error: bool,
+
+    #[pkd(1, 1, 2, 2)]
+    pub data_terminate_on_error: bool,
+
+    #[pkd(0, 0, 2, 2)]
+    pub disable_correction: bool,
+
+    #[pkd(7, 0, 3, 3)]
+    pub read_retry_count: u8,
+
+    #[pkd(7, 0, 8, 8)]
+    pub write_retry_count: u8,
+
+    #[pkd(7, 0, 10, 11)]
+    pub recovery_time_limit: u16,
+}
+impl Default for ReadWriteErrorRecoveryModePage {
+    fn default() -> Self {
+        Self {
+            page_code: PageCode::ReadWriteErrorRecovery,
+            page_length: Self::BYTES as u8 - 2,
+            automatic_write_reallocation_enabled: false,
+            automatic_read_reallocation_enabled: false,
+            transfer_block: false,
+            read_continuous: false,
+            enable_early_recovery: false,
+            post_error: false,
+            data_terminate_on_error: false,
+            disable_correction: false,
+            read_retry_count: 0,
+            write_retry_count: 0,
+            recovery_time_limit: 0,
+        }
+    }
+}
+
+/// SBC-2 6.3.4, dropped from SBC-3 but UFI hosts still ask for it
+/// None of it means anything for flash so it's made up from the size of the device
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct FlexibleDiskModePage {
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    /// kbit/s
+    #[pkd(7, 0, 2, 3)]
+    pub transfer_rate: u16,
+
+    #[pkd(7, 0, 4, 4)]
+    pub number_of_heads: u8,
+
+    #[pkd(7, 0, 5, 5)]
+    pub sectors_per_track: u8,
+
+    #[pkd(7, 0, 6, 7)]
+    pub data_bytes_per_sector: u16,
+
+    #[pkd(7, 0, 8, 9)]
+    pub number_of_cylinders: u16,
+
+    /// Tenths of a second
+    #[pkd(7, 0, 19, 19)]
+    pub motor_on_delay: u8,
+
+    /// Tenths of a second
+    #[pkd(7, 0, 20, 20)]
+    pub motor_off_delay: u8,
+
+    /// RPM
+    #[pkd(7, 0, 28, 29)]
+    pub medium_rotation_rate: u16,
+
+    #[pkd(7, 0, 30, 31)]
+    _reserved: u16,
 }
+impl Default for FlexibleDiskModePage {
+    fn default() -> Self {
+        Self {
+            page_code: PageCode::FlexibleDisk,
+            page_length: Self::BYTES as u8 - 2,
+            // Values for a 1.44MB floppy from the UFI spec
+            transfer_rate: 500,
+            number_of_heads: 2,
+            sectors_per_track: 18,
+            data_bytes_per_sector: 512,
+            number_of_cylinders: 80,
+            motor_on_delay: 5,
+            motor_off_delay: 30,
+            medium_rotation_rate: 300,
+            _reserved: 0,
+        }
+    }
+}
+impl FlexibleDiskModePage {
+    /// A geometry that covers as much of a device with `number_of_blocks` blocks as possible
+    pub fn new(number_of_blocks: u64, block_bytes: u16) -> Self {
+        let mut page = Self {
+            data_bytes_per_sector: block_bytes,
+            ..Default::default()
+        };
 
-/// This is only a partial implementation, there are a whole load of extra
-/// fields defined in SBC-3 6.4.5
+        let floppy_blocks = page.number_of_heads as u64 * page.sectors_per_track as u64 * page.number_of_cylinders as u64;
+        if number_of_blocks != floppy_blocks {
+            // Largest heads and sectors the fields allow that still leave room for cylinders
+            page.number_of_heads = 255;
+            page.sectors_per_track = 63;
+            let blocks_per_cylinder = page.number_of_heads as u64 * page.sectors_per_track as u64;
+            page.number_of_cylinders = (number_of_blocks / blocks_per_cylinder)
+                .max(1)
+                .min(u16::MAX.into()) as u16;
+        }
+        page
+    }
+}
+
+/// SBC-3 6.4.5
 /// Default config is no read or write cache
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
@@ -107,19 +334,167 @@ pub struct CachingModePage {
     #[pkd(7, 0, 1, 1)]
     pub page_length: u8,
 
+    #[pkd(7, 7, 2, 2)]
+    pub initiator_control: bool,
+
+    #[pkd(6, 6, 2, 2)]
+    pub abort_prefetch: bool,
+
+    #[pkd(5, 5, 2, 2)]
+    pub caching_analysis_permitted: bool,
+
+    #[pkd(4, 4, 2, 2)]
+    pub discontinuity: bool,
+
+    #[pkd(3, 3, 2, 2)]
+    pub size_enable: bool,
+
     #[pkd(2, 2, 2, 2)]
     pub write_cache_enabled: bool,
 
+    #[pkd(1, 1, 2, 2)]
+    pub multiplication_factor: bool,
+
     #[pkd(0, 0, 2, 2)]
     pub read_cache_disable: bool,
+
+    #[pkd(7, 4, 3, 3)]
+    pub demand_read_retention_priority: u8,
+
+    #[pkd(3, 0, 3, 3)]
+    pub write_retention_priority: u8,
+
+    #[pkd(7, 0, 4, 5)]
+    pub disable_prefetch_transfer_length: u16,
+
+    #[pkd(7, 0, 6, 7)]
+    pub minimum_prefetch: u16,
+
+    #[pkd(7, 0, 8, 9)]
+    pub maximum_prefetch: u16,
+
+    #[pkd(7, 0, 10, 11)]
+    pub maximum_prefetch_ceiling: u16,
+
+    #[pkd(7, 7, 12, 12)]
+    pub force_sequential_write: bool,
+
+    #[pkd(6, 6, 12, 12)]
+    pub logical_block_cache_segment_size: bool,
+
+    #[pkd(5, 5, 12, 12)]
+    pub disable_read_ahead: bool,
+
+    #[pkd(0, 0, 12, 12)]
+    pub non_volatile_cache_disabled: bool,
+
+    #[pkd(7, 0, 13, 13)]
+    pub number_of_cache_segments: u8,
+
+    #[pkd(7, 0, 14, 15)]
+    pub cache_segment_size: u16,
+
+    #[pkd(7, 0, 16, 19)]
+    _reserved: u32,
 }
 impl Default for CachingModePage {
     fn default() -> Self {
         Self {
-            page_code: PageCode::CachingModePage,
-            page_length: Self::BYTES as u8,
+            page_code: PageCode::Caching,
+            page_length: Self::BYTES as u8 - 2,
+            initiator_control: false,
+            abort_prefetch: false,
+            caching_analysis_permitted: false,
+            discontinuity: false,
+            size_enable: false,
             write_cache_enabled: false,
+            multiplication_factor: false,
             read_cache_disable: true,
+            demand_read_retention_priority: 0,
+            write_retention_priority: 0,
+            disable_prefetch_transfer_length: 0,
+            minimum_prefetch: 0,
+            maximum_prefetch: 0,
+            maximum_prefetch_ceiling: 0,
+            force_sequential_write: false,
+            logical_block_cache_segment_size: false,
+            disable_read_ahead: false,
+            non_volatile_cache_disabled: false,
+            number_of_cache_segments: 0,
+            cache_segment_size: 0,
+            _reserved: 0,
+        }
+    }
+}
+
+/// SPC-4 7.5.8
+/// Default config is informational exceptions disabled since nothing generates them
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct InformationalExceptionsControlModePage {
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    #[pkd(7, 7, 2, 2)]
+    pub performance: bool,
+
+    #[pkd(5, 5, 2, 2)]
+    pub enable_background_function: bool,
+
+    #[pkd(4, 4, 2, 2)]
+    pub enable_warning: bool,
+
+    #[pkd(3, 3, 2, 2)]
+    pub disable_exception_control: bool,
+
+    #[pkd(2, 2, 2, 2)]
+    pub test: bool,
+
+    #[pkd(1, 1, 2, 2)]
+    pub enable_background_error: bool,
+
+    #[pkd(0, 0, 2, 2)]
+    pub log_errors: bool,
+
+    #[pkd(3, 0, 3, 3)]
+    pub method_of_reporting_informational_exceptions: u8,
+
+    /// 100ms units
+    #[pkd(7, 0, 4, 7)]
+    pub interval_timer: u32,
+
+    #[pkd(7, 0, 8, 11)]
+    pub report_count: u32,
+}
+impl Default for InformationalExceptionsControlModePage {
+    fn default() -> Self {
+        Self {
+            page_code: PageCode::InformationalExceptionsControl,
+            page_length: Self::BYTES as u8 - 2,
+            performance: false,
+            enable_background_function: false,
+            enable_warning: false,
+            disable_exception_control: true,
+            test: false,
+            enable_background_error: false,
+            log_errors: false,
+            method_of_reporting_informational_exceptions: 0,
+            interval_timer: 0,
+            report_count: 0,
         }
     }
+}
+
+#[test]
+fn test_mode_page_lengths() {
+    // The page length byte doesn't include itself or the page code
+    assert_eq!(ReadWriteErrorRecoveryModePage::default().page_length, 0x0A);
+    assert_eq!(FlexibleDiskModePage::default().page_length, 0x1E);
+    assert_eq!(CachingModePage::default().page_length, 0x12);
+    assert_eq!(InformationalExceptionsControlModePage::default().page_length, 0x0A);
+    assert_eq!(ShortLbaBlockDescriptor::BYTES, 8);
+    assert_eq!(LongLbaBlockDescriptor::BYTES, 16);
 }
\ No newline at end of file
@@ -25,7 +25,15 @@ B) the default values.
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ModeSenseXCommand {
     pub command_length: CommandLength,
-    pub page_control: PageControl
+    pub page_control: PageControl,
+    /// The page to return or 0x3F for all of them
+    pub page_code: u8,
+    pub subpage_code: u8,
+    /// Leave the block descriptor out
+    pub disable_block_descriptors: bool,
+    /// A long LBA block descriptor may be returned. Always false for MODE SENSE(6)
+    pub long_lba_accepted: bool,
+    pub allocation_length: u16,
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
@@ -58,6 +66,11 @@ impl From<ModeSense6Command> for ModeSenseXCommand {
         Self { 
             command_length: CommandLength::C6,
             page_control: m.page_control,
+            page_code: m.page_code,
+            subpage_code: m.subpage_code,
+            disable_block_descriptors: m.disable_block_descriptors,
+            long_lba_accepted: false,
+            allocation_length: m.allocation_length.into(),
         }
     }
 }
@@ -95,6 +108,11 @@ impl From<ModeSense10Command> for ModeSenseXCommand {
         Self {
             command_length: CommandLength::C10,
             page_control: m.page_control, 
+            page_code: m.page_code,
+            subpage_code: m.subpage_code,
+            disable_block_descriptors: m.disable_block_descriptors,
+            long_lba_accepted: m.long_lba_accepted,
+            allocation_length: m.allocation_length,
         }
     }
 }
\ No newline at end of file
@@ -6,7 +6,7 @@ use crate::scsi::{
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ReadXCommand {
-    pub lba: u32,
+    pub lba: u64,
     pub transfer_length: u32,
 }
 
@@ -32,7 +32,8 @@ impl From<Read6Command> for ReadXCommand {
     fn from(r: Read6Command) -> Self {
         Self {
             lba: r.lba.into(),
-            transfer_length: r.transfer_length.into(),
+            // READ(6) can't ask for 0 blocks, 0 means 256 (SBC-3 5.9)
+            transfer_length: if r.transfer_length == 0 { 256 } else { r.transfer_length.into() },
         }
     }
 }
@@ -120,11 +121,57 @@ impl From<Read12Command> for ReadXCommand {
 }
 
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct Read16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub rd_protect: u8,
+
+    #[pkd(4, 4, 1, 1)]
+    pub dpo: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub fua: bool,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 10, 13)]
+    pub transfer_length: u32,
+
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for Read16Command {}
+impl From<Read16Command> for ReadXCommand {
+    fn from(r: Read16Command) -> Self {
+        Self {
+            lba: r.lba,
+            transfer_length: r.transfer_length,
+        }
+    }
+}
+
+
 
 
 #[test]
 fn test_read10_parse() {
-    let data = [0, 0, 0, 0x1E, 0x80, 0, 0, 0x8, 0, 0, 0, 0, 0, 0, 0];
-    let cmd = Read10Command::parse(&data).unwrap();
+    let data = [0x28, 0, 0, 0, 0x1E, 0x80, 0, 0, 0x08, 0];
+    let cmd: ReadXCommand = Read10Command::parse(&data).unwrap().into();
     assert_eq!(cmd.lba, 0x1E80);
+    assert_eq!(cmd.transfer_length, 8);
+}
+#[test]
+fn test_read16_parse() {
+    let data = [0x88, 0, 0, 0, 0, 0x01, 0, 0, 0x1E, 0x80, 0, 0, 0, 0x08, 0, 0];
+    let cmd: ReadXCommand = Read16Command::parse(&data).unwrap().into();
+    assert_eq!(cmd.lba, 0x0100001E80);
+    assert_eq!(cmd.transfer_length, 8);
 }
\ No newline at end of file
@@ -2,6 +2,7 @@ use packing::Packed;
 use crate::scsi::{
     packing::ParsePackedStruct,
     commands::Control,
+    Error,
 };
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
@@ -16,4 +17,37 @@ pub struct ReadCapacity10Command {
     #[pkd(7, 0, 9, 9)]
     pub control: Control,
 }
-impl ParsePackedStruct for ReadCapacity10Command {}
\ No newline at end of file
+impl ParsePackedStruct for ReadCapacity10Command {}
+
+/// The service action of SERVICE ACTION IN(16) for READ CAPACITY(16)
+pub const SERVICE_ACTION_READ_CAPACITY_16: u8 = 0x10;
+
+/// SERVICE ACTION IN(16) with the READ CAPACITY(16) service action. The only service action
+/// of that op code that's supported
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadCapacity16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    /// Must be `SERVICE_ACTION_READ_CAPACITY_16`
+    #[pkd(4, 0, 1, 1)]
+    pub service_action: u8,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 10, 13)]
+    pub allocation_length: u32,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for ReadCapacity16Command {
+    fn verify(&mut self) -> Result<(), Error> {
+        if self.service_action != SERVICE_ACTION_READ_CAPACITY_16 {
+            Err(Error::InvalidFieldInCdb(1))?;
+        }
+        Ok(())
+    }
+}
\ No newline at end of file
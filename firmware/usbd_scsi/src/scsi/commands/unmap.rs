@@ -0,0 +1,71 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct UnmapCommand {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(0, 0, 1, 1)]
+    pub anchor: bool,
+
+    #[pkd(4, 0, 6, 6)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 7, 8)]
+    pub parameter_list_length: u16,
+
+    #[pkd(7, 0, 9, 9)]
+    pub control: Control,
+}
+impl ParsePackedStruct for UnmapCommand {}
+
+/// Header of the UNMAP parameter list, followed by `block_descriptor_data_length` bytes of
+/// `UnmapBlockDescriptor`s (SBC-3 5.28.2)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct UnmapParameterListHeader {
+    /// Length in bytes of the rest of the parameter list after this field
+    #[pkd(7, 0, 0, 1)]
+    pub unmap_data_length: u16,
+
+    #[pkd(7, 0, 2, 3)]
+    pub block_descriptor_data_length: u16,
+
+    #[pkd(7, 0, 4, 7)]
+    _reserved: u32,
+}
+
+/// A range of blocks to unmap
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct UnmapBlockDescriptor {
+    #[pkd(7, 0, 0, 7)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 8, 11)]
+    pub number_of_blocks: u32,
+
+    #[pkd(7, 0, 12, 15)]
+    _reserved: u32,
+}
+
+#[test]
+fn test_unmap_parameter_list() {
+    use packing::PackedSize;
+
+    assert_eq!(UnmapParameterListHeader::BYTES, 8);
+    assert_eq!(UnmapBlockDescriptor::BYTES, 16);
+
+    let bytes = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34,
+        0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let descriptor = UnmapBlockDescriptor::unpack(&bytes).unwrap();
+    assert_eq!(descriptor.lba, 0x1234);
+    assert_eq!(descriptor.number_of_blocks, 8);
+}
\ No newline at end of file
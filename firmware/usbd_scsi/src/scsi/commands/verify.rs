@@ -16,7 +16,7 @@ pub struct Verify10Command {
     #[pkd(4, 4, 1, 1)]
     pub dpo: bool,
     
-    #[pkd(1, 1, 1, 1)]
+    #[pkd(2, 1, 1, 1)]
     pub byte_check: u8,
     
     #[pkd(7, 0, 2, 5)]
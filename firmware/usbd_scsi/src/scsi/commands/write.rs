@@ -6,7 +6,7 @@ use crate::scsi::{
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct WriteXCommand {
-    pub lba: u32,
+    pub lba: u64,
     pub transfer_length: u32,
 }
 
@@ -30,7 +30,8 @@ impl From<Write6Command> for WriteXCommand {
     fn from(w: Write6Command) -> Self {
         Self {
             lba: w.lba.into(),
-            transfer_length: w.transfer_length.into(),
+            // WRITE(6) can't ask for 0 blocks, 0 means 256 (SBC-3 5.39)
+            transfer_length: if w.transfer_length == 0 { 256 } else { w.transfer_length.into() },
         }
     }
 }
@@ -113,4 +114,41 @@ impl From<Write12Command> for WriteXCommand {
             transfer_length: w.transfer_length.into(),
         }
     }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct Write16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub wr_protect: u8,
+
+    #[pkd(4, 4, 1, 1)]
+    pub dpo: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub fua: bool,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 10, 13)]
+    pub transfer_length: u32,
+
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for Write16Command {}
+impl From<Write16Command> for WriteXCommand {
+    fn from(w: Write16Command) -> Self {
+        Self {
+            lba: w.lba,
+            transfer_length: w.transfer_length,
+        }
+    }
 }
\ No newline at end of file
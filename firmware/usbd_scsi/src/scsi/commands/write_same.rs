@@ -0,0 +1,40 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct WriteSame16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub write_protect: u8,
+
+    /// Unmap the blocks rather than writing the block sent by the host to them
+    #[pkd(3, 3, 1, 1)]
+    pub unmap: bool,
+
+    #[pkd(2, 2, 1, 1)]
+    pub anchor: bool,
+
+    /// No data out buffer, the host doesn't send a block (SBC-4)
+    #[pkd(0, 0, 1, 1)]
+    pub no_data_out_buffer: bool,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    /// 0 means every block from `lba` to the end of the medium
+    #[pkd(7, 0, 10, 13)]
+    pub number_of_blocks: u32,
+
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for WriteSame16Command {}
\ No newline at end of file
@@ -16,6 +16,46 @@ pub enum AdditionalSenseCode {
     EraseFailure,
     /// ASC 0x21, ASCQ: 0x0 - LOGICAL BLOCK ADDRESS OUT OF RANGE
     LogicalBlockAddressOutOfRange,
+    /// ASC 0x25, ASCQ: 0x0 - LOGICAL UNIT NOT SUPPORTED
+    LogicalUnitNotSupported,
+    /// ASC 0x1A, ASCQ: 0x0 - PARAMETER LIST LENGTH ERROR
+    ParameterListLengthError,
+    /// ASC 0x26, ASCQ: 0x0 - INVALID FIELD IN PARAMETER LIST
+    InvalidFieldInParameterList,
+    /// ASC 0x39, ASCQ: 0x0 - SAVING PARAMETERS NOT SUPPORTED
+    SavingParametersNotSupported,
+    /// ASC 0x27, ASCQ: 0x0 - WRITE PROTECTED
+    WriteProtected,
+    /// ASC 0x28, ASCQ: 0x0 - NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    NotReadyToReadyChange,
+    /// ASC 0x3A, ASCQ: 0x0 - MEDIUM NOT PRESENT
+    MediumNotPresent,
+    /// ASC 0x53, ASCQ: 0x2 - MEDIUM REMOVAL PREVENTED
+    MediumRemovalPrevented,
+    /// ASC 0x1D, ASCQ: 0x0 - MISCOMPARE DURING VERIFY OPERATION
+    MiscompareDuringVerifyOperation,
+    /// ASC 0x4, ASCQ: 0x4 - LOGICAL UNIT NOT READY, FORMAT IN PROGRESS
+    LogicalUnitNotReadyFormatInProgress,
+    /// ASC 0x31, ASCQ: 0x1 - FORMAT COMMAND FAILED
+    FormatCommandFailed,
+    /// ASC 0x3E, ASCQ: 0x3 - LOGICAL UNIT FAILED SELF-TEST
+    LogicalUnitFailedSelfTest,
+    /// ASC 0x4, ASCQ: 0x0 - LOGICAL UNIT NOT READY, CAUSE NOT REPORTABLE
+    LogicalUnitNotReadyCauseNotReportable,
+    /// ASC 0x4, ASCQ: 0x1 - LOGICAL UNIT IS IN PROCESS OF BECOMING READY
+    LogicalUnitIsInProcessOfBecomingReady,
+    /// ASC 0x4, ASCQ: 0x2 - LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED
+    LogicalUnitNotReadyInitializingCommandRequired,
+    /// ASC 0x4, ASCQ: 0x7 - LOGICAL UNIT NOT READY, OPERATION IN PROGRESS
+    LogicalUnitNotReadyOperationInProgress,
+    /// ASC 0x11, ASCQ: 0x0 - UNRECOVERED READ ERROR
+    UnrecoveredReadError,
+    /// ASC 0x18, ASCQ: 0x0 - RECOVERED DATA WITH ERROR CORRECTION APPLIED
+    RecoveredDataWithErrorCorrectionApplied,
+    /// ASC 0x3E, ASCQ: 0x2 - TIMEOUT ON LOGICAL UNIT
+    TimeoutOnLogicalUnit,
+    /// ASC 0x44, ASCQ: 0x0 - INTERNAL TARGET FAILURE
+    InternalTargetFailure,
 }
 
 impl AdditionalSenseCode {
@@ -29,6 +69,26 @@ impl AdditionalSenseCode {
             AdditionalSenseCode::WriteError => 12,
             AdditionalSenseCode::EraseFailure => 81,
             AdditionalSenseCode::LogicalBlockAddressOutOfRange => 33,
+            AdditionalSenseCode::LogicalUnitNotSupported => 37,
+            AdditionalSenseCode::ParameterListLengthError => 26,
+            AdditionalSenseCode::InvalidFieldInParameterList => 38,
+            AdditionalSenseCode::SavingParametersNotSupported => 57,
+            AdditionalSenseCode::WriteProtected => 39,
+            AdditionalSenseCode::NotReadyToReadyChange => 40,
+            AdditionalSenseCode::MediumNotPresent => 58,
+            AdditionalSenseCode::MediumRemovalPrevented => 83,
+            AdditionalSenseCode::MiscompareDuringVerifyOperation => 29,
+            AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress => 4,
+            AdditionalSenseCode::FormatCommandFailed => 49,
+            AdditionalSenseCode::LogicalUnitFailedSelfTest => 62,
+            AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable => 4,
+            AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady => 4,
+            AdditionalSenseCode::LogicalUnitNotReadyInitializingCommandRequired => 4,
+            AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress => 4,
+            AdditionalSenseCode::UnrecoveredReadError => 17,
+            AdditionalSenseCode::RecoveredDataWithErrorCorrectionApplied => 24,
+            AdditionalSenseCode::TimeoutOnLogicalUnit => 62,
+            AdditionalSenseCode::InternalTargetFailure => 68,
         }
     }
     /// Returns the ASCQ code for this variant
@@ -41,6 +101,26 @@ impl AdditionalSenseCode {
             AdditionalSenseCode::WriteError => 0,
             AdditionalSenseCode::EraseFailure => 0,
             AdditionalSenseCode::LogicalBlockAddressOutOfRange => 0,
+            AdditionalSenseCode::LogicalUnitNotSupported => 0,
+            AdditionalSenseCode::ParameterListLengthError => 0,
+            AdditionalSenseCode::InvalidFieldInParameterList => 0,
+            AdditionalSenseCode::SavingParametersNotSupported => 0,
+            AdditionalSenseCode::WriteProtected => 0,
+            AdditionalSenseCode::NotReadyToReadyChange => 0,
+            AdditionalSenseCode::MediumNotPresent => 0,
+            AdditionalSenseCode::MediumRemovalPrevented => 2,
+            AdditionalSenseCode::MiscompareDuringVerifyOperation => 0,
+            AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress => 4,
+            AdditionalSenseCode::FormatCommandFailed => 1,
+            AdditionalSenseCode::LogicalUnitFailedSelfTest => 3,
+            AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable => 0,
+            AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady => 1,
+            AdditionalSenseCode::LogicalUnitNotReadyInitializingCommandRequired => 2,
+            AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress => 7,
+            AdditionalSenseCode::UnrecoveredReadError => 0,
+            AdditionalSenseCode::RecoveredDataWithErrorCorrectionApplied => 0,
+            AdditionalSenseCode::TimeoutOnLogicalUnit => 2,
+            AdditionalSenseCode::InternalTargetFailure => 0,
         }
     }
     /// Returns the ASCQ code for this variant
@@ -53,6 +133,26 @@ impl AdditionalSenseCode {
             (12, 0) => Some(AdditionalSenseCode::WriteError),
             (81, 0) => Some(AdditionalSenseCode::EraseFailure),
             (33, 0) => Some(AdditionalSenseCode::LogicalBlockAddressOutOfRange),
+            (37, 0) => Some(AdditionalSenseCode::LogicalUnitNotSupported),
+            (26, 0) => Some(AdditionalSenseCode::ParameterListLengthError),
+            (38, 0) => Some(AdditionalSenseCode::InvalidFieldInParameterList),
+            (57, 0) => Some(AdditionalSenseCode::SavingParametersNotSupported),
+            (39, 0) => Some(AdditionalSenseCode::WriteProtected),
+            (40, 0) => Some(AdditionalSenseCode::NotReadyToReadyChange),
+            (58, 0) => Some(AdditionalSenseCode::MediumNotPresent),
+            (83, 2) => Some(AdditionalSenseCode::MediumRemovalPrevented),
+            (29, 0) => Some(AdditionalSenseCode::MiscompareDuringVerifyOperation),
+            (4, 4) => Some(AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress),
+            (49, 1) => Some(AdditionalSenseCode::FormatCommandFailed),
+            (62, 3) => Some(AdditionalSenseCode::LogicalUnitFailedSelfTest),
+            (4, 0) => Some(AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable),
+            (4, 1) => Some(AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady),
+            (4, 2) => Some(AdditionalSenseCode::LogicalUnitNotReadyInitializingCommandRequired),
+            (4, 7) => Some(AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress),
+            (17, 0) => Some(AdditionalSenseCode::UnrecoveredReadError),
+            (24, 0) => Some(AdditionalSenseCode::RecoveredDataWithErrorCorrectionApplied),
+            (62, 2) => Some(AdditionalSenseCode::TimeoutOnLogicalUnit),
+            (68, 0) => Some(AdditionalSenseCode::InternalTargetFailure),
             _ => None,
         }
     }
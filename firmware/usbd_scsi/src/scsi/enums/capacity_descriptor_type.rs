@@ -0,0 +1,18 @@
+use packing::Packed;
+
+/// What the current/maximum capacity descriptor of READ FORMAT CAPACITIES describes (UFI 4.10.2)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum CapacityDescriptorType {
+    /// The medium is unformatted, the capacity is the maximum that can be formatted
+    UnformattedMedia = 0b01,
+    /// The medium is formatted, the capacity is the current one
+    FormattedMedia = 0b10,
+    /// There's no medium, the capacity is the maximum the device supports
+    NoMediaPresent = 0b11,
+}
+
+impl Default for CapacityDescriptorType {
+    fn default() -> Self {
+        CapacityDescriptorType::FormattedMedia
+    }
+}
\ No newline at end of file
@@ -32,4 +32,10 @@ mod spc_version;
 pub use spc_version::*;
 
 mod response_data_format;
-pub use response_data_format::*;
\ No newline at end of file
+pub use response_data_format::*;
+
+mod vital_product_data_page;
+pub use vital_product_data_page::*;
+
+mod capacity_descriptor_type;
+pub use capacity_descriptor_type::*;
\ No newline at end of file
@@ -24,8 +24,14 @@ pub enum OpCode {
     Write10 = 0x2A,
     Verify10 = 0x2F,
     SynchronizeCache10 = 0x35,
+    Unmap = 0x42,
     ReadTocPmaAtip = 0x43,
     ModeSelect10 = 0x55,
     Read12 = 0xA8,
     Write12 = 0xAA,
+    Read16 = 0x88,
+    Write16 = 0x8A,
+    WriteSame16 = 0x93,
+    /// READ CAPACITY(16) is a service action of this
+    ServiceActionIn16 = 0x9E,
 }
\ No newline at end of file
@@ -2,11 +2,15 @@ use packing::Packed;
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 pub enum ResponseCode {
-    FixedSenseData = 0x70,
-    DescriptorSenseData = 0x72,    
+    Fixed = 0x70,
+    /// Fixed format sense data for an error from an earlier command
+    DeferredFixed = 0x71,
+    Descriptor = 0x72,
+    /// Descriptor format sense data for an error from an earlier command
+    DeferredDescriptor = 0x73,
 }
 impl Default for ResponseCode {
     fn default() -> Self {
-        ResponseCode::FixedSenseData
+        ResponseCode::Fixed
     }
 }
\ No newline at end of file
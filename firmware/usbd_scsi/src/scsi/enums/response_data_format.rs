@@ -4,6 +4,8 @@ use packing::Packed;
 pub enum ResponseDataFormat {
     /// A RESPONSE DATA FORMAT field set to 2h indicates that the standard INQUIRY data
     Standard = 0x2,
+    /// UFI devices use 1h. Section 4.2 [UFI Command Spec](https://www.usb.org/document-library/mass-storage-ufi-command-specification-10)
+    Ufi = 0x1,
 }
 
 impl Default for ResponseDataFormat {
@@ -0,0 +1,24 @@
+use packing::Packed;
+
+/// The vital product data pages returned by INQUIRY when EVPD is set (SPC-4 7.8, SBC-3 6.5)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum VitalProductDataPage {
+    /// List of the supported VPD pages
+    SupportedVpdPages = 0x00,
+    /// ASCII serial number of the logical unit
+    UnitSerialNumber = 0x80,
+    /// Designators that identify the logical unit
+    DeviceIdentification = 0x83,
+    /// Limits on transfer lengths (SBC-3)
+    BlockLimits = 0xB0,
+    /// Rotation rate and form factor of the medium (SBC-3)
+    BlockDeviceCharacteristics = 0xB1,
+    /// Which logical block provisioning commands are supported (SBC-3)
+    LogicalBlockProvisioning = 0xB2,
+}
+
+impl Default for VitalProductDataPage {
+    fn default() -> Self {
+        VitalProductDataPage::SupportedVpdPages
+    }
+}
\ No newline at end of file
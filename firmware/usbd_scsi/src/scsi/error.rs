@@ -1,16 +1,60 @@
 use packing::Error as PackingError;
-use usbd_bulk_only_transport::Error as BulkOnlyTransportError;
+use usbd_mass_storage::TransportError;
 use usb_device::UsbError;
 use crate::block_device::BlockDeviceError;
+use crate::scsi::enums::{
+    SenseKey,
+    AdditionalSenseCode,
+};
 
 #[derive(Debug)]
 pub enum Error {
     UnhandledOpCode,
     /// The identified opcode requires more data than was sent
     InsufficientDataForCommand,
+    /// The command was addressed to a LUN that doesn't exist
+    LogicalUnitNotSupported,
+    /// A field in the command block has a value that isn't supported. Holds the byte it's in
+    InvalidFieldInCdb(u16),
+    /// A field in the parameter list sent with the command has a value that isn't supported
+    InvalidFieldInParameterList,
+    /// The parameter list sent with the command is the wrong length
+    ParameterListLengthError,
+    /// The command asked for parameters to be saved or the saved values, which isn't supported
+    SavingParametersNotSupported,
+    /// The command would change the contents of a write protected block device
+    WriteProtected,
+    /// The command needs the medium but it has been ejected or removed
+    MediumNotPresent,
+    /// The command needs the medium but the host stopped the logical unit and hasn't started it
+    UnitStopped,
+    /// The medium has been inserted or swapped since the last command, reported once
+    MediumMayHaveChanged,
+    /// The host asked to eject the medium after preventing its removal
+    MediumRemovalPrevented,
+    /// Data sent by the host for VERIFY didn't match the medium at this LBA
+    Miscompare(u64),
+    /// A format started with IMMED is still running
+    FormatInProgress,
+    /// The block device's self-test for SEND DIAGNOSTIC failed
+    SelfTestFailed,
+    /// The command addresses blocks past the end of the medium
+    LogicalBlockAddressOutOfRange,
+    /// Reported to the next command after something carried on past its status and failed
+    Deferred(DeferredError),
     PackingError(PackingError),
     BlockDeviceError(BlockDeviceError),
-    BulkOnlyTransportError(BulkOnlyTransportError),
+    TransportError(TransportError),
+}
+
+/// An error from work that finished after its command's status was sent (SPC-4 4.5.5), for
+/// example a background format or writing back a cache
+#[derive(Debug, Clone, Copy)]
+pub struct DeferredError {
+    pub sense_key: SenseKey,
+    pub additional_sense_code: AdditionalSenseCode,
+    /// The LBA that failed if there is one
+    pub information: Option<u64>,
 }
 
 impl From<PackingError> for Error {
@@ -25,14 +69,14 @@ impl From<BlockDeviceError> for Error {
     }
 }
 
-impl From<BulkOnlyTransportError> for Error {
-    fn from(e: BulkOnlyTransportError) -> Error {
-        Error::BulkOnlyTransportError(e)
+impl From<TransportError> for Error {
+    fn from(e: TransportError) -> Error {
+        Error::TransportError(e)
     }
 }
 
 impl From<UsbError> for Error {
     fn from(e: UsbError) -> Error {
-        Error::BulkOnlyTransportError(e.into())
+        Error::TransportError(e.into())
     }
 }
\ No newline at end of file
@@ -0,0 +1,86 @@
+use crate::scsi::{
+    enums::{
+        SenseKey,
+        AdditionalSenseCode,
+    },
+    responses::RequestSenseResponse,
+    error::DeferredError,
+    ModePages,
+};
+
+/// Progress of FORMAT UNIT
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub(crate) enum FormatState {
+    Idle,
+    /// FORMAT UNIT is waiting for the block device to finish
+    Running,
+    /// IMMED was set so the status has already been sent, the format finishes in the background
+    Background,
+}
+
+impl Default for FormatState {
+    fn default() -> Self {
+        FormatState::Idle
+    }
+}
+
+/// State that [Scsi](struct.Scsi.html) keeps for each logical unit
+///
+/// The contents are private, it's only public so that [LogicalUnits](trait.LogicalUnits.html)
+/// implementations can provide storage for it
+#[derive(Clone, Copy, Default)]
+pub struct LogicalUnitState {
+    /// Returned by the next request sense
+    pub(crate) request_sense_response: RequestSenseResponse,
+    /// The INFORMATION in `request_sense_response` in full, fixed format only has room for 32 bits
+    pub(crate) sense_information: Option<u64>,
+    /// Reported to the next command that isn't inquiry
+    pub(crate) deferred_error: Option<DeferredError>,
+    /// Current values of the mode pages, changed by MODE SELECT
+    pub(crate) mode_pages: ModePages,
+    /// The host ejected the medium with START STOP UNIT
+    pub(crate) ejected: bool,
+    /// The host stopped the logical unit with START STOP UNIT and hasn't started it again
+    pub(crate) stopped: bool,
+    /// The firmware removed the medium with `Scsi::remove_medium`
+    pub(crate) removed: bool,
+    /// The host has prevented the medium being ejected with PREVENT ALLOW MEDIUM REMOVAL
+    pub(crate) prevent_removal: bool,
+    /// The medium has been inserted or swapped and the host hasn't been told yet
+    pub(crate) medium_changed: bool,
+    pub(crate) format: FormatState,
+}
+
+impl LogicalUnitState {
+    /// Set the sense data that will be returned by the next request sense
+    pub(crate) fn set_sense(&mut self, sense_key: SenseKey, additional_sense_code: AdditionalSenseCode) {
+        self.reset_sense();
+        self.request_sense_response.sense_key = sense_key;
+        self.request_sense_response.additional_sense_code = additional_sense_code;
+    }
+
+    /// The medium hasn't been ejected by the host or removed by the firmware
+    pub(crate) fn medium_present(&self) -> bool {
+        !(self.ejected || self.removed)
+    }
+
+    /// Replace the sense data returned by the next request sense
+    pub(crate) fn set_sense_data(&mut self, sense: RequestSenseResponse, information: Option<u64>) {
+        self.request_sense_response = sense;
+        self.sense_information = information;
+    }
+
+    /// Queue an error for the next command. Only one is kept, later ones are dropped until it has
+    /// been reported
+    pub(crate) fn defer_error(&mut self, deferred_error: DeferredError) {
+        if self.deferred_error.is_none() {
+            self.deferred_error = Some(deferred_error);
+        }
+    }
+
+    /// Reset the sense data to good status
+    pub(crate) fn reset_sense(&mut self) {
+        self.request_sense_response.reset_status();
+        self.sense_information = None;
+    }
+}
\ No newline at end of file
@@ -0,0 +1,410 @@
+//! A pretend USB peripheral and block device for driving [Scsi](struct.Scsi.html) over the bulk only
+//! transport, or the bulk only fallback of the UAS transport, the way a host would
+use core::cell::{
+    Cell,
+    RefCell,
+};
+use packing::{
+    Packed,
+    PackedSize,
+};
+use usb_device::{
+    bus::PollResult,
+    class_prelude::*,
+    prelude::*,
+    Result as UsbResult,
+    UsbDirection,
+};
+use usbd_bulk_only_transport::{
+    BulkOnlyTransport,
+    CommandBlockWrapper,
+    Direction,
+};
+use usbd_mass_storage::{
+    InterfaceSubclass,
+    Transport,
+};
+use usbd_uas_transport::UasTransport;
+
+use crate::{
+    BlockDevice,
+    BlockDeviceError,
+    Scsi,
+};
+
+pub const MAX_PACKET_SIZE: usize = 64;
+pub const BLOCK_BYTES: usize = 512;
+pub const BLOCKS: usize = 4;
+
+/// Enough for the longest data phase in the tests along with its CSW
+const IN_BYTES: usize = 2 * BLOCK_BYTES;
+
+const CLEAR_FEATURE: u8 = 0x01;
+const GET_INTERFACE: u8 = 0x0A;
+const SET_INTERFACE: u8 = 0x0B;
+const ENDPOINT_HALT: u16 = 0x00;
+const CSW_SIGNATURE: [u8; 4] = *b"USBS";
+
+pub const COMMAND_PASSED: u8 = 0x00;
+pub const COMMAND_FAILED: u8 = 0x01;
+pub const PHASE_ERROR: u8 = 0x02;
+
+/// Stands in for the USB peripheral. Control requests and bulk out packets are queued by the host
+/// and everything the device sends on the bulk in endpoint is collected until the host takes it.
+/// Only the first pair of bulk endpoints is used by the host, the UAS command and status endpoints
+/// are allocated after them
+pub struct MockBus {
+    next_ep: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    setup: RefCell<Option<[u8; 8]>>,
+    out_packet: RefCell<Option<([u8; MAX_PACKET_SIZE], usize)>>,
+    in_data: RefCell<([u8; IN_BYTES], usize)>,
+    control_in: RefCell<([u8; 8], usize)>,
+    stalled_in: Cell<u16>,
+    stalled_out: Cell<u16>,
+}
+
+// Each test has its own bus and only uses it from the one thread
+unsafe impl Sync for MockBus {}
+
+impl MockBus {
+    pub fn new() -> Self {
+        MockBus {
+            next_ep: 1,
+            bulk_in: 0,
+            bulk_out: 0,
+            setup: RefCell::new(None),
+            out_packet: RefCell::new(None),
+            in_data: RefCell::new(([0; IN_BYTES], 0)),
+            control_in: RefCell::new(([0; 8], 0)),
+            stalled_in: Cell::new(0),
+            stalled_out: Cell::new(0),
+        }
+    }
+
+    fn stalled(&self, direction: UsbDirection) -> &Cell<u16> {
+        match direction {
+            UsbDirection::In => &self.stalled_in,
+            UsbDirection::Out => &self.stalled_out,
+        }
+    }
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> UsbResult<EndpointAddress> {
+        let addr = ep_addr.unwrap_or_else(|| {
+            self.next_ep += 1;
+            EndpointAddress::from_parts(self.next_ep as usize - 1, ep_dir)
+        });
+
+        if let EndpointType::Bulk = ep_type {
+            let bulk = match ep_dir {
+                UsbDirection::In => &mut self.bulk_in,
+                UsbDirection::Out => &mut self.bulk_out,
+            };
+            if *bulk == 0 {
+                *bulk = addr.into();
+            }
+        }
+        Ok(addr)
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> UsbResult<usize> {
+        // Keep the last control data stage, the status stage is a zero length packet
+        if ep_addr.index() == 0 {
+            if !buf.is_empty() {
+                let mut control_in = self.control_in.borrow_mut();
+                let (data, len) = &mut *control_in;
+                data[..buf.len()].copy_from_slice(buf);
+                *len = buf.len();
+            }
+            return Ok(buf.len());
+        }
+        assert!(!self.is_stalled(ep_addr), "Write to a stalled endpoint");
+
+        let mut in_data = self.in_data.borrow_mut();
+        let (data, len) = &mut *in_data;
+        data[*len..*len + buf.len()].copy_from_slice(buf);
+        *len += buf.len();
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> UsbResult<usize> {
+        if ep_addr.index() == 0 {
+            let setup = self.setup.borrow_mut().take().ok_or(UsbError::WouldBlock)?;
+            buf[..setup.len()].copy_from_slice(&setup);
+            return Ok(setup.len());
+        }
+
+        let (packet, len) = self.out_packet.borrow_mut().take().ok_or(UsbError::WouldBlock)?;
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let bits = self.stalled(ep_addr.direction());
+        let bit = 1 << ep_addr.index();
+        bits.set(if stalled { bits.get() | bit } else { bits.get() & !bit });
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.stalled(ep_addr.direction()).get() & 1 << ep_addr.index() != 0
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        if self.setup.borrow().is_some() {
+            PollResult::Data { ep_out: 0, ep_in_complete: 0, ep_setup: 1 }
+        } else {
+            PollResult::None
+        }
+    }
+}
+
+/// A RAM disk of `BLOCKS` blocks that can be given a write back error to report. Counts the
+/// transfers that were aborted
+pub struct MockBlockDevice {
+    pub blocks: [[u8; BLOCK_BYTES]; BLOCKS],
+    pub write_back_error: Option<(u64, BlockDeviceError)>,
+    pub aborted_transfers: usize,
+}
+
+impl MockBlockDevice {
+    pub fn new() -> Self {
+        MockBlockDevice {
+            blocks: [[0; BLOCK_BYTES]; BLOCKS],
+            write_back_error: None,
+            aborted_transfers: 0,
+        }
+    }
+}
+
+impl BlockDevice for MockBlockDevice {
+    const BLOCK_BYTES: usize = BLOCK_BYTES;
+
+    fn read_block(&mut self, lba: u64, block: &mut [u8]) -> nb::Result<(), BlockDeviceError> {
+        block.copy_from_slice(&self.blocks[lba as usize]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> nb::Result<(), BlockDeviceError> {
+        self.blocks[lba as usize].copy_from_slice(block);
+        Ok(())
+    }
+
+    fn max_lba(&self) -> u64 {
+        BLOCKS as u64 - 1
+    }
+
+    fn compare_block(&mut self, lba: u64, block: &[u8]) -> nb::Result<bool, BlockDeviceError> {
+        Ok(self.blocks[lba as usize][..] == *block)
+    }
+
+    fn take_write_back_error(&mut self) -> Option<(u64, BlockDeviceError)> {
+        self.write_back_error.take()
+    }
+
+    fn abort_transfer(&mut self) {
+        self.aborted_transfers += 1;
+    }
+}
+
+/// The command status wrapper the device sent at the end of a command
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Csw {
+    pub residue: u32,
+    pub status: u8,
+}
+
+/// What the host received on the bulk in endpoint
+pub struct Response {
+    data: [u8; IN_BYTES],
+    len: usize,
+    pub csw: Option<Csw>,
+}
+
+impl Response {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// Plays the part of the host, sending bulk only transport commands to a `Scsi` over a `MockBus`
+pub struct Host<'a, T: Transport<MockBus> = BulkOnlyTransport<'a, MockBus>> {
+    usb_dev: UsbDevice<'a, MockBus>,
+    scsi: Scsi<'a, MockBus, MockBlockDevice, T>,
+    tag: u32,
+}
+
+impl<'a> Host<'a> {
+    pub fn new(alloc: &'a UsbBusAllocator<MockBus>, block_device: MockBlockDevice) -> Self {
+        // The endpoints have to be allocated before the device is built
+        let scsi = Scsi::new(alloc, MAX_PACKET_SIZE as u16, block_device, "Vendor", "Product", "0.1");
+        Self::with_scsi(alloc, scsi)
+    }
+}
+
+impl<'a> Host<'a, UasTransport<'a, MockBus>> {
+    /// A host talking to the bulk only fallback (alternate setting 0) of a UAS transport
+    pub fn with_uas(alloc: &'a UsbBusAllocator<MockBus>, block_device: MockBlockDevice) -> Self {
+        let transport = UasTransport::new(alloc, MAX_PACKET_SIZE as u16, InterfaceSubclass::ScsiTransparentCommandSet, 0);
+        let scsi = Scsi::with_transport(transport, block_device, "Vendor", "Product", "0.1");
+        Self::with_scsi(alloc, scsi)
+    }
+}
+
+impl<'a, T: Transport<MockBus>> Host<'a, T> {
+    fn with_scsi(alloc: &'a UsbBusAllocator<MockBus>, scsi: Scsi<'a, MockBus, MockBlockDevice, T>) -> Self {
+        let usb_dev = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+        Host {
+            usb_dev,
+            scsi,
+            tag: 0,
+        }
+    }
+
+    pub fn block_device(&mut self) -> &mut MockBlockDevice {
+        self.scsi.block_device_mut()
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        self.scsi.transport_mut()
+    }
+
+    fn bus(&self) -> &MockBus {
+        self.usb_dev.bus()
+    }
+
+    fn poll(&mut self) {
+        for _ in 0..8 {
+            self.scsi.poll();
+        }
+    }
+
+    pub fn is_in_stalled(&self) -> bool {
+        self.bus().is_stalled(self.bus().bulk_in.into())
+    }
+
+    pub fn is_out_stalled(&self) -> bool {
+        self.bus().is_stalled(self.bus().bulk_out.into())
+    }
+
+    /// Sends a CBW for `command_block` followed by as much of `data_out` as the device accepts
+    /// before stalling, then takes whatever the device sent back
+    pub fn command(&mut self, direction: Direction, length: u32, command_block: &[u8], data_out: &[u8]) -> Response {
+        self.tag += 1;
+
+        let mut cbw = CommandBlockWrapper {
+            tag: self.tag,
+            data_transfer_length: length,
+            direction,
+            data_length: command_block.len() as u8,
+            ..Default::default()
+        };
+        cbw.data[..command_block.len()].copy_from_slice(command_block);
+
+        let mut packet = [0; CommandBlockWrapper::BYTES];
+        cbw.pack(&mut packet).unwrap();
+        self.send(&packet);
+
+        for packet in data_out.chunks(MAX_PACKET_SIZE) {
+            if self.is_out_stalled() {
+                break;
+            }
+            self.send(packet);
+        }
+
+        self.receive()
+    }
+
+    /// Clears a halt on the bulk in endpoint and takes the CSW that was waiting for it
+    pub fn clear_in_halt(&mut self) -> Response {
+        let index = self.bus().bulk_in;
+        self.clear_halt(index);
+        self.receive()
+    }
+
+    /// Clears a halt on the bulk out endpoint
+    pub fn clear_out_halt(&mut self) {
+        let index = self.bus().bulk_out;
+        self.clear_halt(index);
+    }
+
+    /// Selects alternate setting `alt_setting` of interface 0
+    pub fn set_interface(&mut self, alt_setting: u8) {
+        self.control([0x01, SET_INTERFACE, alt_setting, 0, 0, 0, 0, 0]);
+    }
+
+    /// Asks for the alternate setting of interface 0
+    pub fn get_interface(&mut self) -> u8 {
+        self.control([0x81, GET_INTERFACE, 0, 0, 0, 0, 1, 0]);
+        let (data, len) = *self.bus().control_in.borrow();
+        assert_eq!(len, 1, "Device didn't answer GET_INTERFACE");
+        data[0]
+    }
+
+    fn clear_halt(&mut self, index: u8) {
+        let mut setup = [0x02, CLEAR_FEATURE, 0, 0, index, 0, 0, 0];
+        setup[2..4].copy_from_slice(&ENDPOINT_HALT.to_le_bytes());
+        self.control(setup);
+    }
+
+    fn control(&mut self, setup: [u8; 8]) {
+        *self.bus().control_in.borrow_mut() = ([0; 8], 0);
+        *self.bus().setup.borrow_mut() = Some(setup);
+        self.usb_dev.poll(&mut [&mut self.scsi]);
+    }
+
+    fn send(&mut self, packet: &[u8]) {
+        let mut out_packet = [0; MAX_PACKET_SIZE];
+        out_packet[..packet.len()].copy_from_slice(packet);
+        *self.bus().out_packet.borrow_mut() = Some((out_packet, packet.len()));
+
+        self.poll();
+        assert!(self.bus().out_packet.borrow().is_none(), "Device didn't read the packet");
+    }
+
+    fn receive(&mut self) -> Response {
+        self.poll();
+
+        let mut in_data = self.bus().in_data.borrow_mut();
+        let (data, len) = &mut *in_data;
+        let mut response = Response {
+            data: *data,
+            len: *len,
+            csw: None,
+        };
+        *len = 0;
+
+        // The CSW is the last thing sent, anything before it is the data phase
+        if let Some(csw_i) = response.len.checked_sub(13) {
+            let csw = &response.data[csw_i..response.len];
+            if csw[..4] == CSW_SIGNATURE && csw[4..8] == self.tag.to_le_bytes() {
+                response.csw = Some(Csw {
+                    residue: u32::from_le_bytes([csw[8], csw[9], csw[10], csw[11]]),
+                    status: csw[12],
+                });
+                response.len = csw_i;
+            }
+        }
+        response
+    }
+}
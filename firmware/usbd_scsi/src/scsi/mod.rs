@@ -4,7 +4,23 @@ mod enums;
 mod packing;
 
 mod error;
-use error::Error;
+use error::{
+    Error,
+    DeferredError,
+};
+
+mod mode_pages;
+use mode_pages::{
+    ModePages,
+    DeviceModePages,
+};
+
+mod logical_unit_state;
+pub use logical_unit_state::LogicalUnitState;
+use logical_unit_state::FormatState;
 
 mod scsi;
-pub use scsi::Scsi;
\ No newline at end of file
+pub use scsi::Scsi;
+
+#[cfg(test)]
+mod mock_device;
\ No newline at end of file
@@ -0,0 +1,170 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+use crate::block_device::BlockDevice;
+use crate::scsi::{
+    commands::{
+        PageCode,
+        ReadWriteErrorRecoveryModePage,
+        FlexibleDiskModePage,
+        CachingModePage,
+        InformationalExceptionsControlModePage,
+        ALL_PAGES,
+        ALL_SUBPAGES,
+        PARAMETERS_SAVEABLE,
+        SUBPAGE_FORMAT,
+    },
+    enums::PageControl,
+    Error,
+};
+
+/// Longest mode page, used to size scratch buffers
+const MAX_PAGE_BYTES: usize = FlexibleDiskModePage::BYTES;
+
+/// Where the page code and subpage code are in both MODE SENSE command blocks, for the field pointer
+const PAGE_CODE_BYTE: u16 = 2;
+const SUBPAGE_CODE_BYTE: u16 = 3;
+
+/// Mode page values that come from the block device rather than MODE SELECT
+pub(crate) struct DeviceModePages {
+    flexible_disk: FlexibleDiskModePage,
+    write_cache_enabled: bool,
+}
+
+impl DeviceModePages {
+    pub fn new<BD: BlockDevice>(block_device: &BD) -> Self {
+        let number_of_blocks = block_device.max_lba().saturating_add(1);
+        Self {
+            flexible_disk: FlexibleDiskModePage::new(number_of_blocks, BD::BLOCK_BYTES as u16),
+            write_cache_enabled: block_device.write_cache_enabled(),
+        }
+    }
+}
+
+/// Current values of the mode pages that can be changed by MODE SELECT
+///
+/// The flexible disk page isn't stored, it's made up from the block device each time and
+/// nothing in it is changeable. Neither is WCE in the caching page, the block device decides
+/// whether it caches writes
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ModePages {
+    read_write_error_recovery: ReadWriteErrorRecoveryModePage,
+    caching: CachingModePage,
+    informational_exceptions_control: InformationalExceptionsControlModePage,
+}
+
+impl ModePages {
+    /// The pages asked for by the page code and subpage code of a MODE SENSE
+    pub fn requested(page_code: u8, subpage_code: u8) -> Result<&'static [PageCode], Error> {
+        match (page_code, subpage_code) {
+            (ALL_PAGES, 0) | (ALL_PAGES, ALL_SUBPAGES) => Ok(&PageCode::ALL),
+            (_, 0) => PageCode::ALL.iter()
+                .position(|p| *p as u8 == page_code)
+                .map(|i| &PageCode::ALL[i..=i])
+                .ok_or(Error::InvalidFieldInCdb(PAGE_CODE_BYTE)),
+            _ => Err(Error::InvalidFieldInCdb(SUBPAGE_CODE_BYTE)),
+        }
+    }
+
+    /// The pages with a bit set in every field MODE SELECT can change
+    fn changeable() -> Self {
+        let mut caching = CachingModePage::default();
+        caching.read_cache_disable = true;
+
+        let informational_exceptions_control = InformationalExceptionsControlModePage {
+            disable_exception_control: true,
+            method_of_reporting_informational_exceptions: 0xF,
+            interval_timer: u32::MAX,
+            report_count: u32::MAX,
+            ..Default::default()
+        };
+
+        Self {
+            // Every field is 0 by default so nothing is changeable
+            read_write_error_recovery: Default::default(),
+            caching,
+            informational_exceptions_control,
+        }
+    }
+
+    /// Packs the `page_control` values of `page` into `buf`, which must be `page.page_bytes()` long
+    pub fn pack_page(
+        &self,
+        page: PageCode,
+        page_control: PageControl,
+        device: &DeviceModePages,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let pages = match page_control {
+            PageControl::CurrentValues => *self,
+            PageControl::ChangeableValues => Self::changeable(),
+            PageControl::DefaultValues => Self::default(),
+            PageControl::SavedValues => Err(Error::SavingParametersNotSupported)?,
+        };
+
+        match page {
+            PageCode::ReadWriteErrorRecovery => pages.read_write_error_recovery.pack(buf)?,
+            PageCode::FlexibleDisk => {
+                device.flexible_disk.pack(buf)?;
+                // Nothing in it can be changed, leave just the page code and length
+                if page_control == PageControl::ChangeableValues {
+                    buf[2..].iter_mut().for_each(|b| *b = 0);
+                }
+            },
+            PageCode::Caching => {
+                let mut caching = pages.caching;
+                if page_control != PageControl::ChangeableValues {
+                    caching.write_cache_enabled = device.write_cache_enabled;
+                }
+                caching.pack(buf)?
+            },
+            PageCode::InformationalExceptionsControl => pages.informational_exceptions_control.pack(buf)?,
+        }
+        Ok(())
+    }
+
+    /// Applies the page at the start of `bytes` from a MODE SELECT parameter list and returns how
+    /// many bytes it used
+    ///
+    /// Only changeable fields may differ from the current values, anything else is rejected
+    /// without changing anything
+    pub fn select_page(&mut self, bytes: &[u8], device: &DeviceModePages) -> Result<usize, Error> {
+        if bytes.len() < 2 {
+            Err(Error::ParameterListLengthError)?;
+        }
+        if bytes[0] & SUBPAGE_FORMAT != 0 {
+            Err(Error::InvalidFieldInParameterList)?;
+        }
+
+        let page = PageCode::from_primitive(bytes[0] & !(PARAMETERS_SAVEABLE | SUBPAGE_FORMAT))
+            .map_err(|_| Error::InvalidFieldInParameterList)?;
+        let len = page.page_bytes();
+        if bytes[1] as usize != len - 2 {
+            Err(Error::InvalidFieldInParameterList)?;
+        }
+        let new = bytes.get(..len).ok_or(Error::ParameterListLengthError)?;
+
+        let mut current = [0; MAX_PAGE_BYTES];
+        let mut changeable = [0; MAX_PAGE_BYTES];
+        self.pack_page(page, PageControl::CurrentValues, device, &mut current[..len])?;
+        self.pack_page(page, PageControl::ChangeableValues, device, &mut changeable[..len])?;
+
+        // The page code and length have already been checked
+        for i in 2..len {
+            if (new[i] ^ current[i]) & !changeable[i] != 0 {
+                Err(Error::InvalidFieldInParameterList)?;
+            }
+        }
+
+        match page {
+            PageCode::ReadWriteErrorRecovery => self.read_write_error_recovery = ReadWriteErrorRecoveryModePage::unpack(new)?,
+            // Nothing changeable so it must have matched exactly
+            PageCode::FlexibleDisk => {},
+            PageCode::Caching => self.caching = CachingModePage::unpack(new)?,
+            PageCode::InformationalExceptionsControl => self.informational_exceptions_control = InformationalExceptionsControlModePage::unpack(new)?,
+        }
+
+        Ok(len)
+    }
+}
\ No newline at end of file
@@ -17,6 +17,10 @@ use crate::scsi::{
 // ASCII space is used to pad shorter string identifiers as per SPC
 const ASCII_SPACE: u8 = 0x20;
 
+/// UFI devices only return the first 36 bytes of the inquiry data
+/// Section 4.2 [UFI Command Spec](https://www.usb.org/document-library/mass-storage-ufi-command-specification-10)
+pub const UFI_INQUIRY_RESPONSE_BYTES: usize = 36;
+
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
 pub struct InquiryResponse {
@@ -177,10 +181,29 @@ impl InquiryResponse {
         assert!(product_identification.as_ref().len() <= self.product_identification.len());
         set_ascii_str(&mut self.product_identification, product_identification);
     }
+    pub fn vendor_identification(&self) -> &[u8] {
+        &self.vendor_identification
+    }
+    pub fn product_identification(&self) -> &[u8] {
+        &self.product_identification
+    }
     pub fn set_product_revision_level<T: AsRef<[u8]>>(&mut self, product_revision_level: T) {
         assert!(product_revision_level.as_ref().len() <= self.product_revision_level.len());
         set_ascii_str(&mut self.product_revision_level, product_revision_level);
     }
+    pub fn set_peripheral_qualifier(&mut self, peripheral_qualifier: PeripheralQualifier) {
+        self.peripheral_qualifier = peripheral_qualifier;
+    }
+    pub fn set_peripheral_device_type(&mut self, peripheral_device_type: PeripheralDeviceType) {
+        self.peripheral_device_type = peripheral_device_type;
+    }
+    /// Changes the version fields to what the UFI command set expects. Only the first
+    /// `UFI_INQUIRY_RESPONSE_BYTES` should be sent
+    pub fn set_ufi_format(&mut self) {
+        self.version = SpcVersion::None;
+        self.response_data_format = ResponseDataFormat::Ufi;
+        self.additional_length = (UFI_INQUIRY_RESPONSE_BYTES - 5) as u8;
+    }
 }
 
 impl Default for InquiryResponse {
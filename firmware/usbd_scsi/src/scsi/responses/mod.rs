@@ -5,4 +5,13 @@ mod inquiry;
 pub use inquiry::*;
 
 mod request_sense;
-pub use request_sense::*;
\ No newline at end of file
+pub use request_sense::*;
+
+mod report_luns;
+pub use report_luns::*;
+
+mod vital_product_data;
+pub use vital_product_data::*;
+
+mod read_format_capacities;
+pub use read_format_capacities::*;
\ No newline at end of file
@@ -8,4 +8,60 @@ pub struct ReadCapacity10Response {
 
     #[pkd(7, 0, 4, 7)]
     pub block_size: u32,
+}
+
+/// Response to READ CAPACITY(16) (SBC-3 5.16.2)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadCapacity16Response {
+    #[pkd(7, 0, 0, 7)]
+    pub max_lba: u64,
+
+    #[pkd(7, 0, 8, 11)]
+    pub block_size: u32,
+
+    #[pkd(3, 1, 12, 12)]
+    pub protection_type: u8,
+
+    #[pkd(0, 0, 12, 12)]
+    pub protection_enable: bool,
+
+    #[pkd(7, 4, 13, 13)]
+    pub protection_information_exponent: u8,
+
+    /// There are 2^n logical blocks per physical block
+    #[pkd(3, 0, 13, 13)]
+    pub logical_blocks_per_physical_block_exponent: u8,
+
+    /// Logical block provisioning management is enabled, e.g. UNMAP is supported
+    #[pkd(7, 7, 14, 14)]
+    pub logical_block_provisioning_management_enabled: bool,
+
+    /// Unmapped blocks read back as zeros
+    #[pkd(6, 6, 14, 14)]
+    pub logical_block_provisioning_read_zeros: bool,
+
+    /// The first LBA that's aligned to the start of a physical block
+    #[pkd(5, 0, 14, 15)]
+    pub lowest_aligned_lba: u16,
+
+    #[pkd(7, 0, 16, 31)]
+    _reserved: [u8; 16],
+}
+
+impl ReadCapacity16Response {
+    pub fn new(max_lba: u64, block_size: u32, logical_blocks_per_physical_block_exponent: u8) -> Self {
+        Self {
+            max_lba,
+            block_size,
+            protection_type: 0,
+            protection_enable: false,
+            protection_information_exponent: 0,
+            logical_blocks_per_physical_block_exponent,
+            logical_block_provisioning_management_enabled: false,
+            logical_block_provisioning_read_zeros: false,
+            lowest_aligned_lba: 0,
+            _reserved: [0; 16],
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,58 @@
+use packing::Packed;
+use crate::scsi::enums::CapacityDescriptorType;
+
+/// Header of the READ FORMAT CAPACITIES response, followed by a `CurrentMaximumCapacityDescriptor`
+/// (UFI 4.10.2)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct CapacityListHeader {
+    /// Length in bytes of the capacity descriptors that follow this header. 8 bytes per descriptor
+    #[pkd(7, 0, 3, 3)]
+    pub capacity_list_length: u8,
+}
+
+impl CapacityListHeader {
+    pub fn new(capacity_list_length: u8) -> Self {
+        Self {
+            capacity_list_length,
+        }
+    }
+}
+
+/// The capacity of the medium that's present, or the maximum the device supports if there isn't one
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct CurrentMaximumCapacityDescriptor {
+    /// Saturates at 0xFFFFFFFF, READ CAPACITY(16) has the real number
+    #[pkd(7, 0, 0, 3)]
+    pub number_of_blocks: u32,
+
+    #[pkd(1, 0, 4, 4)]
+    pub descriptor_type: CapacityDescriptorType,
+
+    #[pkd(7, 0, 5, 7)]
+    pub block_length: u32,
+}
+
+impl CurrentMaximumCapacityDescriptor {
+    pub fn new(number_of_blocks: u64, block_length: u32, descriptor_type: CapacityDescriptorType) -> Self {
+        Self {
+            number_of_blocks: number_of_blocks.min(u32::MAX.into()) as u32,
+            descriptor_type,
+            block_length,
+        }
+    }
+}
+
+#[test]
+fn test_read_format_capacities_pack() {
+    use packing::PackedSize;
+
+    assert_eq!(CapacityListHeader::BYTES, 4);
+    assert_eq!(CurrentMaximumCapacityDescriptor::BYTES, 8);
+
+    let mut buf = [0; 8];
+    CurrentMaximumCapacityDescriptor::new(0x1234, 512, CapacityDescriptorType::NoMediaPresent)
+        .pack(&mut buf).unwrap();
+    assert_eq!(buf, [0x00, 0x00, 0x12, 0x34, 0x03, 0x00, 0x02, 0x00]);
+}
\ No newline at end of file
@@ -0,0 +1,45 @@
+use packing::Packed;
+
+/// Header of the parameter data returned by REPORT LUNS, followed by a `LunListEntry` per LUN
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReportLunsHeader {
+    /// Length in bytes of the LUN list that follows this header. 8 bytes per LUN
+    #[pkd(7, 0, 0, 3)]
+    pub lun_list_length: u32,
+
+    #[pkd(7, 0, 4, 7)]
+    _reserved: u32,
+}
+
+impl ReportLunsHeader {
+    pub fn new(lun_list_length: u32) -> Self {
+        Self {
+            lun_list_length,
+            ..Default::default()
+        }
+    }
+}
+
+/// A single LUN in the REPORT LUNS parameter data
+///
+/// Uses the peripheral device addressing method with bus identifier 0 (SAM-4 4.6.6) which
+/// works out as the LUN in the first 2 bytes followed by 6 zero bytes
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LunListEntry {
+    #[pkd(7, 0, 0, 1)]
+    pub lun: u16,
+
+    #[pkd(7, 0, 2, 7)]
+    _reserved: [u8; 6],
+}
+
+impl LunListEntry {
+    pub fn new(lun: u8) -> Self {
+        Self {
+            lun: lun.into(),
+            ..Default::default()
+        }
+    }
+}
\ No newline at end of file
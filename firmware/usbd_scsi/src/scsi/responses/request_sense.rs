@@ -1,4 +1,5 @@
 use packing::{
+    Error as PackingError,
     Packed,
     PackedSize,
 };
@@ -67,9 +68,10 @@ sense_key_specific
 impl Default for RequestSenseResponse {
     fn default() -> Self {
         Self {
-            valid: true,
+            // Set along with the fields they cover, when there's something to report in them
+            valid: false,
             additional_sense_length: Self::BYTES as u8 - 7,
-            sense_key_specific_valid: true,
+            sense_key_specific_valid: false,
             additional_sense_data: [0; 235],
 
             response_code: Default::default(),
@@ -87,20 +89,179 @@ impl Default for RequestSenseResponse {
 }
 
 impl RequestSenseResponse {
+    /// Length of fixed format sense data without any additional sense bytes
+    pub const FIXED_FORMAT_BYTES: usize = 18;
+
     pub fn reset_status(&mut self) {
         *self = Default::default()
     }
+
+    /// Progress of the operation that's making the logical unit not ready, out of 65536
+    pub fn set_progress_indication(&mut self, progress: u16) {
+        self.sense_key_specific_valid = true;
+        self.sense_key_specific = progress.into();
+    }
+
+    /// Points at the invalid field for ILLEGAL REQUEST. `command` is set if `byte` is in the command
+    /// block rather than the parameter list
+    pub fn set_field_pointer(&mut self, command: bool, byte: u16) {
+        self.sense_key_specific_valid = true;
+        self.sense_key_specific = u32::from(command) << 22 | u32::from(byte);
+    }
+
+    /// Fixed format only has 32 bits for the information field, VALID is cleared if it doesn't fit
+    pub fn set_information(&mut self, information: u64) {
+        self.valid = information <= u32::MAX.into();
+        self.information = information as u32;
+    }
+
+    /// Packs the first `FIXED_FORMAT_BYTES` with the additional sense length to match. For
+    /// transports that send the sense data along with the status
+    pub fn pack_fixed_format(&self) -> Result<[u8; Self::FIXED_FORMAT_BYTES], PackingError> {
+        let mut response = *self;
+        response.additional_sense_length = (Self::FIXED_FORMAT_BYTES - 8) as u8;
+
+        let mut buf = [0; Self::BYTES];
+        response.pack(&mut buf)?;
+
+        let mut fixed_format = [0; Self::FIXED_FORMAT_BYTES];
+        fixed_format.copy_from_slice(&buf[..Self::FIXED_FORMAT_BYTES]);
+        Ok(fixed_format)
+    }
+
+    /// Packs the same sense data in descriptor format into `buf`, which must be
+    /// `DESCRIPTOR_FORMAT_BYTES` long. There's an information descriptor if `information` is
+    /// `Some` and a sense key specific descriptor if SKSV is set. Any bytes after them are zeroed
+    pub fn pack_descriptor_format(&self, information: Option<u64>, buf: &mut [u8]) -> Result<(), PackingError> {
+        assert_eq!(buf.len(), DESCRIPTOR_FORMAT_BYTES);
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+
+        let mut i = DescriptorSenseDataHeader::BYTES;
+        if let Some(information) = information {
+            InformationSenseDataDescriptor::new(information)
+                .pack(&mut buf[i..i + InformationSenseDataDescriptor::BYTES])?;
+            i += InformationSenseDataDescriptor::BYTES;
+        }
+        if self.sense_key_specific_valid {
+            SenseKeySpecificSenseDataDescriptor::new(self.sense_key_specific)
+                .pack(&mut buf[i..i + SenseKeySpecificSenseDataDescriptor::BYTES])?;
+            i += SenseKeySpecificSenseDataDescriptor::BYTES;
+        }
+
+        let response_code = match self.response_code {
+            ResponseCode::DeferredFixed |
+            ResponseCode::DeferredDescriptor => ResponseCode::DeferredDescriptor,
+            _ => ResponseCode::Descriptor,
+        };
+        let header = DescriptorSenseDataHeader {
+            response_code,
+            sense_key: self.sense_key,
+            additional_sense_code: self.additional_sense_code,
+            additional_sense_length: (i - DescriptorSenseDataHeader::BYTES) as u8,
+        };
+        header.pack(&mut buf[..DescriptorSenseDataHeader::BYTES])
+    }
 }
 
-/*
-    if !descriptor_format
-        return fixed sense data
-    else
-        if descriptor sense data supported
-            return descriptor sense data
-        else 
-            return CHECK CONDITION with sense:
-                key: ILLEGAL REQUEST
-                additional code: INVALID FIELD IN CDB
-
-*/
\ No newline at end of file
+/// Longest descriptor format sense data, with an information and a sense key specific descriptor
+pub const DESCRIPTOR_FORMAT_BYTES: usize = DescriptorSenseDataHeader::BYTES +
+    InformationSenseDataDescriptor::BYTES + SenseKeySpecificSenseDataDescriptor::BYTES;
+
+/// Header of descriptor format sense data, followed by `additional_sense_length` bytes of sense
+/// data descriptors (SPC-4 4.5.2)
+#[derive(Clone, Copy, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct DescriptorSenseDataHeader {
+    #[pkd(6, 0, 0, 0)]
+    pub response_code: ResponseCode,
+
+    #[pkd(3, 0, 1, 1)]
+    pub sense_key: SenseKey,
+
+    #[pkd(7, 0, 2, 3)]
+    pub additional_sense_code: AdditionalSenseCode,
+
+    #[pkd(7, 0, 7, 7)]
+    pub additional_sense_length: u8,
+}
+
+/// Sense data descriptor holding the INFORMATION field in full (SPC-4 4.5.2.2)
+#[derive(Clone, Copy, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct InformationSenseDataDescriptor {
+    #[pkd(7, 0, 0, 0)]
+    pub descriptor_type: u8,
+
+    #[pkd(7, 0, 1, 1)]
+    pub additional_length: u8,
+
+    #[pkd(7, 7, 2, 2)]
+    pub valid: bool,
+
+    #[pkd(7, 0, 4, 11)]
+    pub information: u64,
+}
+
+impl InformationSenseDataDescriptor {
+    pub fn new(information: u64) -> Self {
+        Self {
+            descriptor_type: 0x00,
+            additional_length: (Self::BYTES - 2) as u8,
+            valid: true,
+            information,
+        }
+    }
+}
+
+/// Sense data descriptor holding the sense key specific field, e.g. progress or a field pointer
+/// (SPC-4 4.5.2.3)
+#[derive(Clone, Copy, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct SenseKeySpecificSenseDataDescriptor {
+    #[pkd(7, 0, 0, 0)]
+    pub descriptor_type: u8,
+
+    #[pkd(7, 0, 1, 1)]
+    pub additional_length: u8,
+
+    #[pkd(7, 7, 4, 4)]
+    pub sense_key_specific_valid: bool,
+
+    #[pkd(6, 0, 4, 6)]
+    pub sense_key_specific: u32,
+
+    #[pkd(7, 0, 7, 7)]
+    _reserved: u8,
+}
+
+impl SenseKeySpecificSenseDataDescriptor {
+    pub fn new(sense_key_specific: u32) -> Self {
+        Self {
+            descriptor_type: 0x02,
+            additional_length: (Self::BYTES - 2) as u8,
+            sense_key_specific_valid: true,
+            sense_key_specific,
+            _reserved: 0,
+        }
+    }
+}
+
+#[test]
+fn test_descriptor_format() {
+    let mut sense = RequestSenseResponse {
+        sense_key: SenseKey::IllegalRequest,
+        additional_sense_code: AdditionalSenseCode::InvalidFieldInCdb,
+        ..Default::default()
+    };
+    sense.set_field_pointer(true, 2);
+
+    let mut buf = [0xFF; DESCRIPTOR_FORMAT_BYTES];
+    sense.pack_descriptor_format(Some(0x1_0000_0000), &mut buf).unwrap();
+    assert_eq!(buf, [
+        0x72, 0x05, 0x24, 0x00, 0x00, 0x00, 0x00, 0x14,
+        0x00, 0x0A, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x02, 0x06, 0x00, 0x00, 0xC0, 0x00, 0x02, 0x00,
+    ]);
+}
\ No newline at end of file
@@ -0,0 +1,235 @@
+use packing::Packed;
+
+use crate::scsi::enums::{
+    PeripheralQualifier,
+    PeripheralDeviceType,
+    VitalProductDataPage,
+};
+
+/// Code set of a designator containing ASCII
+pub const CODE_SET_ASCII: u8 = 0x2;
+
+/// Designator made up of the T10 vendor identification followed by a vendor specific identifier
+pub const DESIGNATOR_TYPE_T10_VENDOR_ID: u8 = 0x1;
+
+/// The designator is associated with the logical unit rather than a port or the target device
+pub const ASSOCIATION_LOGICAL_UNIT: u8 = 0x0;
+
+/// Header of every vital product data page, followed by `page_length` bytes of the page itself
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct VitalProductDataHeader {
+    #[pkd(7, 5, 0, 0)]
+    pub peripheral_qualifier: PeripheralQualifier,
+
+    #[pkd(4, 0, 0, 0)]
+    pub peripheral_device_type: PeripheralDeviceType,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_code: VitalProductDataPage,
+
+    /// Length in bytes of the page that follows this header
+    #[pkd(7, 0, 2, 3)]
+    pub page_length: u16,
+}
+
+impl VitalProductDataHeader {
+    pub fn new(page_code: VitalProductDataPage, page_length: u16) -> Self {
+        Self {
+            page_code,
+            page_length,
+            ..Default::default()
+        }
+    }
+}
+
+/// Header of a designation descriptor in the device identification page, followed by
+/// `designator_length` bytes of designator (SPC-4 7.8.6)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct DesignationDescriptorHeader {
+    /// Only meaningful when `protocol_identifier_valid` is set
+    #[pkd(7, 4, 0, 0)]
+    pub protocol_identifier: u8,
+
+    /// `CODE_SET_ASCII` or binary (1h)
+    #[pkd(3, 0, 0, 0)]
+    pub code_set: u8,
+
+    #[pkd(7, 7, 1, 1)]
+    pub protocol_identifier_valid: bool,
+
+    /// What the designator identifies, e.g. `ASSOCIATION_LOGICAL_UNIT`
+    #[pkd(5, 4, 1, 1)]
+    pub association: u8,
+
+    /// The format of the designator, e.g. `DESIGNATOR_TYPE_T10_VENDOR_ID`
+    #[pkd(3, 0, 1, 1)]
+    pub designator_type: u8,
+
+    #[pkd(7, 0, 3, 3)]
+    pub designator_length: u8,
+}
+
+/// The block limits page (SBC-3 6.5.3) without the header
+///
+/// Byte offsets are from the end of the header so they're 4 less than in the spec. 0 in any of
+/// the length fields means there's no limit or it isn't reported
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct BlockLimitsPage {
+    /// Write same with a number of blocks of 0 isn't supported
+    #[pkd(0, 0, 0, 0)]
+    pub write_same_non_zero: bool,
+
+    #[pkd(7, 0, 1, 1)]
+    pub maximum_compare_and_write_length: u8,
+
+    /// Transfers that aren't a multiple of this many blocks may be slower
+    #[pkd(7, 0, 2, 3)]
+    pub optimal_transfer_length_granularity: u16,
+
+    /// Largest number of blocks that can be transferred by a single command
+    #[pkd(7, 0, 4, 7)]
+    pub maximum_transfer_length: u32,
+
+    /// Number of blocks above which transfers may be slower
+    #[pkd(7, 0, 8, 11)]
+    pub optimal_transfer_length: u32,
+
+    #[pkd(7, 0, 12, 15)]
+    pub maximum_prefetch_length: u32,
+
+    #[pkd(7, 0, 16, 19)]
+    pub maximum_unmap_lba_count: u32,
+
+    #[pkd(7, 0, 20, 23)]
+    pub maximum_unmap_block_descriptor_count: u32,
+
+    #[pkd(7, 0, 24, 27)]
+    pub optimal_unmap_granularity: u32,
+
+    #[pkd(7, 7, 28, 28)]
+    pub unmap_granularity_alignment_valid: bool,
+
+    #[pkd(6, 0, 28, 31)]
+    pub unmap_granularity_alignment: u32,
+
+    #[pkd(7, 0, 32, 39)]
+    pub maximum_write_same_length: u64,
+
+    #[pkd(7, 0, 40, 59)]
+    _reserved: [u8; 20],
+}
+
+/// The block device characteristics page (SBC-3 6.5.2) without the header
+///
+/// Byte offsets are from the end of the header so they're 4 less than in the spec
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct BlockDeviceCharacteristicsPage {
+    /// 0 is not reported, 1 is non-rotating (solid state), otherwise the nominal RPM
+    #[pkd(7, 0, 0, 1)]
+    pub medium_rotation_rate: u16,
+
+    #[pkd(7, 0, 2, 2)]
+    pub product_type: u8,
+
+    #[pkd(7, 6, 3, 3)]
+    pub write_after_block_erase_required: u8,
+
+    #[pkd(5, 4, 3, 3)]
+    pub write_after_cryptographic_erase_required: u8,
+
+    /// 0 is not reported, the rest are various disk sizes
+    #[pkd(3, 0, 3, 3)]
+    pub nominal_form_factor: u8,
+
+    #[pkd(1, 1, 4, 4)]
+    pub force_unit_access_behaviour: bool,
+
+    #[pkd(0, 0, 4, 4)]
+    pub verify_byte_check_unmapped_lba_supported: bool,
+
+    #[pkd(7, 0, 5, 59)]
+    _reserved: [u8; 55],
+}
+
+impl Default for BlockDeviceCharacteristicsPage {
+    fn default() -> Self {
+        Self {
+            medium_rotation_rate: Default::default(),
+            product_type: Default::default(),
+            write_after_block_erase_required: Default::default(),
+            write_after_cryptographic_erase_required: Default::default(),
+            nominal_form_factor: Default::default(),
+            force_unit_access_behaviour: Default::default(),
+            verify_byte_check_unmapped_lba_supported: Default::default(),
+            _reserved: [0; 55],
+        }
+    }
+}
+
+/// Provisioning type of a logical unit with enough resources to map every LBA
+pub const PROVISIONING_TYPE_RESOURCE: u8 = 0x1;
+
+/// The logical block provisioning page (SBC-3 6.5.4) without the header
+///
+/// Byte offsets are from the end of the header so they're 4 less than in the spec. There are no
+/// provisioning group descriptors
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LogicalBlockProvisioningPage {
+    #[pkd(7, 0, 0, 0)]
+    pub threshold_exponent: u8,
+
+    /// UNMAP is supported
+    #[pkd(7, 7, 1, 1)]
+    pub unmap_supported: bool,
+
+    /// WRITE SAME(16) with the UNMAP bit is supported
+    #[pkd(6, 6, 1, 1)]
+    pub write_same_16_unmap_supported: bool,
+
+    /// WRITE SAME(10) with the UNMAP bit is supported
+    #[pkd(5, 5, 1, 1)]
+    pub write_same_10_unmap_supported: bool,
+
+    /// Unmapped blocks read back as zeros
+    #[pkd(2, 2, 1, 1)]
+    pub read_zeros: bool,
+
+    #[pkd(1, 1, 1, 1)]
+    pub anchor_supported: bool,
+
+    #[pkd(0, 0, 1, 1)]
+    pub descriptor_present: bool,
+
+    #[pkd(2, 0, 2, 2)]
+    pub provisioning_type: u8,
+
+    #[pkd(7, 0, 3, 3)]
+    _reserved: u8,
+}
+
+impl LogicalBlockProvisioningPage {
+    /// UNMAP and WRITE SAME(16) with the UNMAP bit are both supported or neither is
+    pub fn new(unmap_supported: bool) -> Self {
+        Self {
+            unmap_supported,
+            write_same_16_unmap_supported: unmap_supported,
+            provisioning_type: if unmap_supported { PROVISIONING_TYPE_RESOURCE } else { 0 },
+            ..Default::default()
+        }
+    }
+}
+
+#[test]
+fn test_page_lengths() {
+    use packing::PackedSize;
+
+    // SBC-3 fixes the page length of both at 3Ch
+    assert_eq!(BlockLimitsPage::BYTES, 0x3C);
+    assert_eq!(BlockDeviceCharacteristicsPage::BYTES, 0x3C);
+    assert_eq!(LogicalBlockProvisioningPage::BYTES, 0x4);
+}
\ No newline at end of file
@@ -1,3 +1,4 @@
+use core::marker::PhantomData;
 use packing::{
     Packed,
     PackedSize,
@@ -6,246 +7,518 @@ use packing::{
 use usb_device::class_prelude::*;
 use usb_device::Result as UsbResult;
 
-use usbd_bulk_only_transport::{
-    BulkOnlyTransport,
-    Error as BulkOnlyTransportError,
+use usbd_bulk_only_transport::BulkOnlyTransport;
+
+use usbd_mass_storage::{
+    InterfaceSubclass,
+    Transport,
+    TransportError,
+    Direction,
     TransferState,
 };
 
-use usbd_mass_storage::InterfaceSubclass;
-
 use crate::{
     logging::*,
     block_device::{
         BlockDevice,
         BlockDeviceError,
+        PowerCondition,
+    },
+    logical_units::{
+        LogicalUnits,
+        BlockDeviceVisitor,
     },
     scsi::{
         commands::*,
         responses::*,
         enums::*,
         Error,
+        DeferredError,
+        LogicalUnitState,
+        FormatState,
+        ModePages,
+        DeviceModePages,
     },
 };
 
+/// Longest serial number `Scsi::set_unit_serial_number` accepts
+const MAX_UNIT_SERIAL_NUMBER_BYTES: usize = 32;
+
+/// Reported in the block device characteristics page. Every block device is assumed to be solid state
+const NON_ROTATING_MEDIUM: u16 = 0x0001;
+
+/// The vital product data pages supported with and without a serial number set
+const VITAL_PRODUCT_DATA_PAGES: [VitalProductDataPage; 6] = [
+    VitalProductDataPage::SupportedVpdPages,
+    VitalProductDataPage::UnitSerialNumber,
+    VitalProductDataPage::DeviceIdentification,
+    VitalProductDataPage::BlockLimits,
+    VitalProductDataPage::BlockDeviceCharacteristics,
+    VitalProductDataPage::LogicalBlockProvisioning,
+];
+const VITAL_PRODUCT_DATA_PAGES_WITHOUT_SERIAL: [VitalProductDataPage; 5] = [
+    VitalProductDataPage::SupportedVpdPages,
+    VitalProductDataPage::DeviceIdentification,
+    VitalProductDataPage::BlockLimits,
+    VitalProductDataPage::BlockDeviceCharacteristics,
+    VitalProductDataPage::LogicalBlockProvisioning,
+];
+
+/// UFI always sends a format descriptor after the FORMAT UNIT parameter list header
+const UFI_FORMAT_DESCRIPTOR_BYTES: usize = 8;
+
 enum CommandState {
     None,
     Done,
     Ongoing,
 }
 
+/// Where the block device is with the span of blocks for the current READ or WRITE
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TransferSpan {
+    /// There isn't one or the block device has finished it
+    Idle,
+    /// `begin_read`/`begin_write` hasn't returned `Ok` yet
+    Starting,
+    /// Blocks are being transferred
+    Open,
+}
+
 /// # Scsi Transparent Command Set implementation
 ///
-/// Built on top of [BulkOnlyTransport](struct.BulkOnlyTransport.html)
+/// Built on top of a mass storage [Transport](trait.Transport.html), by default
+/// [BulkOnlyTransport](struct.BulkOnlyTransport.html). If the transport reports the UFI subclass the
+/// UFI command set is used instead. UFI is a subset of SCSI for floppy drives with 12 byte command
+/// blocks, the LUN in the command block and a shorter inquiry response. See
+/// [UFI Command Spec](https://www.usb.org/document-library/mass-storage-ufi-command-specification-10)
+///
+/// Commands are routed to one of the block devices in `LU` using the LUN from the transport (or the
+/// command block for UFI). A single [BlockDevice](trait.BlockDevice.html) is presented as LUN 0, see
+/// [LogicalUnits](trait.LogicalUnits.html) for presenting more than one.
+///
+/// The buffer of the transport must be a multiple of the block size of every logical unit. When it's
+/// a larger multiple, READ and WRITE commands transfer as many blocks as will fit in the buffer each poll.
 ///
 /// [Glossary](index.html#glossary)
-pub struct Scsi<'a, B: UsbBus, BD: BlockDevice> {
-    inner: BulkOnlyTransport<'a, B>,
+pub struct Scsi<'a, B: UsbBus, LU: LogicalUnits, T: Transport<B> = BulkOnlyTransport<'a, B>> {
+    processor: CommandProcessor<B, T>,
+    logical_units: LU,
+    logical_unit_states: LU::States,
+    /// Bit per LUN that has finished resetting while the transport has a reset pending so it isn't
+    /// reset again while waiting for the others
+    reset_done: u16,
+    _transport_lifetime: PhantomData<&'a ()>,
+}
+
+/// Everything needed to process a command apart from the block devices. Kept separate from
+/// `Scsi` so it can be borrowed at the same time as a block device from `LogicalUnits`
+struct CommandProcessor<B: UsbBus, T: Transport<B>> {
+    inner: T,
+    /// The transport reported the UFI subclass
+    ufi: bool,
     current_command: Command,
+    current_lun: u8,
     inquiry_response: InquiryResponse,
-    request_sense_response: RequestSenseResponse,
-    block_device: BD,
-    lba: u32,
-    lba_end: u32,
+    unit_serial_number: [u8; MAX_UNIT_SERIAL_NUMBER_BYTES],
+    unit_serial_number_len: usize,
+    lba: u64,
+    lba_end: u64,
+    /// The first block of the current transfer the block device had to correct
+    recovered_lba: Option<u64>,
+    transfer: TransferSpan,
+    _bus: PhantomData<B>,
+}
+
+/// Processes the current command with the block device for the current LUN
+struct ProcessCommand<'p, B: UsbBus, T: Transport<B>> {
+    processor: &'p mut CommandProcessor<B, T>,
+    state: &'p mut LogicalUnitState,
+    new_command: bool,
+}
+
+impl<B: UsbBus, T: Transport<B>> BlockDeviceVisitor for ProcessCommand<'_, B, T> {
+    type Output = Result<CommandState, Error>;
+
+    fn visit<BD: BlockDevice>(self, block_device: &mut BD) -> Self::Output {
+        self.processor.process_command(self.state, block_device, self.new_command)
+    }
+}
+
+/// Gets `BLOCK_BYTES` of the block device for a LUN
+struct BlockBytes;
+
+impl BlockDeviceVisitor for BlockBytes {
+    type Output = usize;
+
+    fn visit<BD: BlockDevice>(self, _block_device: &mut BD) -> Self::Output {
+        BD::BLOCK_BYTES
+    }
+}
+
+/// Gets the last LBA of the block device for a LUN
+struct MaxLba;
+
+impl BlockDeviceVisitor for MaxLba {
+    type Output = u64;
+
+    fn visit<BD: BlockDevice>(self, block_device: &mut BD) -> Self::Output {
+        block_device.max_lba()
+    }
+}
+
+/// Takes a failed write back from the block device for a LUN
+struct TakeWriteBackError;
+
+impl BlockDeviceVisitor for TakeWriteBackError {
+    type Output = Option<(u64, BlockDeviceError)>;
+
+    fn visit<BD: BlockDevice>(self, block_device: &mut BD) -> Self::Output {
+        block_device.take_write_back_error()
+    }
+}
+
+/// Tells the block device for a LUN its span of blocks won't be finished
+struct AbortTransfer;
+
+impl BlockDeviceVisitor for AbortTransfer {
+    type Output = ();
+
+    fn visit<BD: BlockDevice>(self, block_device: &mut BD) -> Self::Output {
+        block_device.abort_transfer()
+    }
+}
+
+/// Resets the block device for a LUN
+struct ResetBlockDevice;
+
+impl BlockDeviceVisitor for ResetBlockDevice {
+    type Output = nb::Result<(), BlockDeviceError>;
+
+    fn visit<BD: BlockDevice>(self, block_device: &mut BD) -> Self::Output {
+        block_device.reset()
+    }
+}
+
+/// Carries on formatting the block device for a LUN
+struct FormatBlockDevice;
+
+impl BlockDeviceVisitor for FormatBlockDevice {
+    type Output = nb::Result<(), BlockDeviceError>;
+
+    fn visit<BD: BlockDevice>(self, block_device: &mut BD) -> Self::Output {
+        block_device.format()
+    }
 }
 
-impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
-    /// Creates a new Scsi block device
+impl<'a, B: UsbBus, LU: LogicalUnits, const BUFFER_BYTES: usize> Scsi<'a, B, LU, BulkOnlyTransport<'a, B, BUFFER_BYTES>> {
+    /// Creates a new Scsi block device using the bulk only transport
     ///
-    /// `block_device` provides reading and writing of blocks to the underlying filesystem
+    /// `logical_units` provides reading and writing of blocks to the underlying filesystem. Either
+    ///      a single [BlockDevice](trait.BlockDevice.html) or a tuple of them to present more than
+    ///      one LUN to the host. Panics if there are more than 16 logical units or if `BUFFER_BYTES`
+    ///      isn't a multiple of the block size of all of them.
     ///
-    /// `vendor_identification` is an ASCII string that forms part of the SCSI inquiry response. 
+    /// `vendor_identification` is an ASCII string that forms part of the SCSI inquiry response.
     ///      Should come from [t10](https://www.t10.org/lists/2vid.htm). Any semi-unique non-blank
     ///      string should work fine for local development. Panics if > 8 characters are supplied.
     ///
-    /// `product_identification` is an ASCII string that forms part of the SCSI inquiry response. 
+    /// `product_identification` is an ASCII string that forms part of the SCSI inquiry response.
     ///      Vendor (probably you...) defined so pick whatever you want. Panics if > 16 characters
     ///      are supplied.
     ///
-    /// `product_revision_level` is an ASCII string that forms part of the SCSI inquiry response. 
+    /// `product_revision_level` is an ASCII string that forms part of the SCSI inquiry response.
     ///      Vendor (probably you...) defined so pick whatever you want. Typically a version number.
     ///      Panics if > 4 characters are supplied.
     pub fn new<V: AsRef<[u8]>, P: AsRef<[u8]>, R: AsRef<[u8]>> (
-        alloc: &UsbBusAllocator<B>, 
-        max_packet_size: u16, 
-        block_device: BD,
+        alloc: &'a UsbBusAllocator<B>,
+        max_packet_size: u16,
+        logical_units: LU,
+        vendor_identification: V,
+        product_identification: P,
+        product_revision_level: R,
+    ) -> Self {
+        // `with_transport` checks the number of logical units, this just keeps the transport from
+        // panicking first with a less helpful message
+        let max_lun = LU::COUNT.saturating_sub(1).min(15);
+
+        let transport = BulkOnlyTransport::new(
+            alloc,
+            max_packet_size,
+            InterfaceSubclass::ScsiTransparentCommandSet,
+            max_lun,
+        );
+
+        Self::with_transport(
+            transport,
+            logical_units,
+            vendor_identification,
+            product_identification,
+            product_revision_level,
+        )
+    }
+
+    /// Relaxes the checks made on command block wrappers for hosts that don't follow the spec.
+    /// See [BulkOnlyTransport::set_lenient_cbw_validation](struct.BulkOnlyTransport.html#method.set_lenient_cbw_validation)
+    pub fn set_lenient_cbw_validation(&mut self, lenient: bool) {
+        self.processor.inner.set_lenient_cbw_validation(lenient);
+    }
+}
+
+impl<B: UsbBus, LU: LogicalUnits, T: Transport<B>> Scsi<'_, B, LU, T> {
+    /// Creates a new Scsi block device on top of an existing transport
+    ///
+    /// The command set is picked using the subclass of `transport`, UFI if it's
+    /// `InterfaceSubclass::Ufi` and SCSI otherwise. UFI only has room for 8 logical units rather
+    /// than 16. See [new](#method.new) for the other arguments.
+    pub fn with_transport<V: AsRef<[u8]>, P: AsRef<[u8]>, R: AsRef<[u8]>> (
+        transport: T,
+        mut logical_units: LU,
         vendor_identification: V,
         product_identification: P,
         product_revision_level: R,
-    ) -> Scsi<'_, B, BD> {
+    ) -> Self {
+        let ufi = transport.interface_subclass() == InterfaceSubclass::Ufi;
+
         let mut inquiry_response = InquiryResponse::default();
         inquiry_response.set_vendor_identification(vendor_identification);
         inquiry_response.set_product_identification(product_identification);
         inquiry_response.set_product_revision_level(product_revision_level);
+        if ufi {
+            inquiry_response.set_ufi_format();
+        }
+
+        // The largest LUN field of any transport or command set is 4 bits, UFI only has 3
+        assert!(LU::COUNT > 0 && LU::COUNT <= if ufi { 8 } else { 16 });
+
+        // Whole blocks are always moved in and out of the buffer so it has to divide evenly
+        for lun in 0..LU::COUNT {
+            let block_bytes = logical_units.visit(lun, BlockBytes).unwrap();
+            assert!(T::BUFFER_BYTES.is_multiple_of(block_bytes));
+        }
 
-        //TODO: This is reasonable for FAT but not FAT32 or others. BOT buffer should probably be 
-        //configurable from here, perhaps passing in BD::BLOCK_BYTES.max(BOT::MIN_BUFFER) or something 
-        assert!(BD::BLOCK_BYTES <= BulkOnlyTransport::<B>::BUFFER_BYTES);
         Scsi {
-            inner: BulkOnlyTransport::new(
-                alloc, 
-                max_packet_size, 
-                InterfaceSubclass::ScsiTransparentCommandSet,
-                0,
-            ),
-            current_command: Command::None,
-            inquiry_response,
-            request_sense_response: Default::default(),
-            block_device,
-            lba: 0,
-            lba_end: 0,
+            processor: CommandProcessor {
+                inner: transport,
+                ufi,
+                current_command: Command::None,
+                current_lun: 0,
+                inquiry_response,
+                unit_serial_number: [0; MAX_UNIT_SERIAL_NUMBER_BYTES],
+                unit_serial_number_len: 0,
+                lba: 0,
+                lba_end: 0,
+                recovered_lba: None,
+                transfer: TransferSpan::Idle,
+                _bus: PhantomData,
+            },
+            logical_units,
+            logical_unit_states: Default::default(),
+            reset_done: 0,
+            _transport_lifetime: PhantomData,
         }
     }
 
-    /// Grants access to the block device for the purposes of housekeeping etc.
-    pub fn block_device_mut(&mut self) -> &mut BD {
-        &mut self.block_device
+    /// Grants access to the block device(s) for the purposes of housekeeping etc.
+    pub fn block_device_mut(&mut self) -> &mut LU {
+        &mut self.logical_units
     }
 
-    fn get_new_command(&mut self) -> Result<bool, Error> {
-        if self.current_command != Command::None {
-            Ok(false)
-        } else {
-            if let Some(cbw) = self.inner.get_current_command() {
-                self.current_command = Command::extract_from_cbw(cbw)?;
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        }
+    /// Grants access to the underlying transport
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.processor.inner
     }
 
-    fn process_command(&mut self, new_command: bool) -> Result<CommandState, Error> {
-        use CommandState::*;
+    /// Sets the serial number returned in the unit serial number and device identification vital
+    /// product data pages. Hosts use it to tell devices apart, Linux names them in /dev/disk/by-id
+    /// with it for example. Every LUN reports the same serial. Without one the unit serial number
+    /// page isn't supported. Panics if > 32 characters are supplied.
+    pub fn set_unit_serial_number<S: AsRef<[u8]>>(&mut self, serial_number: S) {
+        let serial_number = serial_number.as_ref();
+        assert!(serial_number.len() <= MAX_UNIT_SERIAL_NUMBER_BYTES);
+        self.processor.unit_serial_number[..serial_number.len()].copy_from_slice(serial_number);
+        self.processor.unit_serial_number_len = serial_number.len();
+    }
 
-        trace_scsi_command!("COMMAND> {:?}", self.current_command);
+    /// Takes the medium out of the logical unit. Commands that access it fail with NOT READY until
+    /// [insert_medium](#method.insert_medium) is called. Panics if `lun` doesn't exist
+    pub fn remove_medium(&mut self, lun: u8) {
+        self.logical_unit_states.as_mut()[lun as usize].removed = true;
+    }
 
-        Ok(match self.current_command {
-            // No command, nothing to do
-            Command::None => None,
+    /// Puts the medium back after [remove_medium](#method.remove_medium) or the host ejecting it.
+    /// The medium is ready to use, as if the host had started it again. The next command gets a
+    /// UNIT ATTENTION so the host drops anything it has cached. Call it on its own when the contents
+    /// change without a removal, for example swapping the image a block device exposes. Panics if
+    /// `lun` doesn't exist
+    pub fn insert_medium(&mut self, lun: u8) {
+        let state = &mut self.logical_unit_states.as_mut()[lun as usize];
+        state.removed = false;
+        state.ejected = false;
+        state.stopped = false;
+        state.medium_changed = true;
+    }
 
-            // Inquiry, send back inquiry response
-            // TODO: This always responds with "standard" response data but the req might be
-            // for descriptor based response data.
-            Command::Inquiry(_) => {
-                let buf = self.inner.take_buffer_space(InquiryResponse::BYTES)?;
-                self.inquiry_response.pack(buf)?;
-                Done
-            },
+    /// The medium hasn't been removed by the firmware or ejected by the host. Panics if `lun`
+    /// doesn't exist
+    pub fn is_medium_present(&self, lun: u8) -> bool {
+        self.logical_unit_states.as_ref()[lun as usize].medium_present()
+    }
 
-            // Testing if the unit is ready. Responding CommandOk is sufficient for this request
-            // TODO: There's a situation where after enough errors the host will keep sending TUR
-            // requests and nothing else, regardless of the response. There may be additional data
-            // in the request that indicates you should respond CommandError and prepare sense
-            // response data with more info but not looked in detail yet.
-            Command::TestUnitReady(_) => Done,
+    /// The host has asked for the medium not to be removed, the firmware should avoid calling
+    /// [remove_medium](#method.remove_medium) while it's set. Panics if `lun` doesn't exist
+    pub fn is_medium_removal_prevented(&self, lun: u8) -> bool {
+        self.logical_unit_states.as_ref()[lun as usize].prevent_removal
+    }
 
-            // Prevent the user removing the disk, not implemented. Just responding CommandOk sufficient
-            // for flash based device.
-            Command::PreventAllowMediumRemoval(_) => Done,
+    /// Retries a read, write or reset that a block device returned `WouldBlock` for
+    ///
+    /// The endpoint NAKs while the block device is busy so there may not be a USB interrupt to poll
+    /// the class once it finishes. Call this when the block device finishes or periodically. It does
+    /// nothing if there's nothing to retry
+    pub fn resume(&mut self) {
+        if let Err(e) = self.update() {
+            error!("Error from Scsi::update: {:?}", e);
+        }
+    }
 
-            // Read the capacity and block size of the device
-            Command::ReadCapacity(_)  => {
-                let max_lba = self.block_device.max_lba();
-                let block_size = BD::BLOCK_BYTES as u32;
-                let cap = ReadCapacity10Response {
-                    max_lba,
-                    block_size,
-                };
-                
-                let buf = self.inner.take_buffer_space(ReadCapacity10Response::BYTES)?;
-                cap.pack(buf)?;
-                Done
-            },
+    /// Drops the command in progress. A format that was holding up the status finishes in the
+    /// background instead since the block device has already started it
+    fn abandon_command(&mut self) {
+        self.abort_transfer();
+        self.processor.current_command = Command::None;
 
-            // Check the readonly and cache (potentially other info) about the device
-            Command::ModeSense(ModeSenseXCommand { command_length: CommandLength::C6, page_control: PageControl::CurrentValues })  => {
-                let mut header = ModeParameterHeader6::default();
-                header.increase_length_for_page(PageCode::CachingModePage);
-                
-                // Default is both caches disabled
-                let cache_page = CachingModePage::default();
+        for state in self.logical_unit_states.as_mut() {
+            if state.format == FormatState::Running {
+                state.format = FormatState::Background;
+            }
+        }
+    }
 
-                let buf = self.inner.take_buffer_space(
-                    ModeParameterHeader6::BYTES + CachingModePage::BYTES
-                )?;   
+    /// Lets the block device know the span of blocks for the current command won't be finished
+    fn abort_transfer(&mut self) {
+        if self.processor.transfer != TransferSpan::Idle {
+            self.processor.transfer = TransferSpan::Idle;
+            self.logical_units.visit(self.processor.current_lun, AbortTransfer);
+        }
+    }
 
-                header.pack(&mut buf[..ModeParameterHeader6::BYTES])?;
-                cache_page.pack(&mut buf[ModeParameterHeader6::BYTES..])?;
-                Done
-            },
+    /// Resets the block devices while the transport has a reset pending and lets the transport carry
+    /// on once they've all finished. A LUN that fails ends the reset straight away
+    fn continue_reset(&mut self) {
+        if !self.processor.inner.reset_pending() {
+            return;
+        }
 
-            // Request sense is how more info about the state of the device is returned
-            // Returning CommandError will cause the host to perform a request sense
-            // to get more details.
-            Command::RequestSense(_) => {
-                let buf = self.inner.take_buffer_space(RequestSenseResponse::BYTES)?;
-                self.request_sense_response.pack(buf)?;
-                Done
-            },
+        let mut pending = false;
+        for lun in 0..LU::COUNT {
+            if self.reset_done & (1 << lun) != 0 {
+                continue;
+            }
 
-            // Read `transfer_length` blocks from `lba`
-            Command::Read(r) => {
-                // Record the end condition
-                if new_command {
-                    self.lba = r.lba;
-                    self.lba_end = r.lba + r.transfer_length - 1;
-                }
+            match self.logical_units.visit(lun, ResetBlockDevice).unwrap() {
+                Ok(()) => self.reset_done |= 1 << lun,
+                Err(nb::Error::WouldBlock) => pending = true,
+                Err(nb::Error::Other(e)) => {
+                    error!("Reset of LUN {} failed: {:?}", lun, e);
+                    self.reset_done = 0;
+                    self.processor.inner.finish_reset(false);
+                    return;
+                },
+            }
+        }
 
-                trace_scsi_fs!("FS> Read; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}, done: {}",
-                    new_command, self.lba, self.lba_end, self.lba == self.lba_end);
+        if !pending {
+            self.reset_done = 0;
+            self.processor.inner.finish_reset(true);
+        }
+    }
 
-                // We only get here if the buffer is empty 
-                let buf = self.inner.take_buffer_space(BD::BLOCK_BYTES)?;
-                self.block_device.read_block(self.lba, buf)?;
-                self.lba += 1;
+    /// Keeps formats started with IMMED going. A failure is reported to the next command as a
+    /// deferred error
+    fn continue_background_formats(&mut self) {
+        let logical_units = &mut self.logical_units;
 
-                if self.lba <= self.lba_end {
-                    Ongoing
-                } else {
-                    Done
-                }
-            },
+        for (lun, state) in self.logical_unit_states.as_mut().iter_mut().enumerate() {
+            if state.format != FormatState::Background {
+                continue;
+            }
 
-            // Write `transfer_length` blocks from `lba`
-            Command::Write(w) => {
-                // Record the end condition
-                if new_command {
-                    self.lba = w.lba;
-                    self.lba_end = w.lba + w.transfer_length - 1;
-                }
+            match logical_units.visit(lun as u8, FormatBlockDevice).unwrap() {
+                Ok(()) => state.format = FormatState::Idle,
+                Err(nb::Error::WouldBlock) => {},
+                Err(nb::Error::Other(e)) => {
+                    error!("Background format of LUN {} failed: {:?}", lun, e);
+                    state.format = FormatState::Idle;
+                    state.defer_error(DeferredError {
+                        sense_key: SenseKey::MediumError,
+                        additional_sense_code: AdditionalSenseCode::FormatCommandFailed,
+                        information: None,
+                    });
+                },
+            }
+        }
+    }
 
-                trace_scsi_fs!("FS> Write; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}, done: {}",
-                    new_command, self.lba, self.lba_end, self.lba == self.lba_end);
+    /// The state for the LUN the current command is addressed to. None if that LUN doesn't exist
+    fn current_state_mut(&mut self) -> Option<&mut LogicalUnitState> {
+        self.logical_unit_states.as_mut().get_mut(self.processor.current_lun as usize)
+    }
 
-                let len = match self.inner.transfer_state() {
-                    TransferState::ReceivingDataFromHost { done: true, full: false, bytes_available: b } => b,
-                    // TODO: Does this ever happen?
-                    _ => BD::BLOCK_BYTES,
-                };
+    fn process_command(&mut self, new_command: bool) -> Result<CommandState, Error> {
+        let lun = self.processor.current_lun;
 
-                let buf = self.inner.take_buffered_data(len, false).expect("Buffer should have enough data");
-                self.block_device.write_block(self.lba, buf)?;
-                self.lba += 1;
+        // Let the transport know what we're going to transfer so it can deal with any mismatch with
+        // what the host asked for
+        if new_command {
+            let block_bytes = self.logical_units.visit(lun, BlockBytes);
+            let max_lba = self.logical_units.visit(lun, MaxLba);
+            let (direction, bytes) = self.processor.device_intent(block_bytes, max_lba, LU::COUNT);
+            self.processor.inner.set_device_intent(direction, bytes)?;
+        }
 
-                if self.lba <= self.lba_end {
-                    Ongoing
-                } else {
-                    Done
+        // A failed write back is reported to the next command for the LUN it happened on
+        if new_command {
+            if let Some(state) = self.logical_unit_states.as_mut().get_mut(lun as usize) {
+                if let Some((lba, e)) = self.logical_units.visit(lun, TakeWriteBackError).flatten() {
+                    error!("Write back of LBA 0x{:X?} on LUN {} failed: {:?}", lba, lun, e);
+                    let (sense_key, additional_sense_code) = map_error_to_sense_data(&e.into());
+                    state.defer_error(DeferredError {
+                        sense_key,
+                        additional_sense_code,
+                        information: Some(lba),
+                    });
                 }
-            },
+            }
+        }
 
-            _ => Err(Error::UnhandledOpCode)?,
-        })
+        // Report LUNs describes the whole target rather than any one logical unit
+        if let Command::ReportLuns(r) = self.processor.current_command {
+            return self.processor.report_luns(&r, LU::COUNT);
+        }
+
+        let processor = &mut self.processor;
+        let logical_units = &mut self.logical_units;
+
+        let result = self.logical_unit_states.as_mut()
+            .get_mut(lun as usize)
+            .and_then(|state| logical_units.visit(lun, ProcessCommand {
+                processor,
+                state,
+                new_command,
+            }));
+
+        match result {
+            Some(r) => r,
+            None => self.processor.process_unsupported_lun_command(),
+        }
     }
 
-    fn receive_command(&mut self) -> Result<(), Error> {
-        let transfer_state = self.inner.transfer_state();
-        // These calls all assume only a single block will fit in the buffer which 
-        // is true here because we configure BOT that way but we could make the inner
-        // buffer length a multiple of BLOCK_SIZE and queue up more than one block
-        // at a time. I don't know if there's any benefit to that but the option is there
-        let skip = match transfer_state {
+    /// Checks if an ongoing command can make progress
+    fn ready_for_data(&self) -> bool {
+        // The buffer is a multiple of the block size so waiting until it's full or empty
+        // means there's always room for at least one whole block. Read and write then
+        // process as many blocks as they can before waiting again
+        let skip = match self.processor.inner.transfer_state() {
             TransferState::ReceivingDataFromHost { full, done, .. } => {
                 !(full || done)
             },
@@ -260,41 +533,62 @@ impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
             }
         };
 
-        if skip {
-            Err(UsbError::WouldBlock)?;
-        }
+        !skip
+    }
 
-        let new_command = self.get_new_command()?;
+    fn receive_command(&mut self) -> Result<(), Error> {
+        // New commands are always processed straight away so the transport knows what to expect before
+        // any data is transferred
+        let result = match self.processor.get_new_command() {
+            Ok(new_command) if new_command || self.ready_for_data() => self.process_command(new_command),
+            Ok(_) => Err(UsbError::WouldBlock.into()),
+            Err(e) => Err(e),
+        };
 
-        match self.process_command(new_command) {
+        match result {
             Ok(CommandState::Done) => {
                 // Command is done, send CommandOk
-                self.inner.send_command_ok()?;
+                self.processor.inner.send_command_ok()?;
                 // Clear the command so we don't try and execute it again
-                self.current_command = Command::None;
+                self.processor.current_command = Command::None;
 
                 // Reset sense code to good
-                self.request_sense_response.reset_status();
+                if let Some(state) = self.current_state_mut() {
+                    state.reset_sense();
+                }
             },
             // WouldBlock error is handled the same as ongoing (i.e. do nothing)
             Ok(CommandState::None) |
             Ok(CommandState::Ongoing) |
-            Err(Error::BulkOnlyTransportError(
-                BulkOnlyTransportError::UsbError(
+            Err(Error::TransportError(
+                TransportError::UsbError(
                     UsbError::WouldBlock))) => {
                 // No command, command is ongoing or we couldn't get a buffer/some other WouldBlock issue
                 // Do nothing
             },
+            Err(Error::TransportError(TransportError::PhaseError)) => {
+                // The transport has already sent the status, just abandon the command
+                self.abandon_command();
+            },
             Err(e) => {
-                // Command failed, send CommandErr
-                self.inner.send_command_error()?;
+                self.abort_transfer();
+
+                let information = self.processor.sense_information(&e);
+                let sense = sense_data(&e, information);
+
+                // Command failed, send CommandErr along with the sense for transports that report it
+                self.processor.inner.send_command_error(&sense.pack_fixed_format()?)?;
                 // Clear the command so we don't try and execute it again
                 // All errors immediately terminate the command and cause the host to
                 // retry or issue RequestSense to find out more info
-                self.current_command = Command::None;
+                self.processor.current_command = Command::None;
 
                 // Update the sense data so the host can find out what went wrong
-                self.map_error_to_sense_data(&e);
+                // There's nowhere to store sense data for a LUN that doesn't exist but that's fine since
+                // request sense for those always reports LogicalUnitNotSupported anyway
+                if let Some(state) = self.current_state_mut() {
+                    state.set_sense_data(sense, information);
+                }
 
                 // Return the error to the caller so it can get logged
                 Err(e)?;
@@ -304,77 +598,23 @@ impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
         Ok(())
     }
 
-    fn map_error_to_sense_data(&mut self, err: &Error) {
-        let (sense_key, additional_sense_code) = match err {
-            Error::UnhandledOpCode => (
-                 SenseKey::IllegalRequest,
-                 AdditionalSenseCode::InvalidCommandOperationCode,
-            ),
-            
-            Error::InsufficientDataForCommand => (
-                SenseKey::IllegalRequest,
-                // Closest thing I could find. Some sources suggest OS does very little with ASC/ASCQ and it's
-                // most useful for debugging so as long as it's unique here it's probably ok.
-                AdditionalSenseCode::InvalidPacketSize,
-            ),
-
-            Error::PackingError(p) |
-            Error::BulkOnlyTransportError(BulkOnlyTransportError::PackingError(p)) => match p {
-                PackingError::InsufficientBytes => panic!("PackingError::InsufficientBytes: Logical error in program"),
-                PackingError::Infallible(_) => unreachable!(),
-                PackingError::InvalidEnumDiscriminant => (
-                    SenseKey::IllegalRequest,
-                    AdditionalSenseCode::InvalidFieldInCdb,
-                ),
-            },
-
-            Error::BlockDeviceError(BlockDeviceError::HardwareError) => (
-                SenseKey::HardwareError,
-                //TODO: split errors up more?
-                AdditionalSenseCode::NoAdditionalSenseInformation,
-            ),
-            Error::BlockDeviceError(BlockDeviceError::WriteError) => (
-                SenseKey::MediumError,
-                AdditionalSenseCode::WriteError,
-            ),
-            Error::BlockDeviceError(BlockDeviceError::EraseError) => (
-                SenseKey::MediumError,
-                AdditionalSenseCode::EraseFailure,
-            ),
-            Error::BlockDeviceError(BlockDeviceError::InvalidAddress) => (
-                SenseKey::IllegalRequest,
-                AdditionalSenseCode::LogicalBlockAddressOutOfRange,
-            ),
-
-            Error::BulkOnlyTransportError(BulkOnlyTransportError::DataError) => (
-                SenseKey::IllegalRequest,
-                AdditionalSenseCode::InvalidFieldInCdb,
-            ),
-
-            // These USB errors are likely to result in a USB reset, it's unlikely a SCSI
-            // request sense will ever be issued in these cases but just-in-case
-            Error::BulkOnlyTransportError(BulkOnlyTransportError::UsbError(_)) => (
-                SenseKey::HardwareError,
-                AdditionalSenseCode::NoAdditionalSenseInformation,
-            ),
-        };
+    fn update(&mut self) -> Result<(), Error> {
 
-        info!("SENSE: {:?}, ASC: {} {}", sense_key, additional_sense_code.asc(), additional_sense_code.ascq());
-        self.request_sense_response.sense_key = sense_key;
-        self.request_sense_response.additional_sense_code = additional_sense_code;
-    }
+        // Nothing moves on the bulk endpoints until a reset has finished
+        self.continue_reset();
 
-    fn update(&mut self) -> Result<(), Error> {
+        // Formats run between commands once their status has been sent
+        self.continue_background_formats();
 
         // Send anything that's already queued
         accept_would_block(
-            self.inner.write()
+            self.processor.inner.write()
                 .map_err(|e| e.into())
         )?;
 
         // Read new data if available
         accept_would_block(
-            self.inner.read()
+            self.processor.inner.read()
                 .map_err(|e| e.into())
         )?;
 
@@ -383,7 +623,7 @@ impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
 
         // Send anything we may have generated this go around
         accept_would_block(
-            self.inner.write()
+            self.processor.inner.write()
                 .map_err(|e| e.into())
         )?;
 
@@ -391,38 +631,1713 @@ impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
     }
 }
 
-fn accept_would_block(r: Result<(), Error>) -> Result<(), Error> {
-    match r {
-        Ok(_) | Err(Error::BulkOnlyTransportError(BulkOnlyTransportError::UsbError(UsbError::WouldBlock))) => Ok(()),
-        e => e
+impl<B: UsbBus, T: Transport<B>> CommandProcessor<B, T> {
+    fn get_new_command(&mut self) -> Result<bool, Error> {
+        if self.current_command != Command::None {
+            Ok(false)
+        } else {
+            if let Some(command_block) = self.inner.get_current_command() {
+                self.current_lun = if self.ufi {
+                    // UFI puts the LUN in the top 3 bits of byte 1 of every command block
+                    command_block.bytes.get(1).map_or(0, |b| b >> 5)
+                } else {
+                    command_block.lun
+                };
+                self.current_command = Command::extract_from_command_block(&command_block)?;
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
     }
-}
 
-impl<B: UsbBus, BD: BlockDevice> UsbClass<B> for Scsi<'_, B, BD> {
-    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
-        self.inner.get_configuration_descriptors(writer)
+    /// The number of bytes of inquiry data the command set returns
+    fn inquiry_bytes(&self) -> usize {
+        if self.ufi {
+            UFI_INQUIRY_RESPONSE_BYTES
+        } else {
+            InquiryResponse::BYTES
+        }
     }
 
-    fn reset(&mut self) { 
-        self.current_command = Command::None;
-        self.request_sense_response.reset_status();
-        self.lba = 0;
-        self.lba_end = 0;
+    fn inquiry(&mut self, inquiry_response: &InquiryResponse) -> Result<(), Error> {
+        let mut bytes = [0; InquiryResponse::BYTES];
+        inquiry_response.pack(&mut bytes)?;
+
+        let len = self.inquiry_bytes();
+        let buf = self.inner.take_buffer_space(len)?;
+        buf.copy_from_slice(&bytes[..len]);
+        Ok(())
+    }
 
-        self.inner.reset()
+    fn supported_vital_product_data_pages(&self) -> &'static [VitalProductDataPage] {
+        if self.unit_serial_number_len > 0 {
+            &VITAL_PRODUCT_DATA_PAGES
+        } else {
+            &VITAL_PRODUCT_DATA_PAGES_WITHOUT_SERIAL
+        }
     }
 
-    fn control_in(&mut self, xfer: ControlIn<B>) {
-        self.inner.control_in(xfer)
+    /// Checks the page requested by an inquiry with EVPD set is supported
+    fn vital_product_data_page(&self, page_code: u8) -> Result<VitalProductDataPage, Error> {
+        VitalProductDataPage::from_primitive(page_code)
+            .ok()
+            .filter(|page| self.supported_vital_product_data_pages().contains(page))
+            .ok_or(Error::InvalidFieldInCdb(2))
     }
 
-    fn control_out(&mut self, xfer: ControlOut<B>) {
-        self.inner.control_out(xfer)
+    /// The number of bytes in a vital product data page including the header
+    fn vital_product_data_bytes(&self, page: VitalProductDataPage) -> usize {
+        use VitalProductDataPage::*;
+
+        let vendor_product_bytes = self.inquiry_response.vendor_identification().len() +
+            self.inquiry_response.product_identification().len();
+
+        VitalProductDataHeader::BYTES + match page {
+            SupportedVpdPages => self.supported_vital_product_data_pages().len(),
+            UnitSerialNumber => self.unit_serial_number_len,
+            DeviceIdentification => DesignationDescriptorHeader::BYTES + vendor_product_bytes + self.unit_serial_number_len,
+            BlockLimits => BlockLimitsPage::BYTES,
+            BlockDeviceCharacteristics => BlockDeviceCharacteristicsPage::BYTES,
+            LogicalBlockProvisioning => LogicalBlockProvisioningPage::BYTES,
+        }
     }
 
-    fn poll(&mut self) { 
-        if let Err(e) = self.update() {
-            error!("Error from Scsi::update: {:?}", e);
+    /// Sends a vital product data page (SPC-4 7.8)
+    fn vital_product_data<BD: BlockDevice>(&mut self, block_device: &BD, page: VitalProductDataPage) -> Result<(), Error> {
+        use VitalProductDataPage::*;
+
+        let len = self.vital_product_data_bytes(page);
+        let header = VitalProductDataHeader::new(page, (len - VitalProductDataHeader::BYTES) as u16);
+        let supported_pages = self.supported_vital_product_data_pages();
+        let vendor_identification = self.inquiry_response.vendor_identification();
+        let product_identification = self.inquiry_response.product_identification();
+        let serial_number = &self.unit_serial_number[..self.unit_serial_number_len];
+
+        let buf = self.inner.take_buffer_space(len)?;
+        let (header_buf, page_buf) = buf.split_at_mut(VitalProductDataHeader::BYTES);
+        header.pack(header_buf)?;
+
+        match page {
+            SupportedVpdPages => {
+                for (b, supported_page) in page_buf.iter_mut().zip(supported_pages) {
+                    *b = supported_page.to_primitive();
+                }
+            },
+            UnitSerialNumber => page_buf.copy_from_slice(serial_number),
+            // A single T10 vendor ID based designator, the vendor followed by the product and serial
+            // number. That's unique enough as long as the serial number is set
+            DeviceIdentification => {
+                let descriptor = DesignationDescriptorHeader {
+                    code_set: CODE_SET_ASCII,
+                    association: ASSOCIATION_LOGICAL_UNIT,
+                    designator_type: DESIGNATOR_TYPE_T10_VENDOR_ID,
+                    designator_length: (page_buf.len() - DesignationDescriptorHeader::BYTES) as u8,
+                    ..Default::default()
+                };
+                let (descriptor_buf, designator_buf) = page_buf.split_at_mut(DesignationDescriptorHeader::BYTES);
+                descriptor.pack(descriptor_buf)?;
+
+                let (vendor_buf, designator_buf) = designator_buf.split_at_mut(vendor_identification.len());
+                vendor_buf.copy_from_slice(vendor_identification);
+                let (product_buf, serial_buf) = designator_buf.split_at_mut(product_identification.len());
+                product_buf.copy_from_slice(product_identification);
+                serial_buf.copy_from_slice(serial_number);
+            },
+            BlockLimits => {
+                let mut limits = BlockLimitsPage::default();
+                limits.optimal_transfer_length_granularity = 1;
+                // A buffer full of blocks is transferred each poll
+                limits.optimal_transfer_length = (T::BUFFER_BYTES / BD::BLOCK_BYTES) as u32;
+                if block_device.unmap_supported() {
+                    // The whole UNMAP parameter list has to fit in the buffer
+                    limits.maximum_unmap_lba_count = u32::MAX;
+                    limits.maximum_unmap_block_descriptor_count =
+                        ((T::BUFFER_BYTES - UnmapParameterListHeader::BYTES) / UnmapBlockDescriptor::BYTES) as u32;
+                }
+                limits.pack(page_buf)?;
+            },
+            BlockDeviceCharacteristics => {
+                let mut characteristics = BlockDeviceCharacteristicsPage::default();
+                characteristics.medium_rotation_rate = NON_ROTATING_MEDIUM;
+                characteristics.pack(page_buf)?;
+            },
+            LogicalBlockProvisioning => {
+                let provisioning = LogicalBlockProvisioningPage::new(block_device.unmap_supported());
+                provisioning.pack(page_buf)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// The direction and number of bytes the current command will transfer
+    ///
+    /// `block_bytes` and `max_lba` are `None` if the command is addressed to a LUN that doesn't exist
+    fn device_intent(&self, block_bytes: Option<usize>, max_lba: Option<u64>, lun_count: u8) -> (Direction, u32) {
+        use Direction::*;
+
+        let block_bytes = block_bytes.map(|b| b as u32);
+
+        match self.current_command {
+            // Vital product data needs a LUN that exists. UFI doesn't have it
+            Command::Inquiry(i) if i.enable_vital_product_data && !self.ufi => (
+                DeviceToHost,
+                block_bytes
+                    .and(self.vital_product_data_page(i.page_code).ok())
+                    .map_or(0, |page| self.vital_product_data_bytes(page) as u32)
+                    .min(i.allocation_length.into()),
+            ),
+            // Invalid, there's only one page of standard inquiry data
+            Command::Inquiry(i) if i.page_code != 0 && !self.ufi => (DeviceToHost, 0),
+            Command::Inquiry(i) => (
+                DeviceToHost,
+                (self.inquiry_bytes() as u32).min(i.allocation_length.into()),
+            ),
+            Command::RequestSense(r) => (
+                DeviceToHost,
+                (self.request_sense_bytes(&r) as u32).min(r.allocation_length.into()),
+            ),
+            Command::ReportLuns(r) => (
+                DeviceToHost,
+                report_luns_count(&r, lun_count)
+                    .map_or(0, |count| (ReportLunsHeader::BYTES + LunListEntry::BYTES * count as usize) as u32)
+                    .min(r.allocation_length),
+            ),
+            // Everything else needs a LUN that exists
+            _ if block_bytes.is_none() => (DeviceToHost, 0),
+
+            // Nothing is transferred if any of the blocks are past the end of the medium
+            Command::Read(r) if !blocks_on_medium(r.lba, r.transfer_length.into(), max_lba.unwrap()) => (DeviceToHost, 0),
+            Command::Write(w) if !blocks_on_medium(w.lba, w.transfer_length.into(), max_lba.unwrap()) => (DeviceToHost, 0),
+            Command::Verify(v) if !blocks_on_medium(v.lba.into(), v.verification_length.into(), max_lba.unwrap()) => (DeviceToHost, 0),
+
+            Command::ReadCapacity(_) => (
+                DeviceToHost,
+                ReadCapacity10Response::BYTES as u32,
+            ),
+            Command::ReadCapacity16(r) => (
+                DeviceToHost,
+                (ReadCapacity16Response::BYTES as u32).min(r.allocation_length),
+            ),
+            Command::ReadFormatCapacities(r) => (
+                DeviceToHost,
+                ((CapacityListHeader::BYTES + CurrentMaximumCapacityDescriptor::BYTES) as u32).min(r.allocation_length.into()),
+            ),
+            Command::ModeSense(m) => (
+                DeviceToHost,
+                self.mode_sense_bytes(&m).map_or(0, |b| b as u32).min(m.allocation_length.into()),
+            ),
+            Command::ModeSelect(m) => (
+                HostToDevice,
+                m.parameter_list_length.into(),
+            ),
+            Command::Format(f) if f.format_data => (
+                HostToDevice,
+                self.format_parameter_list_bytes(&f) as u32,
+            ),
+            Command::Read(r) => (
+                DeviceToHost,
+                r.transfer_length.saturating_mul(block_bytes.unwrap()),
+            ),
+            Command::Write(w) => (
+                HostToDevice,
+                w.transfer_length.saturating_mul(block_bytes.unwrap()),
+            ),
+            // The host sends the data to compare against
+            Command::Verify(v) if v.byte_check == 0b01 => (
+                HostToDevice,
+                u32::from(v.verification_length).saturating_mul(block_bytes.unwrap()),
+            ),
+            Command::Unmap(u) => (
+                HostToDevice,
+                u.parameter_list_length.into(),
+            ),
+            // The block to write, which is ignored since only unmapping is supported
+            Command::WriteSame(w) if !w.no_data_out_buffer => (
+                HostToDevice,
+                block_bytes.unwrap(),
+            ),
+            _ => (DeviceToHost, 0),
         }
     }
+
+    fn process_command<BD: BlockDevice>(
+        &mut self,
+        state: &mut LogicalUnitState,
+        block_device: &mut BD,
+        new_command: bool,
+    ) -> Result<CommandState, Error> {
+        use CommandState::*;
+
+        trace_scsi_command!("COMMAND> {:?}, LUN: {}", self.current_command, self.current_lun);
+
+        Ok(match self.current_command {
+            // No command, nothing to do
+            Command::None => None,
+
+            // Inquiry, send back the vital product data page if EVPD is set or the standard
+            // inquiry response otherwise. UFI reserves the EVPD and page code fields
+            Command::Inquiry(i) if i.enable_vital_product_data && !self.ufi => {
+                let page = self.vital_product_data_page(i.page_code)?;
+                self.vital_product_data(block_device, page)?;
+                Done
+            },
+            Command::Inquiry(i) if i.page_code != 0 && !self.ufi => Err(Error::InvalidFieldInCdb(2))?,
+            Command::Inquiry(_) => {
+                let inquiry_response = self.inquiry_response;
+                self.inquiry(&inquiry_response)?;
+                Done
+            },
+
+            // Errors from work that carried on after its status was sent are reported to the next
+            // command, or returned by request sense (SPC-4 4.5.5)
+            Command::RequestSense(_) if state.deferred_error.is_some() => {
+                let error = Error::Deferred(state.deferred_error.take().unwrap());
+                let information = self.sense_information(&error);
+                state.set_sense_data(sense_data(&error, information), information);
+                self.request_sense(state)?;
+                Done
+            },
+            _ if new_command && state.deferred_error.is_some() => Err(Error::Deferred(state.deferred_error.take().unwrap()))?,
+
+            // Nothing else can be done while the medium is being formatted. Request sense reports how
+            // far through the format is
+            Command::RequestSense(_) if state.format != FormatState::Idle => {
+                state.set_sense(SenseKey::NotReady, AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress);
+                state.request_sense_response.set_progress_indication(block_device.format_progress());
+                self.request_sense(state)?;
+                Done
+            },
+            _ if new_command && state.format != FormatState::Idle => Err(Error::FormatInProgress)?,
+
+            // A changed medium is reported once, to the first command after the change that isn't
+            // inquiry. Request sense returns it in place of whatever sense was there before
+            Command::RequestSense(_) if state.medium_changed => {
+                state.medium_changed = false;
+                state.set_sense(SenseKey::UnitAttention, AdditionalSenseCode::NotReadyToReadyChange);
+                self.request_sense(state)?;
+                Done
+            },
+            _ if new_command && state.medium_changed => {
+                state.medium_changed = false;
+                Err(Error::MediumMayHaveChanged)?
+            },
+
+            // Anything that needs the medium fails while it's ejected or removed, or while the host
+            // has stopped the logical unit. A stopped unit needs START STOP UNIT to start it again
+            Command::TestUnitReady(_) |
+            Command::ReadCapacity(_) |
+            Command::ReadCapacity16(_) |
+            Command::Read(_) |
+            Command::Write(_) |
+            Command::Format(_) |
+            Command::Verify(_) |
+            Command::SynchronizeCache(_) |
+            Command::Unmap(_) |
+            Command::WriteSame(_) if new_command && (!state.medium_present() || state.stopped) => {
+                if !state.medium_present() {
+                    Err(Error::MediumNotPresent)?
+                } else {
+                    Err(Error::UnitStopped)?
+                }
+            },
+
+            // Testing if the unit is ready. The medium and the stopped state have been checked
+            // above so it is
+            Command::TestUnitReady(_) => Done,
+
+            // Stop the host ejecting the medium. Flash can't be pulled out by the user so all this
+            // does is make START STOP UNIT refuse to eject it
+            Command::PreventAllowMediumRemoval(p) => {
+                state.prevent_removal = p.prevent & 0b01 != 0;
+                Done
+            },
+
+            // Start or stop the medium and load or eject it, or change the power condition
+            Command::StartStopUnit(s) => self.start_stop_unit(state, block_device, &s)?,
+
+            // Only the default self-test is supported, there are no diagnostic pages. Without SELFTEST
+            // and a parameter list there's nothing to do
+            Command::SendDiagnostic(d) if d.self_test_code != 0 => Err(Error::InvalidFieldInCdb(1))?,
+            Command::SendDiagnostic(d) if d.parameter_list_length != 0 => Err(Error::InvalidFieldInCdb(3))?,
+            Command::SendDiagnostic(d) if !d.self_test => Done,
+            Command::SendDiagnostic(_) => match block_device.self_test() {
+                Ok(()) => Done,
+                Err(nb::Error::WouldBlock) => Ongoing,
+                Err(nb::Error::Other(e)) => {
+                    error!("Self-test failed: {:?}", e);
+                    Err(Error::SelfTestFailed)?
+                },
+            },
+
+            // Read the capacity and block size of the device
+            Command::ReadCapacity(_)  => {
+                // All Fs tells the host to use READ CAPACITY(16) if the LBA doesn't fit (SBC-3 5.15.2)
+                let max_lba = block_device.max_lba().min(u32::MAX.into()) as u32;
+                let block_size = BD::BLOCK_BYTES as u32;
+                let cap = ReadCapacity10Response {
+                    max_lba,
+                    block_size,
+                };
+
+                let buf = self.inner.take_buffer_space(ReadCapacity10Response::BYTES)?;
+                cap.pack(buf)?;
+                Done
+            },
+
+            // Same as above with a 64 bit LBA and the physical block size
+            Command::ReadCapacity16(_)  => {
+                let mut cap = ReadCapacity16Response::new(
+                    block_device.max_lba(),
+                    BD::BLOCK_BYTES as u32,
+                    block_device.logical_blocks_per_physical_block_exponent(),
+                );
+                cap.logical_block_provisioning_management_enabled = block_device.unmap_supported();
+
+                let buf = self.inner.take_buffer_space(ReadCapacity16Response::BYTES)?;
+                cap.pack(buf)?;
+                Done
+            },
+
+            // Windows asks for this on every enumeration. Only the current capacity is listed, which
+            // is the most the device supports when there's no medium
+            Command::ReadFormatCapacities(_) => {
+                let descriptor_type = if state.medium_present() {
+                    CapacityDescriptorType::FormattedMedia
+                } else {
+                    CapacityDescriptorType::NoMediaPresent
+                };
+                let header = CapacityListHeader::new(CurrentMaximumCapacityDescriptor::BYTES as u8);
+                let descriptor = CurrentMaximumCapacityDescriptor::new(
+                    block_device.max_lba().saturating_add(1),
+                    BD::BLOCK_BYTES as u32,
+                    descriptor_type,
+                );
+
+                let buf = self.inner.take_buffer_space(CapacityListHeader::BYTES + CurrentMaximumCapacityDescriptor::BYTES)?;
+                let (header_buf, descriptor_buf) = buf.split_at_mut(CapacityListHeader::BYTES);
+                header.pack(header_buf)?;
+                descriptor.pack(descriptor_buf)?;
+                Done
+            },
+
+            // Report the current, changeable or default values of one or all of the mode pages
+            Command::ModeSense(m) => {
+                self.mode_sense(state, block_device, &m)?;
+                Done
+            },
+
+            // Change the current values of the mode pages once the whole parameter list has arrived
+            Command::ModeSelect(m) => self.mode_select(state, block_device, &m)?,
+
+            // Request sense is how more info about the state of the device is returned
+            // Returning CommandError will cause the host to perform a request sense
+            // to get more details.
+            Command::RequestSense(_) => {
+                self.request_sense(state)?;
+                Done
+            },
+
+            // Read `transfer_length` blocks from `lba`. The whole range is checked before anything is
+            // sent. Reading 0 blocks isn't an error, there's just nothing to do
+            Command::Read(r) if r.transfer_length == 0 => Done,
+            Command::Read(r) => {
+                // Record the end condition
+                if new_command {
+                    if !blocks_on_medium(r.lba, r.transfer_length.into(), block_device.max_lba()) {
+                        Err(Error::LogicalBlockAddressOutOfRange)?;
+                    }
+                    self.lba = r.lba;
+                    self.lba_end = r.lba + u64::from(r.transfer_length) - 1;
+                    self.recovered_lba = Option::None;
+                    self.transfer = TransferSpan::Starting;
+                }
+
+                trace_scsi_fs!("FS> Read; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}",
+                    new_command, self.lba, self.lba_end);
+
+                // The block device gets the whole span before the first block
+                if self.transfer == TransferSpan::Starting {
+                    let begin = block_device.begin_read(r.lba, r.transfer_length.into());
+                    if !self.transfer_started(begin)? {
+                        return Ok(Ongoing);
+                    }
+                }
+
+                // We only get here if the buffer is empty so at least one block will fit.
+                // Keep going until the buffer is full or we run out of blocks
+                loop {
+                    if self.lba > self.lba_end {
+                        let end = block_device.end_transfer();
+                        break self.transfer_finished(end)?;
+                    }
+
+                    // Only keep the space once the block device has filled it, if it's still busy
+                    // the buffer stays empty so the endpoint NAKs until we try again
+                    let buf = match self.inner.peek_buffer_space(BD::BLOCK_BYTES) {
+                        Ok(buf) => buf,
+                        Err(TransportError::UsbError(UsbError::WouldBlock)) => break Ongoing,
+                        Err(e) => Err(e)?,
+                    };
+                    let result = block_device.read_next_block(self.lba, buf);
+                    match self.recover(result) {
+                        Ok(()) => {},
+                        Err(nb::Error::WouldBlock) => break Ongoing,
+                        Err(nb::Error::Other(e)) => Err(e)?,
+                    }
+                    self.inner.take_buffer_space(BD::BLOCK_BYTES)?;
+                    self.lba += 1;
+                }
+            },
+
+            // Write back anything the block device has cached. The whole device is flushed whatever
+            // range was asked for and the status isn't sent until it's done, even if IMMED is set
+            Command::SynchronizeCache(_) => match block_device.flush() {
+                Ok(()) => Done,
+                Err(nb::Error::WouldBlock) => Ongoing,
+                Err(nb::Error::Other(e)) => Err(e)?,
+            },
+
+            // Nothing that changes the contents of the device is allowed while it's write protected
+            Command::Write(_) |
+            Command::Format(_) |
+            Command::Unmap(_) |
+            Command::WriteSame(_) if new_command && block_device.is_write_protected() => Err(Error::WriteProtected)?,
+
+            // Format the medium, after checking the parameter list if there is one
+            Command::Format(f) => self.format_unit(state, block_device, &f)?,
+
+            // Discard the ranges of blocks in the parameter list once all of it has arrived
+            Command::Unmap(u) => self.unmap(block_device, &u, new_command)?,
+
+            // Only the unmapping form of WRITE SAME is supported
+            Command::WriteSame(w) => self.write_same(block_device, &w, new_command)?,
+
+            // Write `transfer_length` blocks from `lba`. Like read the range is checked first and 0 blocks
+            // does nothing
+            Command::Write(w) if w.transfer_length == 0 => Done,
+            Command::Write(w) => {
+                // Record the end condition
+                if new_command {
+                    if !blocks_on_medium(w.lba, w.transfer_length.into(), block_device.max_lba()) {
+                        Err(Error::LogicalBlockAddressOutOfRange)?;
+                    }
+                    self.lba = w.lba;
+                    self.lba_end = w.lba + u64::from(w.transfer_length) - 1;
+                    self.transfer = TransferSpan::Starting;
+                }
+
+                trace_scsi_fs!("FS> Write; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}",
+                    new_command, self.lba, self.lba_end);
+
+                // Like read the block device gets the whole span first
+                if self.transfer == TransferSpan::Starting {
+                    let begin = block_device.begin_write(w.lba, w.transfer_length.into());
+                    if !self.transfer_started(begin)? {
+                        return Ok(Ongoing);
+                    }
+                }
+
+                // Write every whole block in the buffer
+                loop {
+                    if self.lba > self.lba_end {
+                        let end = block_device.end_transfer();
+                        break self.transfer_finished(end)?;
+                    }
+
+                    match self.inner.transfer_state() {
+                        TransferState::ReceivingDataFromHost { bytes_available: b, .. } if b >= BD::BLOCK_BYTES => {},
+                        // The host stopped short of what the CDB asked for. Block devices only ever get
+                        // whole blocks so the partial one is dropped and the command fails
+                        TransferState::ReceivingDataFromHost { done: true, .. } => Err(Error::InsufficientDataForCommand)?,
+                        _ => break Ongoing,
+                    }
+
+                    // Leave the data in the buffer until the block device accepts it. While it's busy
+                    // the buffer stays full so the endpoint NAKs until we try again
+                    let buf = self.inner.peek_buffered_data(BD::BLOCK_BYTES, false).expect("Buffer should have enough data");
+                    match block_device.write_next_block(self.lba, buf) {
+                        Ok(()) => {},
+                        Err(nb::Error::WouldBlock) => break Ongoing,
+                        Err(nb::Error::Other(e)) => Err(e)?,
+                    }
+                    self.inner.take_buffered_data(BD::BLOCK_BYTES, false)?;
+                    self.lba += 1;
+                }
+            },
+
+            // Check `verification_length` blocks from `lba`. Without BYTCHK the block device just checks
+            // they're readable, with it they're compared with data from the host
+            // BYTCHK 10b is reserved and 11b (one block compared with every LBA) isn't supported
+            Command::Verify(v) if v.byte_check > 0b01 => Err(Error::InvalidFieldInCdb(1))?,
+            Command::Verify(v) if v.verification_length == 0 => Done,
+            Command::Verify(v) => {
+                // Record the end condition
+                if new_command {
+                    if !blocks_on_medium(v.lba.into(), v.verification_length.into(), block_device.max_lba()) {
+                        Err(Error::LogicalBlockAddressOutOfRange)?;
+                    }
+                    self.lba = v.lba.into();
+                    self.lba_end = self.lba + u64::from(v.verification_length) - 1;
+                    self.recovered_lba = Option::None;
+                }
+
+                trace_scsi_fs!("FS> Verify; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}, byte_check: {}",
+                    new_command, self.lba, self.lba_end, v.byte_check);
+
+                loop {
+                    if v.byte_check == 0 {
+                        match self.recover(block_device.verify_block(self.lba)) {
+                            Ok(()) => {},
+                            Err(nb::Error::WouldBlock) => break Ongoing,
+                            Err(nb::Error::Other(e)) => Err(e)?,
+                        }
+                    } else {
+                        match self.inner.transfer_state() {
+                            TransferState::ReceivingDataFromHost { bytes_available: b, .. } if b >= BD::BLOCK_BYTES => {},
+                            // There's no such thing as a short block to compare
+                            TransferState::ReceivingDataFromHost { done: true, .. } => Err(Error::InsufficientDataForCommand)?,
+                            _ => break Ongoing,
+                        }
+
+                        // Like write the data stays in the buffer while the block device is busy
+                        let buf = self.inner.peek_buffered_data(BD::BLOCK_BYTES, false).expect("Buffer should have enough data");
+                        match block_device.compare_block(self.lba, buf) {
+                            Ok(true) => {},
+                            Ok(false) => Err(Error::Miscompare(self.lba))?,
+                            Err(nb::Error::WouldBlock) => break Ongoing,
+                            Err(nb::Error::Other(e)) => Err(e)?,
+                        }
+                        self.inner.take_buffered_data(BD::BLOCK_BYTES, false)?;
+                    }
+                    self.lba += 1;
+
+                    if self.lba > self.lba_end {
+                        break self.recovered_done()?;
+                    }
+                }
+            },
+
+            _ => Err(Error::UnhandledOpCode)?,
+        })
+    }
+
+    /// Responds to a command addressed to a LUN that doesn't exist
+    ///
+    /// Inquiry and request sense still have to work (SPC-4 4.6.5), everything else is rejected
+    fn process_unsupported_lun_command(&mut self) -> Result<CommandState, Error> {
+        use CommandState::*;
+
+        trace_scsi_command!("COMMAND> {:?}, unsupported LUN: {}", self.current_command, self.current_lun);
+
+        Ok(match self.current_command {
+            Command::None => None,
+
+            // Report that there's nothing connected at this LUN. There's no vital product data for
+            // a LUN that doesn't exist
+            Command::Inquiry(i) if i.enable_vital_product_data && !self.ufi => Err(Error::LogicalUnitNotSupported)?,
+            Command::Inquiry(i) if i.page_code != 0 && !self.ufi => Err(Error::InvalidFieldInCdb(2))?,
+            Command::Inquiry(_) => {
+                let mut inquiry_response = self.inquiry_response;
+                inquiry_response.set_peripheral_qualifier(PeripheralQualifier::Incapable);
+                inquiry_response.set_peripheral_device_type(PeripheralDeviceType::UnknownOrNone);
+
+                self.inquiry(&inquiry_response)?;
+                Done
+            },
+
+            Command::RequestSense(_) => {
+                let mut state = LogicalUnitState::default();
+                state.set_sense(SenseKey::IllegalRequest, AdditionalSenseCode::LogicalUnitNotSupported);
+                self.request_sense(&state)?;
+                Done
+            },
+
+            _ => Err(Error::LogicalUnitNotSupported)?,
+        })
+    }
+
+    /// Length of the block descriptor MODE SENSE returns. UFI doesn't have them
+    fn mode_sense_block_descriptor_bytes(&self, m: &ModeSenseXCommand) -> usize {
+        if self.ufi || m.disable_block_descriptors {
+            0
+        } else if m.long_lba_accepted {
+            LongLbaBlockDescriptor::BYTES
+        } else {
+            ShortLbaBlockDescriptor::BYTES
+        }
+    }
+
+    /// Length of the whole MODE SENSE response before it's cut down to the allocation length
+    fn mode_sense_bytes(&self, m: &ModeSenseXCommand) -> Result<usize, Error> {
+        if m.page_control == PageControl::SavedValues {
+            Err(Error::SavingParametersNotSupported)?;
+        }
+
+        let header_bytes = match m.command_length {
+            CommandLength::C6 => ModeParameterHeader6::BYTES,
+            CommandLength::C10 => ModeParameterHeader10::BYTES,
+        };
+        let page_bytes: usize = ModePages::requested(m.page_code, m.subpage_code)?
+            .iter()
+            .map(|p| p.page_bytes())
+            .sum();
+
+        Ok(header_bytes + self.mode_sense_block_descriptor_bytes(m) + page_bytes)
+    }
+
+    /// Sends the mode parameter header, a block descriptor and the requested pages
+    fn mode_sense<BD: BlockDevice>(
+        &mut self,
+        state: &LogicalUnitState,
+        block_device: &BD,
+        m: &ModeSenseXCommand,
+    ) -> Result<(), Error> {
+        let len = self.mode_sense_bytes(m)?;
+        let pages = ModePages::requested(m.page_code, m.subpage_code)?;
+        let descriptor_bytes = self.mode_sense_block_descriptor_bytes(m);
+
+        let number_of_blocks = block_device.max_lba().saturating_add(1);
+        let block_bytes = BD::BLOCK_BYTES as u32;
+        let device_pages = DeviceModePages::new(block_device);
+        let device_specific_parameter = SbcDeviceSpecificParameter {
+            write_protect: block_device.is_write_protected(),
+            ..Default::default()
+        };
+
+        let buf = self.inner.take_buffer_space(len)?;
+
+        let header_bytes = match m.command_length {
+            CommandLength::C6 => {
+                let mut header = ModeParameterHeader6 {
+                    device_specific_parameter,
+                    ..Default::default()
+                };
+                header.set_block_descriptor_length(descriptor_bytes);
+                for page in pages {
+                    header.increase_length_for_page(*page);
+                }
+                header.pack(&mut buf[..ModeParameterHeader6::BYTES])?;
+                ModeParameterHeader6::BYTES
+            },
+            CommandLength::C10 => {
+                let mut header = ModeParameterHeader10 {
+                    long_lba: descriptor_bytes == LongLbaBlockDescriptor::BYTES,
+                    device_specific_parameter,
+                    ..Default::default()
+                };
+                header.set_block_descriptor_length(descriptor_bytes);
+                for page in pages {
+                    header.increase_length_for_page(*page);
+                }
+                header.pack(&mut buf[..ModeParameterHeader10::BYTES])?;
+                ModeParameterHeader10::BYTES
+            },
+        };
+
+        let mut offset = header_bytes + descriptor_bytes;
+        let descriptor_buf = &mut buf[header_bytes..offset];
+        if descriptor_bytes == LongLbaBlockDescriptor::BYTES {
+            LongLbaBlockDescriptor::new(number_of_blocks, block_bytes).pack(descriptor_buf)?;
+        } else if descriptor_bytes == ShortLbaBlockDescriptor::BYTES {
+            ShortLbaBlockDescriptor::new(number_of_blocks, block_bytes).pack(descriptor_buf)?;
+        }
+
+        for page in pages {
+            let end = offset + page.page_bytes();
+            state.mode_pages.pack_page(*page, m.page_control, &device_pages, &mut buf[offset..end])?;
+            offset = end;
+        }
+
+        Ok(())
+    }
+
+    /// Handles START STOP UNIT
+    ///
+    /// A power condition replaces start and LoEj. Otherwise the block device is flushed before it's
+    /// stopped, unless NO_FLUSH is set, then LoEj loads or ejects the medium. Commands that need the
+    /// medium fail while the logical unit is stopped. The status isn't sent until the block device
+    /// is done, even if IMMED is set
+    fn start_stop_unit<BD: BlockDevice>(
+        &mut self,
+        state: &mut LogicalUnitState,
+        block_device: &mut BD,
+        s: &StartStopUnitCommand,
+    ) -> Result<CommandState, Error> {
+        let result = match s.power_condition {
+            0x0 => {
+                if s.load_eject && !s.start && state.prevent_removal {
+                    Err(Error::MediumRemovalPrevented)?;
+                }
+
+                // Flushing again when the stop returns WouldBlock is harmless, there's nothing left
+                if !s.start && !s.no_flush {
+                    match block_device.flush() {
+                        Ok(()) => {},
+                        Err(nb::Error::WouldBlock) => return Ok(CommandState::Ongoing),
+                        Err(nb::Error::Other(e)) => Err(e)?,
+                    }
+                }
+
+                block_device.start_stop(s.start)
+            },
+            0x1 => block_device.set_power_condition(PowerCondition::Active),
+            0x2 | 0xA => block_device.set_power_condition(PowerCondition::Idle),
+            0x3 | 0xB => block_device.set_power_condition(PowerCondition::Standby),
+            0x7 => block_device.set_power_condition(PowerCondition::DeviceControlled),
+            _ => Err(Error::InvalidFieldInCdb(4))?,
+        };
+
+        match result {
+            Ok(()) => {},
+            Err(nb::Error::WouldBlock) => return Ok(CommandState::Ongoing),
+            Err(nb::Error::Other(e)) => Err(e)?,
+        }
+
+        if s.power_condition == 0 {
+            state.stopped = !s.start;
+            if s.load_eject {
+                state.ejected = !s.start;
+            }
+        }
+        Ok(CommandState::Done)
+    }
+
+    /// Unmaps every range in the UNMAP parameter list once all of it is in the buffer
+    ///
+    /// Nothing is unmapped unless every range is on the medium. `lba` counts the block descriptors
+    /// that have been unmapped so the list can be picked up where it left off after `WouldBlock`
+    fn unmap<BD: BlockDevice>(
+        &mut self,
+        block_device: &mut BD,
+        u: &UnmapCommand,
+        new_command: bool,
+    ) -> Result<CommandState, Error> {
+        if !block_device.unmap_supported() {
+            Err(Error::UnhandledOpCode)?;
+        }
+        if u.anchor {
+            Err(Error::InvalidFieldInCdb(1))?;
+        }
+
+        let len = u.parameter_list_length as usize;
+        if len == 0 {
+            return Ok(CommandState::Done);
+        }
+        if len < UnmapParameterListHeader::BYTES || len > T::BUFFER_BYTES {
+            Err(Error::ParameterListLengthError)?;
+        }
+
+        match self.inner.transfer_state() {
+            TransferState::ReceivingDataFromHost { bytes_available, .. } if bytes_available >= len => {},
+            TransferState::ReceivingDataFromHost { done: true, .. } => Err(Error::ParameterListLengthError)?,
+            _ => return Ok(CommandState::Ongoing),
+        }
+
+        if new_command {
+            self.lba = 0;
+        }
+
+        let max_lba = block_device.max_lba();
+        let data = self.inner.peek_buffered_data(len, false)?;
+        let header = UnmapParameterListHeader::unpack(&data[..UnmapParameterListHeader::BYTES])?;
+        // A descriptor cut short by the end of the parameter list is ignored
+        let descriptors_end = len.min(UnmapParameterListHeader::BYTES + header.block_descriptor_data_length as usize);
+        let descriptors = &data[UnmapParameterListHeader::BYTES..descriptors_end];
+
+        for descriptor in descriptors.chunks_exact(UnmapBlockDescriptor::BYTES) {
+            let descriptor = UnmapBlockDescriptor::unpack(descriptor)?;
+            if !blocks_on_medium(descriptor.lba, descriptor.number_of_blocks.into(), max_lba) {
+                Err(Error::LogicalBlockAddressOutOfRange)?;
+            }
+        }
+
+        for descriptor in descriptors.chunks_exact(UnmapBlockDescriptor::BYTES).skip(self.lba as usize) {
+            let descriptor = UnmapBlockDescriptor::unpack(descriptor)?;
+            if descriptor.number_of_blocks > 0 {
+                match block_device.unmap(descriptor.lba, descriptor.number_of_blocks.into()) {
+                    Ok(()) => {},
+                    Err(nb::Error::WouldBlock) => return Ok(CommandState::Ongoing),
+                    Err(nb::Error::Other(e)) => Err(e)?,
+                }
+            }
+            self.lba += 1;
+        }
+
+        self.inner.take_buffered_data(len, false)?;
+        Ok(CommandState::Done)
+    }
+
+    /// Unmaps the blocks WRITE SAME(16) addresses. Writing the block from the host to each of them
+    /// isn't supported so UNMAP has to be set. The block is thrown away once they're unmapped
+    fn write_same<BD: BlockDevice>(
+        &mut self,
+        block_device: &mut BD,
+        w: &WriteSame16Command,
+        new_command: bool,
+    ) -> Result<CommandState, Error> {
+        if !block_device.unmap_supported() {
+            Err(Error::UnhandledOpCode)?;
+        }
+        if !w.unmap || w.anchor || w.write_protect != 0 {
+            Err(Error::InvalidFieldInCdb(1))?;
+        }
+
+        // 0 blocks goes up to the end of the medium
+        let max_lba = block_device.max_lba();
+        if w.lba > max_lba {
+            Err(Error::LogicalBlockAddressOutOfRange)?;
+        }
+        let count = match u64::from(w.number_of_blocks) {
+            0 => max_lba - w.lba + 1,
+            count if !blocks_on_medium(w.lba, count, max_lba) => Err(Error::LogicalBlockAddressOutOfRange)?,
+            count => count,
+        };
+
+        trace_scsi_fs!("FS> WriteSame; new: {}, lba: 0x{:X?}, count: {}", new_command, w.lba, count);
+
+        if !w.no_data_out_buffer {
+            match self.inner.transfer_state() {
+                TransferState::ReceivingDataFromHost { bytes_available, .. } if bytes_available >= BD::BLOCK_BYTES => {},
+                TransferState::ReceivingDataFromHost { done: true, .. } => Err(Error::InsufficientDataForCommand)?,
+                _ => return Ok(CommandState::Ongoing),
+            }
+        }
+
+        match block_device.unmap(w.lba, count) {
+            Ok(()) => {},
+            Err(nb::Error::WouldBlock) => return Ok(CommandState::Ongoing),
+            Err(nb::Error::Other(e)) => Err(e)?,
+        }
+
+        if !w.no_data_out_buffer {
+            self.inner.take_buffered_data(BD::BLOCK_BYTES, false)?;
+        }
+        Ok(CommandState::Done)
+    }
+
+    /// Length of the FORMAT UNIT parameter list. Only a header with an empty defect list is accepted,
+    /// apart from UFI which always follows it with a format descriptor
+    fn format_parameter_list_bytes(&self, f: &FormatCommand) -> usize {
+        let header_bytes = if f.long_list {
+            LongFormatParameterListHeader::BYTES
+        } else {
+            ShortFormatParameterListHeader::BYTES
+        };
+
+        if self.ufi {
+            header_bytes + UFI_FORMAT_DESCRIPTOR_BYTES
+        } else {
+            header_bytes
+        }
+    }
+
+    /// Checks the FORMAT UNIT parameter list then has the block device format the medium
+    ///
+    /// The status isn't sent until the format finishes unless IMMED is set in the parameter list,
+    /// then it's sent straight away and the format carries on from `Scsi::update`
+    fn format_unit<BD: BlockDevice>(
+        &mut self,
+        state: &mut LogicalUnitState,
+        block_device: &mut BD,
+        f: &FormatCommand,
+    ) -> Result<CommandState, Error> {
+        if state.format == FormatState::Idle {
+            // Protection information isn't supported
+            if f.format_protection_information != 0 {
+                Err(Error::InvalidFieldInCdb(1))?;
+            }
+
+            let immediate = if f.format_data {
+                let len = self.format_parameter_list_bytes(f);
+                match self.inner.transfer_state() {
+                    TransferState::ReceivingDataFromHost { bytes_available, .. } if bytes_available >= len => {},
+                    TransferState::ReceivingDataFromHost { done: true, .. } => Err(Error::ParameterListLengthError)?,
+                    _ => return Ok(CommandState::Ongoing),
+                }
+
+                let data = self.inner.take_buffered_data(len, false)?;
+                let (header, header_bytes): (FormatParameterListHeader, _) = if f.long_list {
+                    (
+                        LongFormatParameterListHeader::unpack(&data[..LongFormatParameterListHeader::BYTES])?.into(),
+                        LongFormatParameterListHeader::BYTES,
+                    )
+                } else {
+                    (
+                        ShortFormatParameterListHeader::unpack(&data[..ShortFormatParameterListHeader::BYTES])?.into(),
+                        ShortFormatParameterListHeader::BYTES,
+                    )
+                };
+
+                // There are no defect lists or initialization patterns. The UFI format descriptor
+                // is counted as the defect list but there's nothing in it that matters
+                if header.initialization_pattern || header.defect_list_length as usize != len - header_bytes {
+                    Err(Error::InvalidFieldInParameterList)?;
+                }
+                header.immediate
+            } else {
+                false
+            };
+
+            if immediate {
+                state.format = FormatState::Background;
+                return Ok(CommandState::Done);
+            }
+            state.format = FormatState::Running;
+        }
+
+        match block_device.format() {
+            Ok(()) => {
+                state.format = FormatState::Idle;
+                Ok(CommandState::Done)
+            },
+            Err(nb::Error::WouldBlock) => Ok(CommandState::Ongoing),
+            Err(nb::Error::Other(e)) => {
+                state.format = FormatState::Idle;
+                Err(e.into())
+            },
+        }
+    }
+
+    /// Checks a MODE SELECT parameter list and applies the pages in it once all of it is in the buffer
+    ///
+    /// The block descriptor can't change anything but is accepted if it matches the device. Nothing
+    /// is applied unless every page is valid
+    fn mode_select<BD: BlockDevice>(
+        &mut self,
+        state: &mut LogicalUnitState,
+        block_device: &BD,
+        m: &ModeSelectXCommand,
+    ) -> Result<CommandState, Error> {
+        if m.save_pages {
+            Err(Error::InvalidFieldInCdb(1))?;
+        }
+
+        let len = m.parameter_list_length as usize;
+        if len == 0 {
+            return Ok(CommandState::Done);
+        }
+        if len > T::BUFFER_BYTES {
+            Err(Error::ParameterListLengthError)?;
+        }
+
+        match self.inner.transfer_state() {
+            TransferState::ReceivingDataFromHost { bytes_available, .. } if bytes_available >= len => {},
+            TransferState::ReceivingDataFromHost { done: true, .. } => Err(Error::ParameterListLengthError)?,
+            _ => return Ok(CommandState::Ongoing),
+        }
+
+        let number_of_blocks = block_device.max_lba().saturating_add(1);
+        let device_pages = DeviceModePages::new(block_device);
+
+        let data = self.inner.take_buffered_data(len, false)?;
+
+        let (header_bytes, descriptor_bytes, long_lba) = match m.command_length {
+            CommandLength::C6 => {
+                let header = data.get(..ModeParameterHeader6::BYTES)
+                    .ok_or(Error::ParameterListLengthError)?;
+                let header = ModeParameterHeader6::unpack(header)
+                    .map_err(|_| Error::InvalidFieldInParameterList)?;
+                (ModeParameterHeader6::BYTES, header.block_descriptor_length as usize, false)
+            },
+            CommandLength::C10 => {
+                let header = data.get(..ModeParameterHeader10::BYTES)
+                    .ok_or(Error::ParameterListLengthError)?;
+                let header = ModeParameterHeader10::unpack(header)
+                    .map_err(|_| Error::InvalidFieldInParameterList)?;
+                (ModeParameterHeader10::BYTES, header.block_descriptor_length as usize, header.long_lba)
+            },
+        };
+
+        let descriptor = data.get(header_bytes..header_bytes + descriptor_bytes)
+            .ok_or(Error::ParameterListLengthError)?;
+        if !descriptor.is_empty() {
+            let (blocks, block_length, capacity) = if long_lba && descriptor.len() == LongLbaBlockDescriptor::BYTES {
+                let d = LongLbaBlockDescriptor::unpack(descriptor)?;
+                (d.number_of_blocks, d.logical_block_length, number_of_blocks)
+            } else if !long_lba && descriptor.len() == ShortLbaBlockDescriptor::BYTES {
+                let d = ShortLbaBlockDescriptor::unpack(descriptor)?;
+                (d.number_of_blocks.into(), d.logical_block_length, number_of_blocks.min(u32::MAX.into()))
+            } else {
+                Err(Error::InvalidFieldInParameterList)?
+            };
+
+            // The capacity and block size can't be changed. 0 blocks means leave the capacity alone
+            if block_length as usize != BD::BLOCK_BYTES || (blocks != 0 && blocks != capacity) {
+                Err(Error::InvalidFieldInParameterList)?;
+            }
+        }
+
+        let mut page_data = &data[header_bytes + descriptor_bytes..];
+        // Pages are only defined for the page format
+        if !m.page_format && !page_data.is_empty() {
+            Err(Error::InvalidFieldInCdb(1))?;
+        }
+
+        let mut mode_pages = state.mode_pages;
+        while !page_data.is_empty() {
+            let used = mode_pages.select_page(page_data, &device_pages)?;
+            page_data = &page_data[used..];
+        }
+        state.mode_pages = mode_pages;
+
+        Ok(CommandState::Done)
+    }
+
+    /// Length of the sense data REQUEST SENSE returns. Descriptor format is padded to the longest it
+    /// can be since the length isn't known until the sense data is. UFI doesn't have DESC
+    fn request_sense_bytes(&self, r: &RequestSenseCommand) -> usize {
+        if r.descriptor_format && !self.ufi {
+            DESCRIPTOR_FORMAT_BYTES
+        } else {
+            RequestSenseResponse::BYTES
+        }
+    }
+
+    fn request_sense(&mut self, state: &LogicalUnitState) -> Result<(), Error> {
+        let r = match self.current_command {
+            Command::RequestSense(r) => r,
+            _ => unreachable!(),
+        };
+
+        let buf = self.inner.take_buffer_space(self.request_sense_bytes(&r))?;
+        if r.descriptor_format && !self.ufi {
+            state.request_sense_response.pack_descriptor_format(state.sense_information, buf)?;
+        } else {
+            state.request_sense_response.pack(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Checks how the block device responded to `begin_read`/`begin_write`, false until it's
+    /// ready for the first block
+    fn transfer_started(&mut self, begin: nb::Result<(), BlockDeviceError>) -> Result<bool, Error> {
+        match begin {
+            Ok(()) => {
+                self.transfer = TransferSpan::Open;
+                Ok(true)
+            },
+            Err(nb::Error::WouldBlock) => Ok(false),
+            Err(nb::Error::Other(e)) => {
+                self.transfer = TransferSpan::Idle;
+                Err(e)?
+            },
+        }
+    }
+
+    /// Checks how the block device responded to `end_transfer`, the status isn't sent until
+    /// it's done
+    fn transfer_finished(&mut self, end: nb::Result<(), BlockDeviceError>) -> Result<CommandState, Error> {
+        match end {
+            Ok(()) => {
+                self.transfer = TransferSpan::Idle;
+                self.recovered_done()
+            },
+            Err(nb::Error::WouldBlock) => Ok(CommandState::Ongoing),
+            Err(nb::Error::Other(e)) => {
+                self.transfer = TransferSpan::Idle;
+                Err(e)?
+            },
+        }
+    }
+
+    /// Carries on past a block the block device had to correct, remembering the first one so
+    /// `recovered_done` can tell the host once the transfer is finished
+    fn recover(&mut self, result: nb::Result<(), BlockDeviceError>) -> nb::Result<(), BlockDeviceError> {
+        match result {
+            Err(nb::Error::Other(BlockDeviceError::RecoveredReadError)) => {
+                let lba = self.lba;
+                self.recovered_lba.get_or_insert(lba);
+                Ok(())
+            },
+            r => r,
+        }
+    }
+
+    /// Finishes a transfer, with RECOVERED ERROR sense if any of the blocks needed correcting.
+    /// All of the data has been transferred by then so the host can still use it
+    fn recovered_done(&self) -> Result<CommandState, Error> {
+        match self.recovered_lba {
+            Some(_) => Err(BlockDeviceError::RecoveredReadError)?,
+            None => Ok(CommandState::Done),
+        }
+    }
+
+    /// The INFORMATION field of the sense data for `err`. A block device error part way through a
+    /// transfer reports the LBA it happened on, or the last one if it was finishing the span
+    fn sense_information(&self, err: &Error) -> Option<u64> {
+        match (err, self.current_command) {
+            (Error::Miscompare(lba), _) => Some(*lba),
+            (Error::Deferred(d), _) => d.information,
+            (Error::BlockDeviceError(BlockDeviceError::RecoveredReadError), _) => self.recovered_lba,
+            (Error::BlockDeviceError(_), Command::Read(_)) |
+            (Error::BlockDeviceError(_), Command::Write(_)) |
+            (Error::BlockDeviceError(_), Command::Verify(_)) => Some(self.lba.min(self.lba_end)),
+            _ => None,
+        }
+    }
+
+    /// Lists the LUNs SELECT REPORT asks for out of 0 to `lun_count - 1`
+    fn report_luns(&mut self, r: &ReportLunsCommand, lun_count: u8) -> Result<CommandState, Error> {
+        let count = report_luns_count(r, lun_count).ok_or(Error::InvalidFieldInCdb(2))?;
+        let list_bytes = LunListEntry::BYTES * count as usize;
+        let header = ReportLunsHeader::new(list_bytes as u32);
+
+        let buf = self.inner.take_buffer_space(ReportLunsHeader::BYTES + list_bytes)?;
+        let (header_buf, list_buf) = buf.split_at_mut(ReportLunsHeader::BYTES);
+        header.pack(header_buf)?;
+
+        for (lun, entry_buf) in list_buf.chunks_exact_mut(LunListEntry::BYTES).enumerate() {
+            LunListEntry::new(lun as u8).pack(entry_buf)?;
+        }
+
+        Ok(CommandState::Done)
+    }
+}
+
+/// `count` blocks from `lba` are all on a medium that ends at `max_lba`. Always true for 0 blocks
+fn blocks_on_medium(lba: u64, count: u64, max_lba: u64) -> bool {
+    count == 0 || (lba <= max_lba && count - 1 <= max_lba - lba)
+}
+
+/// How many LUNs REPORT LUNS lists for its SELECT REPORT field. None if it isn't supported
+///
+/// There are no well known logical units or administrative logical units so the only LUNs that
+/// get listed are the ones addressing a logical unit (SPC-4 6.27)
+fn report_luns_count(r: &ReportLunsCommand, lun_count: u8) -> Option<u8> {
+    match r.select_report {
+        0x00 | 0x02 => Some(lun_count),
+        0x01 => Some(0),
+        _ => None,
+    }
+}
+
+fn map_error_to_sense_data(err: &Error) -> (SenseKey, AdditionalSenseCode) {
+    let (sense_key, additional_sense_code) = match err {
+        Error::UnhandledOpCode => (
+             SenseKey::IllegalRequest,
+             AdditionalSenseCode::InvalidCommandOperationCode,
+        ),
+
+        Error::InsufficientDataForCommand => (
+            SenseKey::IllegalRequest,
+            // Closest thing I could find. Some sources suggest OS does very little with ASC/ASCQ and it's
+            // most useful for debugging so as long as it's unique here it's probably ok.
+            AdditionalSenseCode::InvalidPacketSize,
+        ),
+
+        Error::LogicalUnitNotSupported => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::LogicalUnitNotSupported,
+        ),
+
+        Error::InvalidFieldInCdb(_) => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInCdb,
+        ),
+
+        Error::InvalidFieldInParameterList => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInParameterList,
+        ),
+
+        Error::ParameterListLengthError => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::ParameterListLengthError,
+        ),
+
+        Error::SavingParametersNotSupported => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::SavingParametersNotSupported,
+        ),
+
+        Error::WriteProtected => (
+            SenseKey::DataProtect,
+            AdditionalSenseCode::WriteProtected,
+        ),
+
+        Error::MediumNotPresent => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::MediumNotPresent,
+        ),
+
+        Error::UnitStopped => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::LogicalUnitNotReadyInitializingCommandRequired,
+        ),
+
+        Error::MediumMayHaveChanged => (
+            SenseKey::UnitAttention,
+            AdditionalSenseCode::NotReadyToReadyChange,
+        ),
+
+        Error::MediumRemovalPrevented => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::MediumRemovalPrevented,
+        ),
+
+        Error::Miscompare(_) => (
+            SenseKey::Miscompare,
+            AdditionalSenseCode::MiscompareDuringVerifyOperation,
+        ),
+
+        Error::Deferred(d) => (
+            d.sense_key,
+            d.additional_sense_code,
+        ),
+
+        Error::FormatInProgress => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress,
+        ),
+
+        Error::SelfTestFailed => (
+            SenseKey::HardwareError,
+            AdditionalSenseCode::LogicalUnitFailedSelfTest,
+        ),
+
+        Error::LogicalBlockAddressOutOfRange => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+        ),
+
+        Error::PackingError(p) |
+        Error::TransportError(TransportError::PackingError(p)) => match p {
+            PackingError::InsufficientBytes => panic!("PackingError::InsufficientBytes: Logical error in program"),
+            PackingError::Infallible(_) => unreachable!(),
+            PackingError::InvalidEnumDiscriminant => (
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            ),
+        },
+
+        Error::BlockDeviceError(BlockDeviceError::HardwareError) => (
+            SenseKey::HardwareError,
+            AdditionalSenseCode::InternalTargetFailure,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::WriteError) => (
+            SenseKey::MediumError,
+            AdditionalSenseCode::WriteError,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::EraseError) => (
+            SenseKey::MediumError,
+            AdditionalSenseCode::EraseFailure,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::InvalidAddress) => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::NotReady) => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::BecomingReady) => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::MediumNotPresent) => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::MediumNotPresent,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::WriteProtected) => (
+            SenseKey::DataProtect,
+            AdditionalSenseCode::WriteProtected,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::UnrecoveredReadError) => (
+            SenseKey::MediumError,
+            AdditionalSenseCode::UnrecoveredReadError,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::RecoveredReadError) => (
+            SenseKey::RecoveredError,
+            AdditionalSenseCode::RecoveredDataWithErrorCorrectionApplied,
+        ),
+        // Hosts retry NOT READY with this ASC/ASCQ after a delay
+        Error::BlockDeviceError(BlockDeviceError::Busy) => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress,
+        ),
+        // ABORTED COMMAND rather than HARDWARE ERROR so the host retries
+        Error::BlockDeviceError(BlockDeviceError::Timeout) => (
+            SenseKey::AbortedCommand,
+            AdditionalSenseCode::TimeoutOnLogicalUnit,
+        ),
+
+        Error::TransportError(TransportError::DataError) |
+        Error::TransportError(TransportError::PhaseError) => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInCdb,
+        ),
+
+        // These USB errors are likely to result in a USB reset, it's unlikely a SCSI
+        // request sense will ever be issued in these cases but just-in-case
+        Error::TransportError(TransportError::UsbError(_)) => (
+            SenseKey::HardwareError,
+            AdditionalSenseCode::NoAdditionalSenseInformation,
+        ),
+    };
+
+    info!("SENSE: {:?}, ASC: {} {}", sense_key, additional_sense_code.asc(), additional_sense_code.ascq());
+
+    (sense_key, additional_sense_code)
+}
+
+/// The sense data reported for `err`, with `information` from `CommandProcessor::sense_information`
+fn sense_data(err: &Error, information: Option<u64>) -> RequestSenseResponse {
+    let (sense_key, additional_sense_code) = map_error_to_sense_data(err);
+    let mut sense = RequestSenseResponse {
+        sense_key,
+        additional_sense_code,
+        ..Default::default()
+    };
+    if let Some(information) = information {
+        sense.set_information(information);
+    }
+
+    match err {
+        Error::InvalidFieldInCdb(byte) => sense.set_field_pointer(true, *byte),
+        Error::Deferred(_) => sense.response_code = ResponseCode::DeferredFixed,
+        _ => {},
+    }
+    sense
+}
+
+fn accept_would_block(r: Result<(), Error>) -> Result<(), Error> {
+    match r {
+        Ok(_) | Err(Error::TransportError(TransportError::UsbError(UsbError::WouldBlock))) => Ok(()),
+        e => e
+    }
+}
+
+impl<B: UsbBus, LU: LogicalUnits, T: Transport<B>> UsbClass<B> for Scsi<'_, B, LU, T> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+        self.processor.inner.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.abandon_command();
+        self.reset_done = 0;
+        self.processor.lba = 0;
+        self.processor.lba_end = 0;
+
+        for state in self.logical_unit_states.as_mut() {
+            state.reset_sense();
+            // Prevention is held by the host, it's gone once the bus resets
+            state.prevent_removal = false;
+        }
+
+        self.processor.inner.reset()
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.processor.inner.control_in(xfer)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let reset_pending = self.processor.inner.reset_pending();
+        self.processor.inner.control_out(xfer);
+
+        // Changing the alternate setting abandons the command that was in progress
+        if self.processor.inner.take_alt_setting_changed() {
+            self.abandon_command();
+        }
+
+        // A transport reset abandons the command that was in progress. The request has been
+        // accepted but the block devices are reset afterwards, the transport holds off the next
+        // command until they're done
+        if !reset_pending && self.processor.inner.reset_pending() {
+            self.abandon_command();
+            self.reset_done = 0;
+            self.continue_reset();
+        }
+    }
+
+    fn poll(&mut self) {
+        if let Err(e) = self.update() {
+            error!("Error from Scsi::update: {:?}", e);
+        }
+    }
+}
+
+#[test]
+fn test_deferred_error_fixed_format() {
+    use crate::scsi::mock_device::*;
+
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut host = Host::new(&alloc, MockBlockDevice::new());
+    host.block_device().write_back_error = Some((2, BlockDeviceError::WriteError));
+
+    // The write back error fails the next command
+    let test_unit_ready = [0x00, 0, 0, 0, 0, 0];
+    let r = host.command(Direction::HostToDevice, 0, &test_unit_ready, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_FAILED }));
+
+    // MEDIUM ERROR, WRITE ERROR, deferred with the LBA in INFORMATION
+    let request_sense = [0x03, 0, 0, 0, 18, 0];
+    let r = host.command(Direction::DeviceToHost, 18, &request_sense, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_PASSED }));
+    assert_eq!(r.data(), [
+        0xF1, 0x00, 0x03, 0x00, 0x00, 0x00, 0x02, 0xF6,
+        0x00, 0x00, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ]);
+
+    // It's only reported once
+    let r = host.command(Direction::HostToDevice, 0, &test_unit_ready, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_PASSED }));
+    let r = host.command(Direction::DeviceToHost, 18, &request_sense, &[]);
+    assert_eq!(r.data()[..3], [0x70, 0x00, 0x00]);
+}
+
+#[test]
+fn test_deferred_error_descriptor_format() {
+    use crate::scsi::mock_device::*;
+
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut host = Host::new(&alloc, MockBlockDevice::new());
+    host.block_device().write_back_error = Some((2, BlockDeviceError::WriteError));
+
+    // Request sense reports the deferred error itself rather than failing
+    let request_sense = [0x03, 0x01, 0, 0, DESCRIPTOR_FORMAT_BYTES as u8, 0];
+    let r = host.command(Direction::DeviceToHost, DESCRIPTOR_FORMAT_BYTES as u32, &request_sense, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_PASSED }));
+    assert_eq!(r.data(), [
+        0x73, 0x03, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x0C,
+        0x00, 0x0A, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ]);
+
+    // And then it's gone
+    let r = host.command(Direction::DeviceToHost, DESCRIPTOR_FORMAT_BYTES as u32, &request_sense, &[]);
+    assert_eq!(r.data()[..4], [0x72, 0x00, 0x00, 0x00]);
+    let test_unit_ready = [0x00, 0, 0, 0, 0, 0];
+    let r = host.command(Direction::HostToDevice, 0, &test_unit_ready, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_PASSED }));
+}
+
+
+#[test]
+fn test_thirteen_cases() {
+    use crate::scsi::mock_device::*;
+    use Direction::*;
+
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut host = Host::new(&alloc, MockBlockDevice::new());
+
+    let test_unit_ready = [0x00, 0, 0, 0, 0, 0];
+    let inquiry = [0x12, 0, 0, 0, 36, 0];
+    let read_1 = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+    let write_1 = [0x2A, 0, 0, 0, 0, 1, 0, 0, 1, 0];
+    let write_2 = [0x2A, 0, 0, 0, 0, 2, 0, 0, 2, 0];
+    let block = [0xA5; BLOCK_BYTES];
+
+    let passed = |residue| Some(Csw { residue, status: COMMAND_PASSED });
+    let phase_error = |residue| Some(Csw { residue, status: PHASE_ERROR });
+
+    // 1. Hn = Dn
+    let r = host.command(HostToDevice, 0, &test_unit_ready, &[]);
+    assert_eq!(r.csw, passed(0));
+
+    // 2. Hn < Di
+    let r = host.command(HostToDevice, 0, &inquiry, &[]);
+    assert_eq!(r.csw, phase_error(0));
+
+    // 3. Hn < Do
+    let r = host.command(HostToDevice, 0, &write_1, &[]);
+    assert_eq!(r.csw, phase_error(0));
+
+    // 4. Hi > Dn
+    let r = host.command(DeviceToHost, 64, &test_unit_ready, &[]);
+    assert_eq!((r.data().len(), r.csw), (0, None));
+    assert!(host.is_in_stalled());
+    assert_eq!(host.clear_in_halt().csw, passed(64));
+
+    // 5. Hi > Di
+    let r = host.command(DeviceToHost, 64, &inquiry, &[]);
+    assert_eq!((r.data().len(), r.csw), (36, None));
+    assert!(host.is_in_stalled());
+    assert_eq!(host.clear_in_halt().csw, passed(28));
+
+    // 6. Hi = Di
+    let r = host.command(DeviceToHost, 36, &inquiry, &[]);
+    assert_eq!((r.data().len(), r.csw), (36, passed(0)));
+    assert!(!host.is_in_stalled());
+
+    // 7. Hi < Di
+    let r = host.command(DeviceToHost, 64, &read_1, &[]);
+    assert_eq!((r.data().len(), r.csw), (0, None));
+    assert!(host.is_in_stalled());
+    assert_eq!(host.clear_in_halt().csw, phase_error(64));
+
+    // 8. Hi <> Do
+    let r = host.command(DeviceToHost, BLOCK_BYTES as u32, &write_1, &[]);
+    assert_eq!((r.data().len(), r.csw), (0, None));
+    assert!(host.is_in_stalled());
+    assert_eq!(host.clear_in_halt().csw, phase_error(BLOCK_BYTES as u32));
+
+    // 9. Ho > Dn
+    let r = host.command(HostToDevice, 64, &test_unit_ready, &[0; 64]);
+    assert_eq!(r.csw, passed(64));
+    assert!(host.is_out_stalled());
+    host.clear_out_halt();
+
+    // 10. Ho <> Di
+    let r = host.command(HostToDevice, 36, &inquiry, &[0; 36]);
+    assert_eq!((r.data().len(), r.csw), (0, phase_error(36)));
+    assert!(host.is_out_stalled());
+    host.clear_out_halt();
+
+    // 11. Ho > Do
+    let mut data = [0; 2 * BLOCK_BYTES];
+    data[..BLOCK_BYTES].copy_from_slice(&block);
+    let r = host.command(HostToDevice, data.len() as u32, &write_1, &data);
+    assert_eq!(r.csw, passed(BLOCK_BYTES as u32));
+    assert!(host.is_out_stalled());
+    assert_eq!(host.block_device().blocks[1], block);
+    host.clear_out_halt();
+
+    // 12. Ho = Do
+    let r = host.command(HostToDevice, BLOCK_BYTES as u32, &write_1, &[0x5A; BLOCK_BYTES]);
+    assert_eq!(r.csw, passed(0));
+    assert!(!host.is_out_stalled());
+    assert_eq!(host.block_device().blocks[1], [0x5A; BLOCK_BYTES]);
+
+    // 13. Ho < Do
+    let r = host.command(HostToDevice, BLOCK_BYTES as u32, &write_2, &block);
+    assert_eq!(r.csw, phase_error(BLOCK_BYTES as u32));
+    assert!(host.is_out_stalled());
+    assert_eq!(host.block_device().blocks[2], [0; BLOCK_BYTES]);
+}
+
+
+#[test]
+fn test_lba_out_of_range() {
+    use crate::scsi::mock_device::*;
+    use Direction::*;
+
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut host = Host::new(&alloc, MockBlockDevice::new());
+
+    // ILLEGAL REQUEST, LOGICAL BLOCK ADDRESS OUT OF RANGE
+    let request_sense = [0x03, 0, 0, 0, 18, 0];
+    let check_sense = |host: &mut Host| {
+        let r = host.command(DeviceToHost, 18, &request_sense, &[]);
+        assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_PASSED }));
+        assert_eq!((r.data()[2], r.data()[12], r.data()[13]), (0x05, 0x21, 0x00));
+    };
+
+    // The last block is fine, one past it isn't
+    let read_last = [0x28, 0, 0, 0, 0, BLOCKS as u8 - 1, 0, 0, 1, 0];
+    let r = host.command(DeviceToHost, BLOCK_BYTES as u32, &read_last, &[]);
+    assert_eq!((r.data().len(), r.csw), (BLOCK_BYTES, Some(Csw { residue: 0, status: COMMAND_PASSED })));
+
+    let read_past_end = [0x28, 0, 0, 0, 0, BLOCKS as u8, 0, 0, 1, 0];
+    let r = host.command(DeviceToHost, BLOCK_BYTES as u32, &read_past_end, &[]);
+    assert_eq!((r.data().len(), r.csw), (0, None));
+    assert_eq!(host.clear_in_halt().csw, Some(Csw { residue: BLOCK_BYTES as u32, status: COMMAND_FAILED }));
+    check_sense(&mut host);
+
+    // Nothing is read if the span runs off the end
+    let read_over_end = [0x28, 0, 0, 0, 0, BLOCKS as u8 - 1, 0, 0, 2, 0];
+    let r = host.command(DeviceToHost, 2 * BLOCK_BYTES as u32, &read_over_end, &[]);
+    assert_eq!((r.data().len(), r.csw), (0, None));
+    assert_eq!(host.clear_in_halt().csw, Some(Csw { residue: 2 * BLOCK_BYTES as u32, status: COMMAND_FAILED }));
+    check_sense(&mut host);
+
+    // Or written
+    let write_past_end = [0x2A, 0, 0, 0, 0, BLOCKS as u8, 0, 0, 1, 0];
+    let r = host.command(HostToDevice, BLOCK_BYTES as u32, &write_past_end, &[0xA5; BLOCK_BYTES]);
+    assert_eq!(r.csw, Some(Csw { residue: BLOCK_BYTES as u32, status: COMMAND_FAILED }));
+    assert!(host.is_out_stalled());
+    host.clear_out_halt();
+    check_sense(&mut host);
+}
+
+#[test]
+fn test_stopped_unit() {
+    use crate::scsi::mock_device::*;
+    use Direction::*;
+
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut host = Host::new(&alloc, MockBlockDevice::new());
+
+    let test_unit_ready = [0x00, 0, 0, 0, 0, 0];
+    let read_1 = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+    let request_sense = [0x03, 0, 0, 0, 18, 0];
+    let stop = [0x1B, 0, 0, 0, 0b00, 0];
+    let start = [0x1B, 0, 0, 0, 0b01, 0];
+    let passed = Some(Csw { residue: 0, status: COMMAND_PASSED });
+
+    // NOT READY, LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED rather than MEDIUM NOT
+    // PRESENT, stopping without LoEj leaves the medium where it is
+    let check_sense = |host: &mut Host| {
+        let r = host.command(DeviceToHost, 18, &request_sense, &[]);
+        assert_eq!(r.csw, passed);
+        assert_eq!((r.data()[2], r.data()[12], r.data()[13]), (0x02, 0x04, 0x02));
+    };
+
+    assert_eq!(host.command(HostToDevice, 0, &stop, &[]).csw, passed);
+
+    let r = host.command(HostToDevice, 0, &test_unit_ready, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_FAILED }));
+    check_sense(&mut host);
+
+    let r = host.command(DeviceToHost, BLOCK_BYTES as u32, &read_1, &[]);
+    assert_eq!((r.data().len(), r.csw), (0, None));
+    assert_eq!(host.clear_in_halt().csw, Some(Csw { residue: BLOCK_BYTES as u32, status: COMMAND_FAILED }));
+    check_sense(&mut host);
+
+    assert_eq!(host.command(HostToDevice, 0, &start, &[]).csw, passed);
+    assert_eq!(host.command(HostToDevice, 0, &test_unit_ready, &[]).csw, passed);
+    let r = host.command(DeviceToHost, BLOCK_BYTES as u32, &read_1, &[]);
+    assert_eq!((r.data().len(), r.csw), (BLOCK_BYTES, passed));
+}
+
+#[test]
+fn test_alt_setting_change_aborts_transfer() {
+    use crate::scsi::mock_device::*;
+    use Direction::*;
+    use usbd_uas_transport::UasTransport;
+
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut host = Host::with_uas(&alloc, MockBlockDevice::new());
+    assert_eq!(host.get_interface(), 0);
+
+    // Start a two block write on the bulk only fallback but only send the first block
+    let write_2 = [0x2A, 0, 0, 0, 0, 0, 0, 0, 2, 0];
+    let start_write = |host: &mut Host<UasTransport<MockBus>>, fill: u8| {
+        let r = host.command(HostToDevice, 2 * BLOCK_BYTES as u32, &write_2, &[fill; BLOCK_BYTES]);
+        assert_eq!(r.csw, None);
+        assert_eq!(host.block_device().blocks[0], [fill; BLOCK_BYTES]);
+        assert!(host.transport().get_current_command().is_some());
+    };
+    let test_unit_ready = [0x00, 0, 0, 0, 0, 0];
+
+    // Selecting the same alternate setting again still resets the bulk only transport
+    start_write(&mut host, 0xA5);
+    host.set_interface(0);
+    assert_eq!(host.block_device().aborted_transfers, 1);
+    assert!(host.transport().get_current_command().is_none());
+    assert_eq!(host.get_interface(), 0);
+
+    // The next CBW is taken as a new command, not as the rest of the write
+    let r = host.command(HostToDevice, 0, &test_unit_ready, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_PASSED }));
+    assert_eq!(host.block_device().blocks[1], [0; BLOCK_BYTES]);
+
+    // Switching to UAS abandons the write the same way and leaves UAS idle
+    start_write(&mut host, 0x5A);
+    host.set_interface(1);
+    assert_eq!(host.block_device().aborted_transfers, 2);
+    assert_eq!(host.get_interface(), 1);
+    assert!(host.transport().is_uas());
+    assert!(host.transport().get_current_command().is_none());
+    assert!(matches!(host.transport().transfer_state(), TransferState::NotTransferring { .. }));
+    assert_eq!(host.block_device().blocks[1], [0; BLOCK_BYTES]);
+
+    // And back again
+    host.set_interface(0);
+    assert_eq!(host.block_device().aborted_transfers, 2);
+    assert!(!host.transport().is_uas());
+    let r = host.command(HostToDevice, 0, &test_unit_ready, &[]);
+    assert_eq!(r.csw, Some(Csw { residue: 0, status: COMMAND_PASSED }));
 }
\ No newline at end of file
@@ -0,0 +1,173 @@
+use packing::Packed;
+
+/// Identifies the type of an information unit. Always the first byte
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum IuId {
+    /// Host to device on the command pipe
+    Command = 0x01,
+    /// Device to host on the status pipe, the status of a command
+    Sense = 0x03,
+    /// Device to host on the status pipe, the outcome of a task management function
+    Response = 0x04,
+    /// Host to device on the command pipe
+    TaskManagement = 0x05,
+    /// Device to host on the status pipe, the device is ready to send data for a command
+    ReadReady = 0x06,
+    /// Device to host on the status pipe, the device is ready to receive data for a command
+    WriteReady = 0x07,
+}
+
+/// SCSI status sent in a sense IU
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum Status {
+    Good = 0x00,
+    CheckCondition = 0x02,
+}
+
+/// Task management functions the host can send in a task management IU
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum TaskManagementFunction {
+    AbortTask = 0x01,
+    AbortTaskSet = 0x02,
+    ClearTaskSet = 0x04,
+    LogicalUnitReset = 0x08,
+    ITNexusReset = 0x10,
+    ClearAca = 0x40,
+    QueryTask = 0x80,
+    QueryTaskSet = 0x81,
+    QueryAsynchronousEvent = 0x82,
+}
+
+/// Response codes sent in a response IU
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum ResponseCode {
+    TaskManagementFunctionComplete = 0x00,
+    InvalidInformationUnit = 0x02,
+    TaskManagementFunctionNotSupported = 0x04,
+    TaskManagementFunctionFailed = 0x05,
+    TaskManagementFunctionSucceeded = 0x08,
+    IncorrectLogicalUnitNumber = 0x09,
+    OverlappedTagAttempted = 0x0A,
+}
+
+/// A command sent from the host on the command pipe. Additional CDB bytes for command blocks longer
+/// than 16 bytes follow it but aren't supported
+/// Big Endian
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct CommandIu {
+    /// Must be `IuId::Command`
+    #[packed(start_bit=7, end_bit=0, start_byte=0, end_byte=0)]
+    pub iu_id: u8,
+    /// Identifies the command. Echoed back in the IUs the device sends about it and must not
+    /// match any other command the host has queued
+    #[packed(start_bit=7, end_bit=0, start_byte=2, end_byte=3)]
+    pub tag: u16,
+    #[packed(start_bit=6, end_bit=3, start_byte=4, end_byte=4)]
+    pub command_priority: u8,
+    /// Simple, head of queue, ordered or ACA. Commands are always executed in the order they
+    /// arrive which satisfies all of them
+    #[packed(start_bit=2, end_bit=0, start_byte=4, end_byte=4)]
+    pub task_attribute: u8,
+    /// Length of the command block beyond 16 bytes, in 4 byte words
+    #[packed(start_bit=7, end_bit=2, start_byte=6, end_byte=6)]
+    pub additional_cdb_length: u8,
+    /// SAM LUN structure. Only single level peripheral device addressing (LUN in byte 1) is supported
+    #[packed(start_bit=7, end_bit=0, start_byte=8, end_byte=15)]
+    pub lun: [u8; 8],
+    /// The command block, padded to 16 bytes
+    #[packed(start_bit=7, end_bit=0, start_byte=16, end_byte=31)]
+    pub cdb: [u8; 16],
+}
+
+/// A task management function sent from the host on the command pipe
+/// Big Endian
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct TaskManagementIu {
+    /// Must be `IuId::TaskManagement`
+    #[packed(start_bit=7, end_bit=0, start_byte=0, end_byte=0)]
+    pub iu_id: u8,
+    /// Identifies the task management function. Shares the tag space with commands
+    #[packed(start_bit=7, end_bit=0, start_byte=2, end_byte=3)]
+    pub tag: u16,
+    /// One of `TaskManagementFunction`
+    #[packed(start_bit=7, end_bit=0, start_byte=4, end_byte=4)]
+    pub function: u8,
+    /// The tag of the command the function applies to, for functions that apply to one command
+    #[packed(start_bit=7, end_bit=0, start_byte=6, end_byte=7)]
+    pub task_tag: u16,
+    #[packed(start_bit=7, end_bit=0, start_byte=8, end_byte=15)]
+    pub lun: [u8; 8],
+}
+
+/// Header of the status sent on the status pipe when a command finishes. Followed by `length` bytes
+/// of sense data
+/// Big Endian
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct SenseIu {
+    #[packed(start_bit=7, end_bit=0, start_byte=0, end_byte=0)]
+    pub iu_id: IuId,
+    /// Tag of the command this is the status for
+    #[packed(start_bit=7, end_bit=0, start_byte=2, end_byte=3)]
+    pub tag: u16,
+    #[packed(start_bit=7, end_bit=0, start_byte=4, end_byte=5)]
+    pub status_qualifier: u16,
+    #[packed(start_bit=7, end_bit=0, start_byte=6, end_byte=6)]
+    pub status: Status,
+    /// Number of bytes of sense data following the header
+    #[packed(start_bit=7, end_bit=0, start_byte=14, end_byte=15)]
+    pub length: u16,
+}
+
+impl SenseIu {
+    pub fn new(tag: u16, status: Status, length: u16) -> Self {
+        Self {
+            iu_id: IuId::Sense,
+            tag,
+            status_qualifier: 0,
+            status,
+            length,
+        }
+    }
+}
+
+/// Sent on the status pipe in reply to a task management IU or a command the device couldn't accept
+/// Big Endian
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ResponseIu {
+    #[packed(start_bit=7, end_bit=0, start_byte=0, end_byte=0)]
+    pub iu_id: IuId,
+    /// Tag of the IU this is the response to
+    #[packed(start_bit=7, end_bit=0, start_byte=2, end_byte=3)]
+    pub tag: u16,
+    #[packed(start_bit=7, end_bit=0, start_byte=4, end_byte=6)]
+    pub additional_response_information: [u8; 3],
+    #[packed(start_bit=7, end_bit=0, start_byte=7, end_byte=7)]
+    pub response_code: ResponseCode,
+}
+
+impl ResponseIu {
+    pub fn new(tag: u16, response_code: ResponseCode) -> Self {
+        Self {
+            iu_id: IuId::Response,
+            tag,
+            additional_response_information: [0; 3],
+            response_code,
+        }
+    }
+}
+
+/// Sent on the status pipe before the data of a command so the host knows which command the data
+/// pipe is about to be used for. `iu_id` is `IuId::ReadReady` or `IuId::WriteReady`
+/// Big Endian
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadyIu {
+    #[packed(start_bit=7, end_bit=0, start_byte=0, end_byte=0)]
+    pub iu_id: IuId,
+    #[packed(start_bit=7, end_bit=0, start_byte=2, end_byte=3)]
+    pub tag: u16,
+}
\ No newline at end of file
@@ -0,0 +1,41 @@
+#![no_std]
+
+mod information_unit;
+mod uas_transport;
+
+pub use uas_transport::UasTransport;
+pub use information_unit::{
+    IuId,
+    Status,
+    TaskManagementFunction,
+    ResponseCode,
+    CommandIu,
+    TaskManagementIu,
+    SenseIu,
+    ResponseIu,
+    ReadyIu,
+};
+
+mod logging {
+    pub use itm_logger::*;
+
+    #[cfg(feature = "trace-uas-commands")]
+    pub use itm_logger::trace as trace_uas_commands;
+    #[cfg(not(feature = "trace-uas-commands"))]
+    pub use itm_logger::stub as trace_uas_commands;
+
+    #[cfg(feature = "trace-uas-states")]
+    pub use itm_logger::trace as trace_uas_states;
+    #[cfg(not(feature = "trace-uas-states"))]
+    pub use itm_logger::stub as trace_uas_states;
+
+    #[cfg(feature = "trace-uas-bytes")]
+    pub use itm_logger::trace as trace_uas_bytes;
+    #[cfg(not(feature = "trace-uas-bytes"))]
+    pub use itm_logger::stub as trace_uas_bytes;
+
+    #[cfg(feature = "trace-usb-control")]
+    pub use itm_logger::trace as trace_usb_control;
+    #[cfg(not(feature = "trace-usb-control"))]
+    pub use itm_logger::stub as trace_usb_control;
+}
\ No newline at end of file
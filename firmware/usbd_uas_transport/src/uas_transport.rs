@@ -0,0 +1,906 @@
+use core::fmt::Debug;
+use usb_device::class_prelude::*;
+use usb_device::{
+    Result as UsbResult,
+    control::{
+        RequestType,
+        Recipient,
+        Request,
+    },
+};
+pub use UsbError::WouldBlock;
+
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use usbd_mass_storage::{
+    USB_CLASS_MSC,
+    InterfaceSubclass,
+    InterfaceProtocol,
+    Transport,
+    TransportError as Error,
+    CommandBlock,
+    Direction,
+    TransferState,
+    TransferBuffer,
+    DEFAULT_BUFFER_BYTES,
+};
+use usbd_bulk_only_transport::BulkOnlyTransport;
+use crate::logging::*;
+use crate::information_unit::{
+    IuId,
+    Status,
+    TaskManagementFunction,
+    ResponseCode,
+    CommandIu,
+    TaskManagementIu,
+    SenseIu,
+    ResponseIu,
+    ReadyIu,
+};
+
+/// Alternate setting with the bulk only transport. Selected by default
+const BOT_ALT_SETTING: u8 = 0;
+
+/// Alternate setting with UAS
+const UAS_ALT_SETTING: u8 = 1;
+
+/// Class specific descriptor that follows each endpoint descriptor in the UAS alternate setting to
+/// say which pipe it is
+const PIPE_USAGE_DESCRIPTOR: u8 = 0x24;
+const COMMAND_PIPE_ID: u8 = 1;
+const STATUS_PIPE_ID: u8 = 2;
+const DATA_IN_PIPE_ID: u8 = 3;
+const DATA_OUT_PIPE_ID: u8 = 4;
+
+/// How many commands the host can have queued including the one being executed. The command pipe
+/// is NAKed while the queue is full
+const COMMAND_QUEUE_DEPTH: usize = 4;
+
+/// IUs from the host are read in a single packet so the max packet size can't be larger than this
+const MAX_PACKET_BYTES: usize = 64;
+
+/// Fixed format sense data without any additional sense bytes
+const MAX_SENSE_DATA_BYTES: usize = 18;
+
+/// Big enough for the largest IU sent on the status pipe, a sense IU with sense data
+const STATUS_BUFFER_BYTES: usize = <SenseIu as PackedSize>::BYTES + MAX_SENSE_DATA_BYTES;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum State {
+    /// There are no commands queued. Moves to ExecutingCommand when a command IU arrives
+    Idle,
+    /// The command at the head of the queue is being executed but the command set hasn't said
+    /// what it wants to transfer yet. Moves to SendingDataToHost or ReceivingDataFromHost
+    /// depending on the device intent or NeedToSendStatus if there's no data
+    ExecutingCommand,
+    /// Command initiated a transfer to the host (IN in USB parlance). Sends a read ready IU then
+    /// the number of bytes the command set intends to unless instructed to terminate early.
+    /// Moves to NeedToSendStatus
+    SendingDataToHost,
+    /// Command initiated a transfer from the host (OUT in USB parlance). Sends a write ready IU then
+    /// reads the number of bytes the command set intends to. Moves to NeedToSendStatus
+    ReceivingDataFromHost,
+    /// Data transfer has finished. Sends the sense IU on the status pipe. Moves to ExecutingCommand
+    /// for the next queued command or Idle
+    NeedToSendStatus,
+}
+
+/// A command waiting in the queue
+#[derive(Clone, Copy, Default)]
+struct QueuedCommand {
+    tag: u16,
+    lun: u8,
+    cdb: [u8; 16],
+}
+
+/// # USB Attached SCSI transport protocol
+///
+/// So far only tested with the SCSI transparent command set - see [Scsi](struct.Scsi.html)
+///
+/// [USB UAS Spec](https://www.usb.org/document-library/usb-attached-scsi-protocol-uasp-v10-and-adopters-agreement)
+///
+/// ## Alternate settings
+/// Like real UAS devices the interface has two alternate settings. Setting 0 is the
+/// [bulk only transport](struct.BulkOnlyTransport.html) for hosts without UAS support. Setting 1 is
+/// UAS, hosts that support it select it with a set interface request. The UAS setting reuses the
+/// bulk only endpoints for the data pipes and adds a command pipe and a status pipe.
+///
+/// ## Functionality overview
+/// 1. Queueing command IUs from the command pipe. Commands are executed one at a time in the order
+///    they arrive. A command that reuses the tag of one still in the queue is rejected
+/// 1. Sending a read ready or write ready IU on the status pipe before the data of each command.
+///    There are no streams on USB 2.0 so this is how the host knows which command the data is for
+/// 1. Initiating a data transfer with the length and direction from the command set (like CBI the
+///    host doesn't send a length, both sides work it out from the command block)
+/// 1. Terminating the data transfer when enough data is processed or early termination is requested.
+///    The host finds out how much data was transferred from the data pipe itself
+/// 1. Sending a sense IU with the status and sense data when each command finishes
+/// 1. Task management IUs. Abort task for a command that hasn't started yet and query task are
+///    supported, everything else is reported as not supported
+///
+/// Only single level LUNs are supported and command blocks are limited to 16 bytes. IUs are read in
+/// a single packet so the max packet size must be between 32 and 64 bytes (full speed).
+///
+/// ## Buffer size
+/// `BUFFER_BYTES` sets the size of the buffer used for both directions of the data transfer. It must
+/// be a multiple of the max packet size. The buffer is shared with the bulk only fallback.
+///
+pub struct UasTransport<'a, B: UsbBus, const BUFFER_BYTES: usize = DEFAULT_BUFFER_BYTES> {
+    /// Alternate setting 0. Also owns the interface and the data endpoints used by UAS
+    bot: BulkOnlyTransport<'a, B, BUFFER_BYTES>,
+
+    command_ep: EndpointOut<'a, B>,
+
+    status_ep: EndpointIn<'a, B>,
+
+    /// Selected with a set interface request
+    alt_setting: u8,
+
+    /// A set interface request has been accepted since `take_alt_setting_changed` was last called
+    alt_setting_changed: bool,
+
+    /// Are we waiting, sending or receiving data
+    state: State,
+
+    /// Commands waiting to be executed, the one at `queue_head` is the current command
+    queue: [QueuedCommand; COMMAND_QUEUE_DEPTH],
+
+    queue_head: usize,
+
+    queue_len: usize,
+
+    /// The read ready or write ready IU for the current command has been sent
+    ready_sent: bool,
+
+    /// Status of the current command, sent in the sense IU after the data
+    status: Status,
+
+    /// Sense data of the current command, sent in the sense IU after the data
+    sense_data: [u8; MAX_SENSE_DATA_BYTES],
+
+    /// The number of valid bytes in sense_data
+    sense_data_bytes: usize,
+
+    /// Reply to a task management IU or a rejected command waiting for the status pipe
+    response_iu: Option<ResponseIu>,
+
+    /// The IU currently being sent on the status pipe
+    status_iu: Option<IuId>,
+
+    status_buffer: [u8; STATUS_BUFFER_BYTES],
+
+    /// The next byte of status_buffer to send
+    status_i: usize,
+
+    /// The number of valid bytes in status_buffer
+    status_bytes: usize,
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> UasTransport<'_, B, BUFFER_BYTES> {
+    pub const BUFFER_BYTES: usize = BUFFER_BYTES;
+
+    /// Creates a new UasTransport
+    ///
+    /// `subclass` is normally `InterfaceSubclass::ScsiTransparentCommandSet`. `max_lun` is only used
+    /// by the bulk only fallback, UAS hosts find the LUNs with report luns. Panics if
+    /// `max_packet_size` isn't between 32 and 64 or `BUFFER_BYTES` isn't a non-zero multiple of it
+    pub fn new(
+        alloc: &UsbBusAllocator<B>,
+        max_packet_size: u16,
+        subclass: InterfaceSubclass,
+        max_lun: u8,
+    ) -> UasTransport<'_, B, BUFFER_BYTES> {
+        assert!(max_packet_size as usize >= CommandIu::BYTES && max_packet_size as usize <= MAX_PACKET_BYTES);
+        UasTransport {
+            bot: BulkOnlyTransport::new(alloc, max_packet_size, subclass, max_lun),
+            command_ep: alloc.bulk(max_packet_size),
+            status_ep: alloc.bulk(max_packet_size),
+            alt_setting: BOT_ALT_SETTING,
+            alt_setting_changed: false,
+            state: State::Idle,
+            queue: [Default::default(); COMMAND_QUEUE_DEPTH],
+            queue_head: 0,
+            queue_len: 0,
+            ready_sent: false,
+            status: Status::Good,
+            sense_data: [0; MAX_SENSE_DATA_BYTES],
+            sense_data_bytes: 0,
+            response_iu: None,
+            status_iu: None,
+            status_buffer: [0; STATUS_BUFFER_BYTES],
+            status_i: 0,
+            status_bytes: 0,
+        }
+    }
+
+    /// Sets lenient CBW validation on the bulk only fallback - see
+    /// [BulkOnlyTransport](struct.BulkOnlyTransport.html)
+    pub fn set_lenient_cbw_validation(&mut self, lenient: bool) {
+        self.bot.set_lenient_cbw_validation(lenient);
+    }
+
+    /// The host has selected the UAS alternate setting
+    pub fn is_uas(&self) -> bool {
+        self.alt_setting == UAS_ALT_SETTING
+    }
+
+    /// Is a bulk only mass storage reset waiting for `finish_reset`
+    ///
+    /// UAS doesn't have any class specific requests so this is only relevant to the bulk only
+    /// fallback - see [BulkOnlyTransport](struct.BulkOnlyTransport.html)
+    pub fn reset_pending(&self) -> bool {
+        self.bot.reset_pending()
+    }
+
+    /// Ends a bulk only mass storage reset, see `reset_pending`
+    pub fn finish_reset(&mut self, ok: bool) {
+        self.bot.finish_reset(ok)
+    }
+
+    /// Has the host selected an alternate setting since this was last called
+    pub fn take_alt_setting_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.alt_setting_changed, false)
+    }
+
+    fn set_alt_setting(&mut self, alternative: u8) {
+        trace_usb_control!("USB_CONTROL> Set alternate setting {}", alternative);
+
+        // Whatever either transport was doing is abandoned and the shared endpoints start afresh
+        self.bot.reset();
+        self.bot.msc_class().unstall_read_ep();
+        self.bot.msc_class().unstall_write_ep();
+        self.uas_reset();
+        self.alt_setting = alternative;
+        self.alt_setting_changed = true;
+    }
+
+    /// The data phase buffer belongs to the bulk only fallback, only one of them uses it at a time
+    fn buffer(&self) -> &TransferBuffer<BUFFER_BYTES> {
+        self.bot.transfer_buffer()
+    }
+
+    fn buffer_mut(&mut self) -> &mut TransferBuffer<BUFFER_BYTES> {
+        self.bot.transfer_buffer_mut()
+    }
+
+    fn max_packet_usize(&self) -> usize {
+        self.bot.msc_class().max_packet_size() as usize
+    }
+
+    pub fn read(&mut self) -> Result<(), Error> {
+        if !self.is_uas() {
+            return self.bot.read();
+        }
+
+        self.read_iu()?;
+
+        match self.state {
+            State::ReceivingDataFromHost if self.ready_sent => self.receiving_data_from_host(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self) -> Result<(), Error> {
+        if !self.is_uas() {
+            return self.bot.write();
+        }
+
+        // Anything already on its way has to finish before the status pipe can be used for
+        // something else
+        self.send_status_iu()?;
+
+        if let Some(response_iu) = self.response_iu.take() {
+            response_iu.pack(&mut self.status_buffer[..ResponseIu::BYTES])?;
+            self.start_status_iu(IuId::Response, ResponseIu::BYTES);
+            return self.send_status_iu();
+        }
+
+        match self.state {
+            State::SendingDataToHost |
+            State::ReceivingDataFromHost if !self.ready_sent => self.send_ready_iu(),
+            State::SendingDataToHost => self.sending_data_to_host(),
+            State::NeedToSendStatus => self.need_to_send_status(),
+            _ => Ok(()),
+        }
+    }
+
+    fn change_state(&mut self, new_state: State) {
+        trace_uas_states!("STATE> {:?} -> {:?}",
+            self.state,
+            new_state,
+        );
+        self.state = new_state;
+    }
+
+    /// Abandons every queued command and anything waiting to be sent on the status pipe
+    fn uas_reset(&mut self) {
+        self.queue_head = 0;
+        self.queue_len = 0;
+        self.response_iu = None;
+        self.status_iu = None;
+        self.status_i = 0;
+        self.status_bytes = 0;
+        self.start_next_command();
+    }
+
+    /// The current command, the one at the head of the queue
+    fn current(&self) -> &QueuedCommand {
+        &self.queue[self.queue_head]
+    }
+
+    /// Finds a queued command by tag. Returns its position in the queue, 0 being the current command
+    fn find_tag(&self, tag: u16) -> Option<usize> {
+        (0..self.queue_len).find(|n| self.queue[(self.queue_head + n) % COMMAND_QUEUE_DEPTH].tag == tag)
+    }
+
+    /// Removes the command at position `n` in the queue. Must not be the current command
+    fn remove_queued(&mut self, n: usize) {
+        for m in n..self.queue_len - 1 {
+            self.queue[(self.queue_head + m) % COMMAND_QUEUE_DEPTH] =
+                self.queue[(self.queue_head + m + 1) % COMMAND_QUEUE_DEPTH];
+        }
+        self.queue_len -= 1;
+    }
+
+    fn start_next_command(&mut self) {
+        // Nothing to transfer until the command set says otherwise
+        self.buffer_mut().reset();
+        self.ready_sent = false;
+
+        self.status = Status::Good;
+        self.sense_data_bytes = 0;
+
+        if self.queue_len > 0 {
+            trace_uas_commands!("COMMAND> Tag: {}, LUN: {}, CDB: {:X?}",
+                self.current().tag,
+                self.current().lun,
+                self.current().cdb,
+            );
+            self.change_state(State::ExecutingCommand);
+        } else {
+            self.change_state(State::Idle);
+        }
+    }
+
+    fn finish_command(&mut self) {
+        self.queue_head = (self.queue_head + 1) % COMMAND_QUEUE_DEPTH;
+        self.queue_len -= 1;
+        self.start_next_command();
+    }
+
+    fn read_iu(&mut self) -> Result<(), Error> {
+        // IUs are left on the command pipe (NAKed) until there's somewhere to put them and anything
+        // they might need to send in response
+        if self.queue_len == COMMAND_QUEUE_DEPTH || self.response_iu.is_some() {
+            return Ok(());
+        }
+
+        let mut buf = [0; MAX_PACKET_BYTES];
+        let bytes = match self.command_ep.read(&mut buf) {
+            Ok(bytes) => bytes,
+            Err(WouldBlock) => return Ok(()),
+            Err(e) => Err(e)?,
+        };
+        let iu = &buf[..bytes];
+
+        match iu.first().map(|id| IuId::from_primitive(*id)) {
+            Some(Ok(IuId::Command)) if iu.len() >= CommandIu::BYTES =>
+                self.queue_command(CommandIu::unpack(&iu[..CommandIu::BYTES])?),
+            Some(Ok(IuId::TaskManagement)) if iu.len() >= TaskManagementIu::BYTES =>
+                self.task_management(TaskManagementIu::unpack(&iu[..TaskManagementIu::BYTES])?),
+            _ => {
+                warn!("Invalid IU: {:X?}", iu);
+                let tag = match iu {
+                    [_, _, t0, t1, ..] => u16::from_be_bytes([*t0, *t1]),
+                    _ => 0,
+                };
+                self.response_iu = Some(ResponseIu::new(tag, ResponseCode::InvalidInformationUnit));
+            },
+        }
+
+        Ok(())
+    }
+
+    fn queue_command(&mut self, iu: CommandIu) {
+        trace_uas_commands!("COMMAND> Command IU received: {:?}", iu);
+
+        if self.find_tag(iu.tag).is_some() {
+            warn!("Command rejected, tag {} is already queued", iu.tag);
+            self.response_iu = Some(ResponseIu::new(iu.tag, ResponseCode::OverlappedTagAttempted));
+            return;
+        }
+
+        // Only single level peripheral device addressing is supported. Anything else gets a LUN
+        // that can't exist so the command set reports it as not supported
+        let lun = match iu.lun {
+            [0, lun, 0, 0, 0, 0, 0, 0] => lun,
+            _ => u8::MAX,
+        };
+
+        let i = (self.queue_head + self.queue_len) % COMMAND_QUEUE_DEPTH;
+        self.queue[i] = QueuedCommand {
+            tag: iu.tag,
+            lun,
+            cdb: iu.cdb,
+        };
+        self.queue_len += 1;
+
+        if self.state == State::Idle {
+            self.start_next_command();
+        }
+    }
+
+    fn task_management(&mut self, iu: TaskManagementIu) {
+        trace_uas_commands!("COMMAND> Task management IU received: {:?}", iu);
+
+        let response_code = match TaskManagementFunction::from_primitive(iu.function) {
+            // The current command can't be aborted because the command set could be part way through it
+            Ok(TaskManagementFunction::AbortTask) => match self.find_tag(iu.task_tag) {
+                Some(0) => ResponseCode::TaskManagementFunctionFailed,
+                Some(n) => {
+                    self.remove_queued(n);
+                    ResponseCode::TaskManagementFunctionComplete
+                },
+                None => ResponseCode::TaskManagementFunctionComplete,
+            },
+            Ok(TaskManagementFunction::QueryTask) => match self.find_tag(iu.task_tag) {
+                Some(_) => ResponseCode::TaskManagementFunctionSucceeded,
+                None => ResponseCode::TaskManagementFunctionComplete,
+            },
+            _ => ResponseCode::TaskManagementFunctionNotSupported,
+        };
+
+        self.response_iu = Some(ResponseIu::new(iu.tag, response_code));
+    }
+
+    fn start_status_iu(&mut self, iu_id: IuId, bytes: usize) {
+        self.status_iu = Some(iu_id);
+        self.status_i = 0;
+        self.status_bytes = bytes;
+    }
+
+    /// Sends the next packet of the IU in `status_buffer`. IUs are never a multiple of the max packet
+    /// size so they always end with a short packet and don't need a ZLP
+    fn send_status_iu(&mut self) -> Result<(), Error> {
+        let iu_id = match self.status_iu {
+            Some(iu_id) => iu_id,
+            None => return Ok(()),
+        };
+
+        let end = (self.status_i + self.max_packet_usize()).min(self.status_bytes);
+        let bytes = self.status_ep.write(&self.status_buffer[self.status_i..end])?;
+        self.status_i += bytes;
+
+        if self.status_i == self.status_bytes {
+            trace_uas_commands!("COMMAND> {:?} IU sent: {:X?}", iu_id, &self.status_buffer[..self.status_bytes]);
+            self.status_iu = None;
+
+            match iu_id {
+                IuId::ReadReady | IuId::WriteReady => self.ready_sent = true,
+                IuId::Sense => self.finish_command(),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_ready_iu(&mut self) -> Result<(), Error> {
+        let iu_id = match self.state {
+            State::SendingDataToHost => IuId::ReadReady,
+            _ => IuId::WriteReady,
+        };
+
+        let ready_iu = ReadyIu {
+            iu_id,
+            tag: self.current().tag,
+        };
+        ready_iu.pack(&mut self.status_buffer[..ReadyIu::BYTES])?;
+        self.start_status_iu(iu_id, ReadyIu::BYTES);
+        self.send_status_iu()
+    }
+
+    /// Tells the transport what the command set intends to do in the data phase of the current command
+    ///
+    /// `bytes` is how much data the command will transfer in `direction`. 0 means no data will be
+    /// transferred, in which case `direction` is ignored. Must be called once per command before any
+    /// data is transferred. If it isn't called the command has no data phase.
+    pub fn set_device_intent(&mut self, direction: Direction, bytes: u32) -> Result<(), Error> {
+        if !self.is_uas() {
+            return self.bot.set_device_intent(direction, bytes);
+        }
+
+        if self.state != State::ExecutingCommand {
+            return Ok(());
+        }
+
+        trace_uas_states!("STATE> Device intends {:?} {} bytes", direction, bytes);
+
+        self.buffer_mut().set_transfer_remaining(bytes);
+
+        if bytes > 0 {
+            match direction {
+                Direction::HostToDevice => self.change_state(State::ReceivingDataFromHost),
+                Direction::DeviceToHost => self.change_state(State::SendingDataToHost),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The command being executed. `None` once the command set has reported a status even if data
+    /// is still being transferred
+    pub fn get_current_command(&self) -> Option<CommandBlock<'_>> {
+        if !self.is_uas() {
+            return Transport::<B>::get_current_command(&self.bot);
+        }
+
+        match self.state {
+            State::ExecutingCommand |
+            State::SendingDataToHost |
+            State::ReceivingDataFromHost if !self.buffer().is_done() => Some(CommandBlock {
+                lun: self.current().lun,
+                bytes: &self.current().cdb,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn transfer_state(&self) -> TransferState {
+        if !self.is_uas() {
+            return self.bot.transfer_state();
+        }
+
+        let direction = match self.state {
+            State::ReceivingDataFromHost => Some(Direction::HostToDevice),
+            State::SendingDataToHost => Some(Direction::DeviceToHost),
+            _ => None,
+        };
+        self.buffer().transfer_state(direction)
+    }
+
+    /// Gets a mutable slice of the buffer of the specified length. The buffer is the same whichever
+    /// alternate setting is selected
+    /// panics if len requested is > the max size of the buffer
+    /// returns WouldBlock if there isn't currently space in the buffer
+    /// Advances the buffer pointer so don't call it unless you actually
+    /// need to put data in the buffer.
+    pub fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.buffer_mut().take_buffer_space(len)
+    }
+
+    /// The same as `take_buffer_space` but doesn't advance the buffer pointer
+    pub fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.buffer_mut().peek_buffer_space(len)
+    }
+
+    /// Returns a slice containing data from the buffer if there is `len` bytes available
+    /// panics if len requested is > the max size of the buffer
+    /// `take_available` modifies behaviour to return whatever is available, even if that is [u8; 0]
+    /// returns WouldBlock if there isn't enough data available
+    pub fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.buffer_mut().take_buffered_data(len, take_available)
+    }
+
+    /// The same as `take_buffered_data` but leaves the data in the buffer
+    pub fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.buffer().peek_buffered_data(len, take_available)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let remaining = self.buffer().transfer_remaining();
+        let (msc, buffer) = self.bot.msc_class_and_transfer_buffer_mut();
+        let bytes = buffer.flush(msc)?;
+
+        trace_uas_bytes!("BYTES> Sent {} bytes. Remaining {} -> {}. Buff bytes: {}",
+            bytes,
+            remaining,
+            self.buffer().transfer_remaining(),
+            self.buffer().bytes_buffered(),
+        );
+
+        Ok(())
+    }
+
+    fn end_data_transfer(&mut self) -> Result<(), Error> {
+        // The host works out how much data was transferred from the data pipe so there's nothing to
+        // do if we stopped short. It abandons the rest of its data transfer when the sense IU arrives
+        if self.buffer().transfer_remaining() > 0 {
+            trace_uas_states!("STATE> Transfer ended with {} bytes remaining", self.buffer().transfer_remaining());
+        }
+
+        // Anything left in the buffer is no longer needed
+        self.buffer_mut().end();
+
+        self.change_state(State::NeedToSendStatus);
+        self.send_status_now()
+    }
+
+    /// Tries to send the status straight away because we may not get an interrupt in a timely manner
+    /// otherwise. If the status pipe is busy it will be sent on the next call to `write` instead
+    fn send_status_now(&mut self) -> Result<(), Error> {
+        match self.need_to_send_status() {
+            Err(Error::UsbError(WouldBlock)) => Ok(()),
+            r => r,
+        }
+    }
+
+    pub fn send_command_ok(&mut self) -> Result<(), Error> {
+        if !self.is_uas() {
+            return self.bot.send_command_ok();
+        }
+
+        self.status = Status::Good;
+        self.sense_data_bytes = 0;
+        self.buffer_mut().set_done();
+        self.check_end_data_transfer()
+    }
+
+    /// Ends the current command with a failure. `sense_data` (fixed format) is sent to the host
+    /// along with the status in the sense IU, the bulk only fallback ignores it
+    pub fn send_command_error(&mut self, sense_data: &[u8]) -> Result<(), Error> {
+        if !self.is_uas() {
+            return self.bot.send_command_error();
+        }
+
+        self.status = Status::CheckCondition;
+        self.sense_data_bytes = sense_data.len().min(MAX_SENSE_DATA_BYTES);
+        self.sense_data[..self.sense_data_bytes].copy_from_slice(&sense_data[..self.sense_data_bytes]);
+        self.buffer_mut().set_done();
+        self.check_end_data_transfer()
+    }
+
+    fn sending_data_to_host(&mut self) -> Result<(), Error> {
+        // Send as much data as possible from the current buffer
+        self.flush()?;
+
+        self.check_end_data_transfer()
+    }
+
+    fn check_end_data_transfer(&mut self) -> Result<(), Error> {
+        // Nothing can end until the command has finished and given us a status
+        if !self.buffer().is_done() {
+            return Ok(());
+        }
+
+        match self.state {
+            State::ExecutingCommand => {
+                trace_uas_states!("STATE> Command finished without data");
+                self.end_data_transfer()?;
+            },
+            State::ReceivingDataFromHost => {
+                // Anything the host is still sending is no longer needed
+                trace_uas_states!("STATE> Command finished, remaining to receive: {}", self.buffer().transfer_remaining());
+                self.end_data_transfer()?;
+            },
+            State::SendingDataToHost => {
+                if self.buffer().transfer_remaining() == 0 {
+                    trace_uas_states!("STATE> All data sent");
+                    self.end_data_transfer()?;
+                } else if self.buffer().is_empty() {
+                    trace_uas_states!("STATE> Buffer empty, early termination");
+                    self.end_data_transfer()?;
+                }
+            }
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn receiving_data_from_host(&mut self) -> Result<(), Error> {
+        let remaining = self.buffer().transfer_remaining();
+        let (msc, buffer) = self.bot.msc_class_and_transfer_buffer_mut();
+        let bytes = buffer.receive(msc)?;
+
+        if bytes > 0 {
+            trace_uas_bytes!("BYTES> Read {} bytes. Remaining {} -> {}. Buff bytes: {}",
+                bytes,
+                remaining,
+                self.buffer().transfer_remaining(),
+                self.buffer().bytes_buffered(),
+            );
+        }
+
+        self.check_end_data_transfer()?;
+
+        Ok(())
+    }
+
+    fn need_to_send_status(&mut self) -> Result<(), Error> {
+        // The status pipe might still be busy with a ready IU or a response IU
+        if self.state != State::NeedToSendStatus || self.status_iu.is_some() {
+            return Ok(());
+        }
+
+        let sense_iu = SenseIu::new(self.current().tag, self.status, self.sense_data_bytes as u16);
+        sense_iu.pack(&mut self.status_buffer[..SenseIu::BYTES])?;
+
+        let bytes = SenseIu::BYTES + self.sense_data_bytes;
+        self.status_buffer[SenseIu::BYTES..bytes].copy_from_slice(&self.sense_data[..self.sense_data_bytes]);
+
+        self.start_status_iu(IuId::Sense, bytes);
+        self.send_status_iu()
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> Transport<B> for UasTransport<'_, B, BUFFER_BYTES> {
+    const BUFFER_BYTES: usize = BUFFER_BYTES;
+
+    fn interface_subclass(&self) -> InterfaceSubclass {
+        self.bot.msc_class().subclass()
+    }
+
+    fn read(&mut self) -> Result<(), Error> {
+        self.read()
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        self.write()
+    }
+
+    fn get_current_command(&self) -> Option<CommandBlock<'_>> {
+        self.get_current_command()
+    }
+
+    fn set_device_intent(&mut self, direction: Direction, bytes: u32) -> Result<(), Error> {
+        self.set_device_intent(direction, bytes)
+    }
+
+    fn transfer_state(&self) -> TransferState {
+        self.transfer_state()
+    }
+
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.take_buffer_space(len)
+    }
+
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.peek_buffer_space(len)
+    }
+
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.take_buffered_data(len, take_available)
+    }
+
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        self.peek_buffered_data(len, take_available)
+    }
+
+    fn send_command_ok(&mut self) -> Result<(), Error> {
+        self.send_command_ok()
+    }
+
+    fn send_command_error(&mut self, sense_data: &[u8]) -> Result<(), Error> {
+        self.send_command_error(sense_data)
+    }
+
+    fn reset_pending(&self) -> bool {
+        self.reset_pending()
+    }
+
+    fn finish_reset(&mut self, ok: bool) {
+        self.finish_reset(ok)
+    }
+
+    fn take_alt_setting_changed(&mut self) -> bool {
+        self.take_alt_setting_changed()
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> UsbClass<B> for UasTransport<'_, B, BUFFER_BYTES> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+        // Alternate setting 0
+        self.bot.get_configuration_descriptors(writer)?;
+
+        // Alternate setting 1 reuses the bulk only endpoints for the data pipes. Each endpoint is
+        // followed by a pipe usage descriptor
+        let msc = self.bot.msc_class();
+        writer.interface_alt(
+            msc.interface_number(),
+            UAS_ALT_SETTING,
+            USB_CLASS_MSC,
+            msc.subclass().to_primitive(),
+            InterfaceProtocol::Uas.to_primitive(),
+            None,
+        )?;
+
+        writer.endpoint(&self.command_ep)?;
+        writer.write(PIPE_USAGE_DESCRIPTOR, &[COMMAND_PIPE_ID, 0])?;
+
+        writer.endpoint(&self.status_ep)?;
+        writer.write(PIPE_USAGE_DESCRIPTOR, &[STATUS_PIPE_ID, 0])?;
+
+        writer.endpoint(msc.write_ep())?;
+        writer.write(PIPE_USAGE_DESCRIPTOR, &[DATA_IN_PIPE_ID, 0])?;
+
+        writer.endpoint(msc.read_ep())?;
+        writer.write(PIPE_USAGE_DESCRIPTOR, &[DATA_OUT_PIPE_ID, 0])?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        trace_usb_control!("USB_CONTROL> reset");
+        self.bot.reset();
+        self.uas_reset();
+        self.alt_setting = BOT_ALT_SETTING;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        match req {
+            // usb-device only knows about alternate setting 0 so the interface requests are
+            // answered here before it gets to them
+            Request {
+                request_type: RequestType::Standard,
+                recipient: Recipient::Interface,
+                request: Request::GET_INTERFACE,
+                index,
+                ..
+            } if self.bot.msc_class().correct_interface_number(index) => {
+                trace_usb_control!("USB_CONTROL> Get interface. Response: {}", self.alt_setting);
+                if let Err(e) = xfer.accept_with(&[self.alt_setting]) {
+                    error!("Error from ControlIn.accept_with: {:?}", e);
+                }
+            },
+
+            // Get max LUN for the bulk only fallback. UAS doesn't have any class specific requests
+            _ => self.bot.control_in(xfer),
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        match req {
+            Request {
+                request_type: RequestType::Standard,
+                recipient: Recipient::Interface,
+                request: Request::SET_INTERFACE,
+                value,
+                index,
+                ..
+            } if self.bot.msc_class().correct_interface_number(index) => {
+                let res = if value <= UAS_ALT_SETTING.into() {
+                    self.set_alt_setting(value as u8);
+                    xfer.accept()
+                } else {
+                    xfer.reject()
+                };
+                if let Err(e) = res {
+                    error!("Error from ControlOut.accept: {:?}", e);
+                }
+            },
+
+            _ => self.bot.control_out(xfer),
+        }
+    }
+
+    fn poll(&mut self) {
+        panic!("UasTransport::poll should never be called. Consumers (SCSI for example) should use UasTransport::read and UasTransport::write");
+    }
+}
+
+#[test]
+fn test_command_iu() {
+    // READ(10) of 8 blocks from LBA 0x20 to LUN 1 with tag 2
+    let bytes = [
+        0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x28, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00,
+        0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let iu = CommandIu::unpack(&bytes).unwrap();
+    assert_eq!(iu.iu_id, IuId::Command.to_primitive());
+    assert_eq!(iu.tag, 2);
+    assert_eq!(iu.lun, [0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(iu.cdb[..10], bytes[16..26]);
+
+    let mut sense = [0; SenseIu::BYTES];
+    SenseIu::new(2, Status::CheckCondition, 18).pack(&mut sense).unwrap();
+    assert_eq!(sense, [0x03, 0x00, 0x00, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12]);
+}
\ No newline at end of file